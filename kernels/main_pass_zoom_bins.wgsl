@@ -3,14 +3,31 @@ struct VertexOutput {
     //@location(0) frag_uv: vec2<f32>,
 }
 
+const MAX_ZOOMS: u32 = 8u;
+
 struct Parameters {
     total1: f32,
     total2: f32,
+    // mask pixels per tile pixel of each zoom searched, see SearchBins in src/gpu/algorithm.rs
+    n_zooms: u32,
+    zooms: array<vec4<f32>, 2>,
 }
 
 const PI: f32 = 3.14159265359;
 
-var<push_constant> tile_widths: array<u32, 16>;
+// Encoding of tex_mask, see MaskChannels in src/mask.rs
+const MASK_PROFILE: u32 = 1u;
+
+const MAX_ROTATIONS: u32 = 7u;
+
+struct PushConstants {
+    tile_widths: array<u32, 16>,
+    // cos and sin of the angles searched, the mask is turned clockwise around its center
+    rotations: array<vec2<f32>, MAX_ROTATIONS>,
+    n_rotations: u32,
+}
+
+var<push_constant> pc: PushConstants;
 
 @group(0) @binding(0) var<uniform> params: Parameters;
 
@@ -29,10 +46,11 @@ fn evalTotal(mask: vec3<f32>) -> f32 {
     return dot(mask.xy, mask.xy);
 }
 
+// The score in the low half as a f16, the index of the best zoom and rotation in the high half
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) u32 {
     let v = process(in);
-    return pack2x16float(v);
+    return (pack2x16float(vec2(v.x, 0.0)) & 0xFFFFu) | (u32(v.y) << 16u);
 }
 
 @fragment
@@ -45,49 +63,113 @@ const AROUND_COEFF_1: f32 = 2.0;
 const AROUND_COEFF_2: f32 = 1.0;
 const MATCHING_SCORE_COEFF: f32 = 1.0;
 
-const N_ZOOMS: u32 = 4;
-var<private> zooms: array<f32, N_ZOOMS> = array<f32, N_ZOOMS>(1.0 / 1.5, 1/1.3333, 1 / 1.1666,  1.0);
+fn zoomAt(i: u32) -> f32 {
+    return params.zooms[i / 4u][i % 4u];
+}
+
+const ZERO_ARR: array<f32, MAX_ZOOMS> = array<f32, MAX_ZOOMS>(0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+const EPS_ARR:  array<f32, MAX_ZOOMS> = array<f32, MAX_ZOOMS>(1e-5, 1e-5, 1e-5, 1e-5, 1e-5, 1e-5, 1e-5, 1e-5);
+const NO_MATCH_ARR: array<f32, MAX_ZOOMS> = array<f32, MAX_ZOOMS>(-1000.0, -1000.0, -1000.0, -1000.0, -1000.0, -1000.0, -1000.0, -1000.0);
+
+// Position in the mask (0..1) seen at `off` tile pixels from the corner of the match
+fn maskPos(off: vec2<f32>, zoom: f32, rot: vec2<f32>, dims_mask: vec2<f32>) -> vec2<f32> {
+    let pos = off * zoom;
+    if (rot.y == 0.0 && rot.x > 0.0) {
+        return pos / dims_mask;
+    }
+    let center = dims_mask * 0.5;
+    let d = pos - center;
+    return (vec2(rot.x * d.x + rot.y * d.y, rot.x * d.y - rot.y * d.x) + center) / dims_mask;
+}
+
+fn outsideMask(mask_pos: vec2<f32>) -> bool {
+    return mask_pos.x < 0.0 || mask_pos.y < 0.0 || mask_pos.x >= 1.0 || mask_pos.y >= 1.0;
+}
 
-const ZERO_ARR: array<f32, N_ZOOMS> = array<f32, N_ZOOMS>(0.0, 0.0, 0.0, 0.0);
-const EPS_ARR:  array<f32, N_ZOOMS> = array<f32, N_ZOOMS>(1e-5, 1e-5, 1e-5, 1e-5);
+// |gx| and |gy| of the turned mask. Their signs aren't stored, so the energy of the
+// gradient is shared between the axes rather than rotated
+fn rotateGrad(mask: vec3<f32>, rot: vec2<f32>) -> vec3<f32> {
+    if (rot.y == 0.0) {
+        return mask;
+    }
+    let cos2 = rot.x * rot.x;
+    let sin2 = rot.y * rot.y;
+    let g2 = mask.xy * mask.xy;
+    return vec3(sqrt(cos2 * g2.x + sin2 * g2.y), sqrt(sin2 * g2.x + cos2 * g2.y), mask.z);
+}
+
+// Tile pixels the turned mask reaches out of the area searched without rotation
+fn rotationMargin(rot: vec2<f32>, dims_mask_extended: vec2<u32>) -> vec2<i32> {
+    let w = vec2<f32>(dims_mask_extended);
+    let half_extent = (abs(rot.x) * w + abs(rot.y) * w.yx) * 0.5;
+    return vec2<i32>(ceil(max(half_extent - w * 0.5, vec2(0.0))));
+}
 
 fn process(in: VertexOutput) -> vec2<f32> {
     var pixelpos = vec2<i32>(in.position.xy) * STEP_SIZE;    // 1920x1952 = 4*(512-32)x(512-24)
     let dims_mask: vec2<u32> = textureDimensions(tex_mask);  // = 32x24
-    let dims_tile  = vec2<f32>(textureDimensions(tex_tile)); // = 2048x2048
-
-    let inv_dims_mask = vec2<f32>(1.0) / vec2<f32>(dims_mask);
 
     let dims_mask_extended: vec2<u32> = dims_mask * 3u / 2u;
 
     let tile_idx: vec2<i32>   = pixelpos / (vec2(512) - vec2<i32>(dims_mask)); // = 0..3
     let tile_local: vec2<i32> = pixelpos % (vec2(512) - vec2<i32>(dims_mask)); // = 0..480 x 0..488
-    let tile_width: u32       = tile_widths[u32(tile_idx.x) + u32(tile_idx.y) * 4];
+    let tile_width: u32       = pc.tile_widths[u32(tile_idx.x) + u32(tile_idx.y) * 4];
 
+    var margin = vec2(0);
+    for (var r = 0u; r < pc.n_rotations; r++) {
+        margin = max(margin, rotationMargin(pc.rotations[r], dims_mask_extended));
+    }
+    let end = max(dims_mask_extended * 3u / 2u, dims_mask_extended + vec2<u32>(margin));
 
-    if  (u32(tile_local.x) >= (tile_width - dims_mask_extended.x * 3u / 2u))
-     || (u32(tile_local.y) >= (512u       - dims_mask_extended.y * 3u / 2u)) {
-        return vec2(-1000.0, 1.0);
+    // forbidden or missing tiles are given no width
+    if tile_width == 0u {
+        return vec2(-1000.0, 0.0);
+    }
+    if  (tile_local.x < margin.x || tile_local.y < margin.y)
+     || (u32(tile_local.x) >= (tile_width - end.x))
+     || (u32(tile_local.y) >= (512u       - end.y)) {
+        return vec2(-1000.0, 0.0);
     }
 
     pixelpos = pixelpos + tile_idx * vec2<i32>(dims_mask);
 
-    var matchingScores = ZERO_ARR;
+    var best_bin = 0u;
+    var best_score = -1000.0;
 
-    let origin_m = vec2<f32>(pixelpos);
+    for (var r = 0u; r < pc.n_rotations; r++) {
+        var scores = processRotation(pixelpos, pc.rotations[r], dims_mask);
+        for (var zoomI = 0u; zoomI < params.n_zooms; zoomI++) {
+            if (scores[zoomI] > best_score) {
+                best_score = scores[zoomI];
+                best_bin = zoomI + r * params.n_zooms;
+            }
+        }
+    }
+
+    return vec2<f32>(best_score, f32(best_bin));
+}
 
-    for (var y = 0u; y < dims_mask_extended.y / 4; y = y + 1) {
-        for (var x = 0u; x < dims_mask_extended.x / 4; x = x + 1) {
-            let off = vec2(i32(x), i32(y));
+fn processRotation(pixelpos: vec2<i32>, rot: vec2<f32>, dims_mask: vec2<u32>) -> array<f32, MAX_ZOOMS> {
+    let dims_mask_f = vec2<f32>(dims_mask);
+    let dims_mask_extended: vec2<u32> = dims_mask * 3u / 2u;
+    let margin = rotationMargin(rot, dims_mask_extended);
+    let start = -margin;
+    let end = vec2<i32>(dims_mask_extended) + margin;
+
+    var matchingScores = ZERO_ARR;
+
+    for (var y = -((-start.y + 3) / 4); y < end.y / 4; y = y + 1) {
+        for (var x = -((-start.x + 3) / 4); x < end.x / 4; x = x + 1) {
+            let off = vec2(x, y);
             let p: vec2<i32> = pixelpos / 4 + off;
             let tile_value = textureLoad(tex_tile, p, 2).xyz;
 
-            for (var zoomI = 0u; zoomI < 10; zoomI++) {
-                let zoom = zooms[zoomI];
+            for (var zoomI = 0u; zoomI < params.n_zooms; zoomI++) {
+                let zoom = zoomAt(zoomI);
 
-                let mask_pos = vec2<f32>(off * 4) * inv_dims_mask * zoom;
-                if (mask_pos.x >= 1.0 || mask_pos.y >= 1.0) {
-                    break;
+                let mask_pos = maskPos(vec2<f32>(off * 4), zoom, rot, dims_mask_f);
+                if (outsideMask(mask_pos)) {
+                    continue;
                 }
                 let mask_value = textureSampleLevel(tex_mask, lsampl, mask_pos, 2.0).xyz;
                 let diff = (mask_value - tile_value);
@@ -96,7 +178,7 @@ fn process(in: VertexOutput) -> vec2<f32> {
 
         }
     }
-    for (var zoomI = 0u; zoomI < N_ZOOMS; zoomI++) {
+    for (var zoomI = 0u; zoomI < params.n_zooms; zoomI++) {
         if (textureLoad(tex_mask, vec2(0), 2).x == 0.0) {
             matchingScores[zoomI] = 0.0;
         }
@@ -108,23 +190,23 @@ fn process(in: VertexOutput) -> vec2<f32> {
     var sums   = ZERO_ARR;
     var totals = EPS_ARR;
 
-    for (var y = 0u; y < dims_mask_extended.y / 2; y = y + 1) {
-        for (var x = 0u; x < dims_mask_extended.x / 2; x = x + 1) {
-            let off = vec2(i32(x * 2), i32(y * 2));
+    for (var y = -((-start.y + 1) / 2); y < end.y / 2; y = y + 1) {
+        for (var x = -((-start.x + 1) / 2); x < end.x / 2; x = x + 1) {
+            let off = vec2(x * 2, y * 2);
             let p: vec2<i32> = pixelpos + off;
             let tile_value = textureLoad(tex_tile, p, 0).xy;
             if (tile_value.x == 1.0) {
-                return vec2(-1000.0, 0.0);
+                return NO_MATCH_ARR;
             }
 
-            for (var zoomI = 0u; zoomI < N_ZOOMS; zoomI++) {
-                let zoom = zooms[zoomI];
+            for (var zoomI = 0u; zoomI < params.n_zooms; zoomI++) {
+                let zoom = zoomAt(zoomI);
 
-                let mask_pos = vec2<f32>(off) * inv_dims_mask * zoom;
-                if (mask_pos.x >= 1.0 || mask_pos.y >= 1.0) {
-                    break;
+                let mask_pos = maskPos(vec2<f32>(off), zoom, rot, dims_mask_f);
+                if (outsideMask(mask_pos)) {
+                    continue;
                 }
-                let mask_value = textureSampleLevel(tex_mask, lsampl, mask_pos, 1.0).xyz;
+                let mask_value = rotateGrad(textureSampleLevel(tex_mask, lsampl, mask_pos, 1.0).xyz, rot);
                 sums[zoomI] += evalSum(mask_value, tile_value, AROUND_COEFF_1);
                 totals[zoomI] += evalTotal(mask_value);
             }
@@ -132,7 +214,7 @@ fn process(in: VertexOutput) -> vec2<f32> {
     }
     var any_has_detailed = false;
 
-    for (var zoomI = 0u; zoomI < N_ZOOMS; zoomI++) {
+    for (var zoomI = 0u; zoomI < params.n_zooms; zoomI++) {
         sums[zoomI] = sums[zoomI] / totals[zoomI] - matchingScores[zoomI];
         if (sums[zoomI] > DETAILED_SCORE_THRESHOLD) {
             any_has_detailed = true;
@@ -142,28 +224,28 @@ fn process(in: VertexOutput) -> vec2<f32> {
 
     if (any_has_detailed) {
         var total2 = 0.00001;
-        for (var zoomI = 0u; zoomI < N_ZOOMS; zoomI++) {
+        for (var zoomI = 0u; zoomI < params.n_zooms; zoomI++) {
             sums[zoomI] = 0.0;
             totals[zoomI] = 0.0;
         }
 
-        for (var y = 0u; y < dims_mask_extended.y; y = y + 1) {
-            for (var x = 0u; x < dims_mask_extended.x; x = x + 1) {
-                let off = vec2(i32(x), i32(y));
+        for (var y = start.y; y < end.y; y = y + 1) {
+            for (var x = start.x; x < end.x; x = x + 1) {
+                let off = vec2(x, y);
                 let p: vec2<i32> = pixelpos + off;
                 let tile_value = textureLoad(tex_tile, p, 0).xy;
                 if (tile_value.x == 1.0) {
-                    return vec2(-1000.0, 0.0);
+                    return NO_MATCH_ARR;
                 }
 
-                for (var zoomI = 0u; zoomI < N_ZOOMS; zoomI++) {
-                    let zoom = zooms[zoomI];
+                for (var zoomI = 0u; zoomI < params.n_zooms; zoomI++) {
+                    let zoom = zoomAt(zoomI);
 
-                    let mask_pos = vec2<f32>(off) * inv_dims_mask * zoom;
-                    if (mask_pos.x >= 1.0 || mask_pos.y >= 1.0) {
-                        break;
+                    let mask_pos = maskPos(vec2<f32>(off), zoom, rot, dims_mask_f);
+                    if (outsideMask(mask_pos)) {
+                        continue;
                     }
-                    let mask_value = textureSampleLevel(tex_mask, lsampl, mask_pos, 0.0).xyz;
+                    let mask_value = rotateGrad(textureSampleLevel(tex_mask, lsampl, mask_pos, 0.0).xyz, rot);
                     sums[zoomI] += evalSum(mask_value, tile_value, AROUND_COEFF_2);
                     totals[zoomI] += evalTotal(mask_value);
                 }
@@ -171,22 +253,12 @@ fn process(in: VertexOutput) -> vec2<f32> {
         }
 
 
-        for (var zoomI = 0u; zoomI < N_ZOOMS; zoomI++) {
+        for (var zoomI = 0u; zoomI < params.n_zooms; zoomI++) {
             sums[zoomI] = sums[zoomI] / totals[zoomI] - matchingScores[zoomI];
         }
     }
 
-    var best_zoom = 0u;
-    var best_score = -1000.0;
-
-    for (var zoomI = 0u; zoomI < N_ZOOMS; zoomI++) {
-        if (sums[zoomI] > best_score) {
-            best_score = sums[zoomI];
-            best_zoom = zoomI;
-        }
-    }
-
-    return vec2<f32>(best_score, 1.0 / zooms[best_zoom]);
+    return sums;
 }
 
 @vertex
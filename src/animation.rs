@@ -0,0 +1,234 @@
+//! Frames read straight from an animated file, so that a video doesn't have to be split
+//! into PNGs first: a YUV4MPEG2 stream (`.y4m`, e.g. `ffmpeg -i video.mp4 video.y4m`),
+//! an animated GIF or an APNG (`.png` or `.apng`).
+//!
+//! Frames are numbered from 1 in stream order, like the frames split by ffmpeg and the
+//! images written by `render`.
+
+use crate::error::{EarthfinderError, Result};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::error::{DecodingError, ImageFormatHint};
+use image::{AnimationDecoder, DynamicImage, ImageError, RgbImage};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// The frames of an animated file, with their number. The stream ends after the first
+/// frame that can't be decoded.
+pub struct Animation {
+    path: PathBuf,
+    frames: Frames,
+    next: u32,
+}
+
+enum Frames {
+    Y4m(Y4m),
+    Decoded(image::Frames<'static>),
+    Done,
+}
+
+impl Animation {
+    pub fn open(path: &Path) -> Result<Animation> {
+        let io_err = |e| EarthfinderError::io(path, e);
+        let image_err = |e| EarthfinderError::image(path, e);
+        let reader = BufReader::new(File::open(path).map_err(io_err)?);
+
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let frames = match extension.to_ascii_lowercase().as_str() {
+            "y4m" => Frames::Y4m(Y4m::new(reader).map_err(image_err)?),
+            "gif" => Frames::Decoded(GifDecoder::new(reader).map_err(image_err)?.into_frames()),
+            "png" | "apng" => Frames::Decoded(
+                PngDecoder::new(reader)
+                    .and_then(PngDecoder::apng)
+                    .map_err(image_err)?
+                    .into_frames(),
+            ),
+            _ => {
+                return Err(EarthfinderError::layout(
+                    path,
+                    "not an animation, expected .y4m, .gif, .png or .apng",
+                ))
+            }
+        };
+
+        Ok(Animation {
+            path: path.to_path_buf(),
+            frames,
+            next: 1,
+        })
+    }
+}
+
+impl Iterator for Animation {
+    type Item = Result<(u32, DynamicImage)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame = match &mut self.frames {
+            Frames::Y4m(y4m) => y4m.next_frame().transpose()?.map(DynamicImage::ImageRgb8),
+            Frames::Decoded(frames) => frames
+                .next()?
+                .map(|frame| DynamicImage::ImageRgba8(frame.into_buffer())),
+            Frames::Done => return None,
+        };
+
+        let n = self.next;
+        self.next += 1;
+        match frame {
+            Ok(frame) => Some(Ok((n, frame))),
+            Err(e) => {
+                self.frames = Frames::Done;
+                Some(Err(EarthfinderError::image(&self.path, e)))
+            }
+        }
+    }
+}
+
+fn y4m_error(msg: impl Into<String>) -> ImageError {
+    ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("YUV4MPEG2".to_string()),
+        msg.into(),
+    ))
+}
+
+/// An 8 bit YUV4MPEG2 stream, converted to RGB with the BT.601 limited range matrix
+struct Y4m {
+    reader: BufReader<File>,
+    width: usize,
+    height: usize,
+    /// Subsampling of the chroma planes, none for grayscale streams
+    chroma: Option<(usize, usize)>,
+}
+
+impl Y4m {
+    fn new(mut reader: BufReader<File>) -> std::result::Result<Y4m, ImageError> {
+        let mut header = vec![];
+        reader.read_until(b'\n', &mut header)?;
+        let header = String::from_utf8_lossy(&header);
+        let params = header
+            .strip_prefix("YUV4MPEG2 ")
+            .ok_or_else(|| y4m_error("missing YUV4MPEG2 signature"))?
+            .split_ascii_whitespace();
+
+        let (mut width, mut height, mut chroma) = (0, 0, Some((2, 2)));
+        for param in params {
+            if let Some(value) = param.strip_prefix('W') {
+                width = value.parse().map_err(|_| y4m_error("invalid width"))?;
+            } else if let Some(value) = param.strip_prefix('H') {
+                height = value.parse().map_err(|_| y4m_error("invalid height"))?;
+            } else if let Some(value) = param.strip_prefix('C') {
+                chroma = match value {
+                    "420" | "420jpeg" | "420paldv" | "420mpeg2" => Some((2, 2)),
+                    "422" => Some((2, 1)),
+                    "444" => Some((1, 1)),
+                    "mono" => None,
+                    _ => return Err(y4m_error(format!("unsupported colorspace {value}"))),
+                };
+            }
+        }
+        if width == 0 || height == 0 {
+            return Err(y4m_error("missing frame size"));
+        }
+
+        Ok(Y4m {
+            reader,
+            width,
+            height,
+            chroma,
+        })
+    }
+
+    fn next_frame(&mut self) -> std::result::Result<Option<RgbImage>, ImageError> {
+        let mut header = vec![];
+        if self.reader.read_until(b'\n', &mut header)? == 0 {
+            return Ok(None);
+        }
+        if !header.starts_with(b"FRAME") {
+            return Err(y4m_error("missing FRAME marker"));
+        }
+
+        let (w, h) = (self.width, self.height);
+        let mut luma = vec![0; w * h];
+        self.reader.read_exact(&mut luma)?;
+        let chroma = match self.chroma {
+            Some((sx, sy)) => {
+                let cw = w.div_ceil(sx);
+                let mut planes = vec![0; 2 * cw * h.div_ceil(sy)];
+                self.reader.read_exact(&mut planes)?;
+                Some((planes, cw, sx, sy))
+            }
+            None => None,
+        };
+
+        let image = RgbImage::from_fn(w as u32, h as u32, |x, y| {
+            let (x, y) = (x as usize, y as usize);
+            let c = 1.164 * (luma[y * w + x] as f32 - 16.0);
+            let (d, e) = match &chroma {
+                Some((planes, cw, sx, sy)) => {
+                    let (u, v) = planes.split_at(planes.len() / 2);
+                    let i = y / sy * cw + x / sx;
+                    (u[i] as f32 - 128.0, v[i] as f32 - 128.0)
+                }
+                None => (0.0, 0.0),
+            };
+            let rgb = [c + 1.596 * e, c - 0.392 * d - 0.813 * e, c + 2.017 * d];
+            image::Rgb(rgb.map(|v| v.round().clamp(0.0, 255.0) as u8))
+        });
+        Ok(Some(image))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Animation;
+    use image::codecs::gif::GifEncoder;
+    use image::{Frame, Rgba, RgbaImage};
+
+    #[test]
+    fn y4m_frames_are_numbered_from_1() {
+        let path = std::env::temp_dir().join("earthfinder_animation_test.y4m");
+        let mut stream = b"YUV4MPEG2 W4 H2 F30000:1001 Ip A1:1 C420jpeg\n".to_vec();
+        for luma in [235, 16] {
+            stream.extend(b"FRAME\n");
+            stream.extend([luma; 8]);
+            stream.extend([128; 4]);
+        }
+        // truncated third frame
+        stream.extend(b"FRAME\n");
+        stream.extend([16; 3]);
+        std::fs::write(&path, &stream).unwrap();
+
+        let frames = Animation::open(&path).unwrap().collect::<Vec<_>>();
+        assert_eq!(frames.len(), 3);
+        let (n, white) = frames[0].as_ref().unwrap();
+        assert_eq!((*n, white.width(), white.height()), (1, 4, 2));
+        assert_eq!(white.to_rgb8().get_pixel(3, 1).0, [255, 255, 255]);
+        let (n, black) = frames[1].as_ref().unwrap();
+        assert_eq!((*n, black.to_rgb8().get_pixel(0, 0).0), (2, [0, 0, 0]));
+        assert!(frames[2].is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn gif_frames_are_numbered_from_1() {
+        let path = std::env::temp_dir().join("earthfinder_animation_test.gif");
+        {
+            let file = std::fs::File::create(&path).unwrap();
+            let mut encoder = GifEncoder::new(file);
+            for value in [255, 0, 255] {
+                let image = RgbaImage::from_pixel(6, 4, Rgba([value, value, value, 255]));
+                encoder.encode_frame(Frame::new(image)).unwrap();
+            }
+        }
+
+        let frames = Animation::open(&path)
+            .unwrap()
+            .map(|frame| frame.unwrap())
+            .map(|(n, image)| (n, image.to_rgb8().get_pixel(5, 3).0[0]))
+            .collect::<Vec<_>>();
+        assert_eq!(frames, [(1, 255), (2, 0), (3, 255)]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
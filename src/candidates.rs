@@ -0,0 +1,191 @@
+//! Sidecar of the results CSV keeping every candidate found for a frame, not only the
+//! best one. One JSON object per line and per frame, in `<csv>.candidates.jsonl`.
+
+use crate::error::{EarthfinderError, Result};
+use crate::gpu::algorithm::{AlgoResult, PosResult};
+use nanoserde::{DeJson, SerJson};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The top-K candidates of one frame
+#[derive(Debug, Clone, PartialEq, DeJson, SerJson)]
+pub struct FrameCandidates {
+    pub frame: u32,
+    /// Best first
+    pub candidates: Vec<PosResult>,
+    /// Best score of every tile searched, only saved with `gpu --save-tile-scores`
+    #[nserde(default)]
+    pub tile_max_scores: Vec<TileScore>,
+}
+
+#[derive(Debug, Clone, PartialEq, DeJson, SerJson)]
+pub struct TileScore {
+    pub tile_x: u32,
+    pub tile_y: u32,
+    pub tile_z: u32,
+    pub score: f32,
+}
+
+impl FrameCandidates {
+    pub fn new(frame: u32, result: &AlgoResult, with_tile_scores: bool) -> Self {
+        let candidates = result
+            .best_pos
+            .results()
+            .iter()
+            // slots that were never filled, when fewer than K places were searched
+            .filter(|pos| pos.tile_x != u32::MAX && pos.score.is_finite())
+            .copied()
+            .collect();
+
+        let mut tile_max_scores = vec![];
+        if with_tile_scores {
+            tile_max_scores = result
+                .tile_max_scores
+                .iter()
+                .map(|(&(tile_x, tile_y, tile_z), &score)| TileScore {
+                    tile_x,
+                    tile_y,
+                    tile_z,
+                    score,
+                })
+                .collect::<Vec<_>>();
+            tile_max_scores.sort_unstable_by_key(|t| (t.tile_z, t.tile_y, t.tile_x));
+        }
+
+        Self {
+            frame,
+            candidates,
+            tile_max_scores,
+        }
+    }
+}
+
+/// Where the candidates of a results CSV are kept
+pub fn candidates_path(csv: &Path) -> PathBuf {
+    csv.with_extension("candidates.jsonl")
+}
+
+pub struct CandidatesWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> CandidatesWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn write(&mut self, frame: &FrameCandidates) -> std::io::Result<()> {
+        writeln!(self.out, "{}", frame.serialize_json())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Opens a candidates file to add frames to it, creating it if needed
+pub fn open_append(path: &Path) -> Result<CandidatesWriter<BufWriter<File>>> {
+    let io_err = |e| EarthfinderError::io(path, e);
+
+    let cut_line = match std::fs::read(path) {
+        Ok(content) => content.last().is_some_and(|&c| c != b'\n'),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => return Err(io_err(e)),
+    };
+
+    let mut file = File::options()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(io_err)?;
+    if cut_line {
+        // the last line was cut by an interrupted run
+        writeln!(file).map_err(io_err)?;
+    }
+    Ok(CandidatesWriter::new(BufWriter::new(file)))
+}
+
+/// Reads a candidates file, lines that can't be parsed are returned as errors
+pub fn read(path: &Path) -> Result<(Vec<FrameCandidates>, Vec<EarthfinderError>)> {
+    let content = std::fs::read_to_string(path).map_err(|e| EarthfinderError::io(path, e))?;
+
+    let mut frames = vec![];
+    let mut errors = vec![];
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match FrameCandidates::deserialize_json(line) {
+            Ok(frame) => frames.push(frame),
+            Err(e) => errors.push(EarthfinderError::parse(path, i + 1, e.msg)),
+        }
+    }
+    Ok((frames, errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open_append, read, FrameCandidates};
+    use crate::gpu::algorithm::{AlgoResult, PosResult, PosResults};
+    use rustc_hash::FxHashMap;
+
+    fn pos(tile_x: u32, score: f32) -> PosResult {
+        PosResult {
+            tile_x,
+            tile_y: 19,
+            tile_z: 7,
+            x: 277,
+            y: 95,
+            score,
+            zoom: 0.874_312_5,
+            angle: 0.0,
+            sub_x: 0.0,
+            sub_y: 0.0,
+            refined_score: None,
+        }
+    }
+
+    #[test]
+    fn candidates_roundtrip() {
+        let mut best_pos = PosResults::new(4);
+        best_pos.insert(pos(1, -2.5));
+        best_pos.insert(pos(2, -1.970_123_4));
+        best_pos.insert(pos(3, -3.25));
+        let result = AlgoResult {
+            best_pos,
+            tile_max_scores: FxHashMap::from_iter([((2, 19, 7), -1.97), ((1, 19, 7), -2.5)]),
+        };
+
+        let frame = FrameCandidates::new(12, &result, true);
+        assert_eq!(
+            frame
+                .candidates
+                .iter()
+                .map(|p| p.tile_x)
+                .collect::<Vec<_>>(),
+            [2, 1, 3]
+        );
+        assert_eq!(frame.tile_max_scores[0].tile_x, 1);
+        assert!(FrameCandidates::new(12, &result, false)
+            .tile_max_scores
+            .is_empty());
+
+        let dir = std::env::temp_dir().join("earthfinder_candidates_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.candidates.jsonl");
+        std::fs::write(&path, "{\"frame\": 3, \"cand").unwrap();
+
+        let mut writer = open_append(&path).unwrap();
+        writer.write(&frame).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let (frames, errors) = read(&path).unwrap();
+        assert_eq!(frames, [frame]);
+        assert_eq!(errors.len(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,372 @@
+use crate::gpu::algorithm::SearchBins;
+use crate::gpu::capture::CaptureTarget;
+use crate::pipeline::Stage;
+use crate::project::{Project, RefineConfig};
+use crate::results::{FrameRange, MergePolicy};
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Find places on earth that look like the frames of an animation
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Root of the data directory
+    #[arg(long, global = true, default_value = "data")]
+    pub data: PathBuf,
+
+    /// Root of the tile trees (tiles, tiles_grad, tiles_smol) [default: <data>]
+    #[arg(long, global = true)]
+    pub tiles: Option<PathBuf>,
+
+    /// Project file describing the animation and the search [default: <data>/project.json]
+    #[arg(long, global = true)]
+    pub project: Option<PathBuf>,
+
+    /// Capture GPU work with RenderDoc: `run:N` (the N-th search), `frame:N` (the search
+    /// of frame N) or `pass:NAME` (the first submission of a kernel, e.g.
+    /// `pass:main_pass_zoom_bins`). Needs the `renderdoc` feature
+    #[arg(long, global = true, value_name = "TARGET")]
+    pub capture: Option<CaptureTarget>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+#[command(rename_all = "snake_case")]
+pub enum Command {
+    /// Compute the gradient and downscaled tiles from the raw tiles
+    TilesGrad(TilesGradArgs),
+    /// Pack the gradient tiles of each zoom level in a single file, read faster by gpu
+    Pack(PackArgs),
+    /// Compute the masks from the animation frames
+    GenMask(GenMaskArgs),
+    /// Find the most similar masks of each frame, used by gpu to exclude tiles
+    MaskSimilarity(MaskSimilarityArgs),
+    /// Split the animation into shots at its hard cuts, used by gpu and render
+    Shots(ShotsArgs),
+    /// Run the search on a few masks and dump the top results as images
+    GpuOneFrame(GpuOneFrameArgs),
+    /// Run the search on every frame and append the best results to a CSV
+    Gpu(GpuArgs),
+    /// Render the final high resolution frames from a results CSV
+    Render(RenderArgs),
+    /// Run gen_mask, tiles_grad, gpu and render, skipping the stages that are up to date
+    Pipeline(PipelineArgs),
+    /// Merge, slice and compare results CSVs
+    Results(ResultsArgs),
+    /// Export the places matched in a results CSV as GeoJSON and KML polygons
+    #[command(alias = "export-geo")]
+    ExportGeo(ExportGeoArgs),
+    /// Print the project file with the defaults filled in
+    Project,
+}
+
+#[derive(Args)]
+pub struct TilesGradArgs {
+    /// Zoom levels to process [default: tiles.zooms]
+    pub zooms: Vec<u32>,
+
+    /// Tiles further from the equator than this latitude (in degrees) are skipped
+    /// [default: tiles.max_latitude]
+    #[arg(long)]
+    pub max_latitude: Option<f32>,
+
+    /// Put the original tile next to the gradient in the output
+    #[arg(long)]
+    pub show_original: bool,
+}
+
+#[derive(Args)]
+pub struct PackArgs {
+    /// Zoom levels to pack [default: tiles.zooms]
+    pub zooms: Vec<u32>,
+}
+
+#[derive(Args)]
+pub struct GenMaskArgs {
+    /// Put the downscaled frame next to the mask in the output
+    #[arg(long)]
+    pub debug: bool,
+
+    /// Only keep the frames numbered `frames.first` plus a multiple of `stride`
+    /// [default: frames.stride]
+    #[arg(long)]
+    pub stride: Option<usize>,
+
+    /// Width and height of the masks, multiples of 4 [default: mask.size]
+    #[arg(long, num_args = 2, value_names = ["WIDTH", "HEIGHT"])]
+    pub size: Option<Vec<u32>>,
+}
+
+#[derive(Args)]
+pub struct MaskSimilarityArgs {
+    /// First frame to compare [default: frames.first]
+    #[arg(long)]
+    pub first: Option<u32>,
+
+    /// Last frame to compare (inclusive) [default: frames.last]
+    #[arg(long)]
+    pub last: Option<u32>,
+
+    /// Number of similar masks to keep per frame
+    #[arg(short, long, default_value_t = 32)]
+    pub k: usize,
+
+    /// Masks less similar than this are not kept
+    #[arg(long, default_value_t = crate::mask_similarity::MIN_SIMILARITY)]
+    pub min_similarity: f32,
+
+    /// Where the matrix is written [default: <data>/<prefix>mask_similarity.jsonl]
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct ShotsArgs {
+    /// First frame to split [default: frames.first]
+    #[arg(long)]
+    pub first: Option<u32>,
+
+    /// Last frame to split (inclusive) [default: frames.last]
+    #[arg(long)]
+    pub last: Option<u32>,
+
+    /// Where the shots are written [default: <data>/<prefix>shots.txt]
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct GpuOneFrameArgs {
+    /// Zoom levels of the tiles to search in [default: tiles.zooms]
+    pub zooms: Vec<u32>,
+
+    /// Masks to search for, among the frames selected by `frames.first` and `frames.stride`
+    #[arg(long, num_args = 1.., default_values_t = [326, 2341])]
+    pub masks: Vec<u32>,
+
+    /// Number of results to keep per mask
+    #[arg(long, default_value_t = 12)]
+    pub top_k: usize,
+
+    /// Directory where the result images are written [default: <data>/results]
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct GpuArgs {
+    /// Zoom levels of the tiles to search in [default: tiles.zooms]
+    pub zooms: Vec<u32>,
+
+    /// First frame to process [default: frames.first]
+    #[arg(long)]
+    pub first: Option<u32>,
+
+    /// Last frame to process (inclusive) [default: frames.last]
+    #[arg(long)]
+    pub last: Option<u32>,
+
+    /// Number of results to keep per frame [default: search.top_k]
+    #[arg(long)]
+    pub top_k: Option<usize>,
+
+    /// Results CSV, frames already present are skipped [default: <data>/results/out.csv]
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+
+    /// Also save the accumulated error of each frame as an image
+    #[arg(long)]
+    pub save_error: bool,
+
+    /// Also save the best score of every tile in the candidates file (large)
+    #[arg(long)]
+    pub save_tile_scores: bool,
+
+    /// Decoded tiles kept in RAM, in MB. The rest is mapped from a file
+    /// [default: search.tile_cache_mb]
+    #[arg(long)]
+    pub tile_cache_mb: Option<u64>,
+
+    /// VRAM the tiles can take to stay on the GPU, in MB. 0 streams them every frame
+    /// [default: search.resident_tiles_mb]
+    #[arg(long)]
+    pub resident_tiles_mb: Option<u64>,
+
+    /// Angles the masks are turned by, in degrees clockwise [default: search.angles]
+    #[arg(long, num_args = 1.., allow_negative_numbers = true)]
+    pub angles: Vec<f32>,
+
+    /// Zooms the masks are searched at, in tile pixels per mask pixel
+    /// [default: search.mask_zooms]
+    #[arg(long, num_args = 1..)]
+    pub mask_zooms: Vec<f32>,
+
+    /// Steps of the refinement of each place found, 0 to keep the places of the kernel
+    /// [default: refine.iterations]
+    #[arg(long)]
+    pub refine_iterations: Option<usize>,
+}
+
+#[derive(Args)]
+pub struct RenderArgs {
+    /// Results CSV to render [default: <data>/results/out.csv]
+    pub csv: Option<PathBuf>,
+
+    /// Directory where the frames are written [default: <data>/render_final]
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+
+    /// How many zoom levels above the matched tile to render from
+    #[arg(long, default_value_t = 6)]
+    pub z_up: u32,
+
+    /// Maximum zoom level of the tiles to fetch
+    #[arg(long, default_value_t = 13)]
+    pub max_z: u32,
+
+    /// Height of the thumbnail of the original frame in the corner (width is 4/3 of it)
+    #[arg(long, default_value_t = 300)]
+    pub thumbnail_height: u32,
+
+    /// Fade the frames up to this many frames away from a cut found by `shots` to black
+    #[arg(long, default_value_t = 0)]
+    pub cut_fade: u32,
+}
+
+#[derive(Args)]
+pub struct PipelineArgs {
+    /// Run these stages even if they are up to date
+    #[arg(long, value_enum, num_args = 1..)]
+    pub force: Vec<Stage>,
+
+    /// Stop after this stage
+    #[arg(long, value_enum)]
+    pub until: Option<Stage>,
+
+    /// Only print which stages would run
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct ResultsArgs {
+    #[command(subcommand)]
+    pub command: ResultsCommand,
+}
+
+#[derive(Subcommand)]
+#[command(rename_all = "snake_case")]
+pub enum ResultsCommand {
+    /// Merge several results CSVs, e.g. frame ranges run on different machines
+    Merge(MergeArgs),
+    /// Keep or drop frame ranges
+    Slice(SliceArgs),
+    /// List the frames that have a mask but no result
+    Missing(MissingArgs),
+    /// Compare two runs frame by frame
+    Diff(DiffArgs),
+}
+
+#[derive(Args)]
+pub struct MergeArgs {
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Which result to keep when a frame is in several files
+    #[arg(long, value_enum, default_value_t = MergePolicy::Best)]
+    pub policy: MergePolicy,
+
+    /// Where the merged CSV is written [default: stdout]
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct SliceArgs {
+    /// [default: <data>/results/out.csv]
+    pub input: Option<PathBuf>,
+
+    /// Only keep these frames, e.g. `1-100 250`
+    #[arg(long, num_args = 1..)]
+    pub keep: Vec<FrameRange>,
+
+    /// Drop these frames
+    #[arg(long, num_args = 1..)]
+    pub drop: Vec<FrameRange>,
+
+    /// Where the sliced CSV is written [default: stdout]
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct MissingArgs {
+    /// [default: <data>/results/out.csv]
+    pub input: Option<PathBuf>,
+
+    /// First frame expected [default: frames.first]
+    #[arg(long)]
+    pub first: Option<u32>,
+
+    /// Last frame expected (inclusive) [default: frames.last]
+    #[arg(long)]
+    pub last: Option<u32>,
+}
+
+#[derive(Args)]
+pub struct DiffArgs {
+    pub a: PathBuf,
+    pub b: PathBuf,
+
+    /// Only list the frames whose place changed
+    #[arg(long)]
+    pub changed: bool,
+}
+
+#[derive(Args)]
+pub struct ExportGeoArgs {
+    /// Results CSV to export [default: <data>/results/out.csv]
+    pub csv: Option<PathBuf>,
+
+    /// Formats to write [default: all]
+    #[arg(long, value_enum, num_args = 1..)]
+    pub format: Vec<GeoFormat>,
+
+    /// Output path, its extension is replaced by the one of each format [default: <csv>]
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum GeoFormat {
+    Geojson,
+    Kml,
+}
+
+/// Zoom levels given on the command line, or the ones of the project
+pub fn zooms_or<'a>(zooms: &'a [u32], project: &'a Project) -> &'a [u32] {
+    if zooms.is_empty() {
+        &project.tiles.zooms
+    } else {
+        zooms
+    }
+}
+
+/// The refinement of the project, with the iterations given on the command line
+pub fn refine_config(args: &GpuArgs, project: &Project) -> RefineConfig {
+    RefineConfig {
+        iterations: args.refine_iterations.unwrap_or(project.refine.iterations),
+        ..project.refine.clone()
+    }
+}
+
+/// Zooms and angles given on the command line, or the ones of the project
+pub fn search_bins(args: &GpuArgs, project: &Project) -> SearchBins {
+    let or = |cli: &[f32], project: &[f32]| if cli.is_empty() { project } else { cli }.to_vec();
+    SearchBins {
+        zooms: or(&args.mask_zooms, &project.search.mask_zooms),
+        angles: or(&args.angles, &project.search.angles),
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+use std::path::PathBuf;
+
+pub type Result<T, E = EarthfinderError> = std::result::Result<T, E>;
+
+/// Everything that can go wrong while reading or writing the data directory.
+///
+/// Every variant carries the offending path, so that a bad file can be found and
+/// fixed (or deleted) without rerunning anything.
+#[derive(Debug)]
+pub enum EarthfinderError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// An image that exists but could not be decoded or encoded
+    Decode {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    /// A malformed line of a text file, `line` starts at 1
+    Parse {
+        path: PathBuf,
+        line: usize,
+        msg: String,
+    },
+    /// A file that is not where or named like the layout expects
+    Layout { path: PathBuf, msg: String },
+}
+
+impl EarthfinderError {
+    pub fn io(path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        Self::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub fn image(path: impl Into<PathBuf>, source: image::ImageError) -> Self {
+        match source {
+            image::ImageError::IoError(source) => Self::io(path, source),
+            source => Self::Decode {
+                path: path.into(),
+                source,
+            },
+        }
+    }
+
+    pub fn parse(path: impl Into<PathBuf>, line: usize, msg: impl Into<String>) -> Self {
+        Self::Parse {
+            path: path.into(),
+            line,
+            msg: msg.into(),
+        }
+    }
+
+    pub fn layout(path: impl Into<PathBuf>, msg: impl Into<String>) -> Self {
+        Self::Layout {
+            path: path.into(),
+            msg: msg.into(),
+        }
+    }
+}
+
+impl fmt::Display for EarthfinderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Self::Decode { path, source } => {
+                write!(f, "{}: could not decode image: {}", path.display(), source)
+            }
+            Self::Parse { path, line, msg } => write!(f, "{}:{}: {}", path.display(), line, msg),
+            Self::Layout { path, msg } => write!(f, "{}: {}", path.display(), msg),
+        }
+    }
+}
+
+impl std::error::Error for EarthfinderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Decode { source, .. } => Some(source),
+            Self::Parse { .. } | Self::Layout { .. } => None,
+        }
+    }
+}
+
+/// Prints the items a bulk operation skipped instead of aborting on the first one
+pub fn report(what: &str, errors: &[EarthfinderError]) {
+    if errors.is_empty() {
+        return;
+    }
+    eprintln!("/!\\ Warning: skipped {} {}:", errors.len(), what);
+    for e in errors {
+        eprintln!("  {}", e);
+    }
+}
@@ -0,0 +1,182 @@
+//! Exports the places matched in a results CSV as GeoJSON and KML, one polygon per frame,
+//! to look at where the animation travels in a GIS tool.
+
+use crate::cli::{ExportGeoArgs, GeoFormat};
+use crate::error::{self, EarthfinderError, Result};
+use crate::geo::BoundingBox;
+use crate::layout::DataLayout;
+use crate::results::{self, FrameData};
+use nanoserde::SerJson;
+use std::fmt::Write as _;
+use std::path::Path;
+
+#[derive(SerJson)]
+struct FeatureCollection {
+    #[nserde(rename = "type")]
+    kind: String,
+    features: Vec<Feature>,
+}
+
+#[derive(SerJson)]
+struct Feature {
+    #[nserde(rename = "type")]
+    kind: String,
+    geometry: Polygon,
+    properties: Properties,
+}
+
+#[derive(SerJson)]
+struct Polygon {
+    #[nserde(rename = "type")]
+    kind: String,
+    coordinates: Vec<Vec<Vec<f64>>>,
+}
+
+#[derive(SerJson)]
+struct Properties {
+    frame: u32,
+    score: f32,
+    zoom: f32,
+    tile_x: u32,
+    tile_y: u32,
+    tile_z: u32,
+}
+
+pub fn to_geojson(frames: &[FrameData], mask_size: (u32, u32)) -> String {
+    let features = frames
+        .iter()
+        .map(|frame| {
+            let ring = BoundingBox::of_result(&frame.result, mask_size).ring();
+            Feature {
+                kind: "Feature".to_string(),
+                geometry: Polygon {
+                    kind: "Polygon".to_string(),
+                    coordinates: vec![ring.iter().map(|c| c.to_vec()).collect()],
+                },
+                properties: Properties {
+                    frame: frame.frame,
+                    score: frame.result.score,
+                    zoom: frame.result.zoom,
+                    tile_x: frame.result.tile_x,
+                    tile_y: frame.result.tile_y,
+                    tile_z: frame.result.tile_z,
+                },
+            }
+        })
+        .collect();
+
+    FeatureCollection {
+        kind: "FeatureCollection".to_string(),
+        features,
+    }
+    .serialize_json()
+}
+
+pub fn to_kml(frames: &[FrameData], mask_size: (u32, u32)) -> String {
+    let mut kml = String::new();
+    kml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    kml.push_str("<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n<Document>\n");
+
+    for frame in frames {
+        let coordinates = BoundingBox::of_result(&frame.result, mask_size)
+            .ring()
+            .iter()
+            .map(|[lon, lat]| format!("{},{},0", lon, lat))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let _ = write!(
+            kml,
+            "<Placemark>\n\
+            <name>Frame {}</name>\n\
+            <ExtendedData>\n\
+            <Data name=\"frame\"><value>{}</value></Data>\n\
+            <Data name=\"score\"><value>{}</value></Data>\n\
+            <Data name=\"zoom\"><value>{}</value></Data>\n\
+            </ExtendedData>\n\
+            <Polygon><outerBoundaryIs><LinearRing><coordinates>{}</coordinates></LinearRing></outerBoundaryIs></Polygon>\n\
+            </Placemark>\n",
+            frame.frame, frame.frame, frame.result.score, frame.result.zoom, coordinates
+        );
+    }
+
+    kml.push_str("</Document>\n</kml>\n");
+    kml
+}
+
+pub fn export_geo(layout: &DataLayout, args: &ExportGeoArgs) -> Result<()> {
+    let csv = args.csv.clone().unwrap_or_else(|| layout.results_csv());
+    let (frames, errors) = results::read(&csv)?;
+    error::report("lines", &errors);
+
+    let Some(first) = frames.first() else {
+        eprintln!("No frame in {}, nothing to export", csv.display());
+        return Ok(());
+    };
+    let mask_path = layout.mask(first.frame);
+    let mask_size =
+        image::image_dimensions(&mask_path).map_err(|e| EarthfinderError::image(mask_path, e))?;
+
+    let output = args.output.clone().unwrap_or_else(|| csv.clone());
+    let formats = if args.format.is_empty() {
+        &[GeoFormat::Geojson, GeoFormat::Kml][..]
+    } else {
+        &args.format[..]
+    };
+
+    for format in formats {
+        let (extension, content) = match format {
+            GeoFormat::Geojson => ("geojson", to_geojson(&frames, mask_size)),
+            GeoFormat::Kml => ("kml", to_kml(&frames, mask_size)),
+        };
+        let path = output.with_extension(extension);
+        write(&path, &content)?;
+        println!("{} frames written to {}", frames.len(), path.display());
+    }
+    Ok(())
+}
+
+fn write(path: &Path, content: &str) -> Result<()> {
+    std::fs::write(path, content).map_err(|e| EarthfinderError::io(path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_geojson, to_kml};
+    use crate::gpu::algorithm::PosResult;
+    use crate::results::FrameData;
+
+    fn pos(tile_x: u32, tile_y: u32, tile_z: u32, x: u32, y: u32, zoom: f32) -> PosResult {
+        PosResult {
+            tile_x,
+            tile_y,
+            tile_z,
+            x,
+            y,
+            score: -1.5,
+            zoom,
+            angle: 0.0,
+            sub_x: 0.0,
+            sub_y: 0.0,
+            refined_score: None,
+        }
+    }
+
+    #[test]
+    fn exports() {
+        let frames = [FrameData {
+            frame: 12,
+            result: pos(1, 1, 2, 0, 0, 1.0),
+            time: None,
+        }];
+
+        let geojson = to_geojson(&frames, (512, 512));
+        assert!(geojson.starts_with("{\"type\":\"FeatureCollection\""));
+        assert!(geojson.contains("\"frame\":12"));
+        assert!(geojson.contains("[[[-90,0],[-90,-90],[0,-90],[0,0],[-90,0]]]"));
+
+        let kml = to_kml(&frames, (512, 512));
+        assert!(kml.contains("<name>Frame 12</name>"));
+        assert!(kml.contains("<coordinates>-90,0,0 -90,-90,0 0,-90,0 0,0,0 -90,0,0</coordinates>"));
+    }
+}
@@ -1,124 +1,384 @@
+use crate::animation::Animation;
+use crate::cli::GenMaskArgs;
+use crate::data::open_image;
+use crate::error::{self, EarthfinderError, Result};
+use crate::layout::DataLayout;
+use crate::mask::MaskChannels;
+use crate::project::{check_mask_size, Project};
 use image::imageops::FilterType;
-use image::{GenericImage, GenericImageView, Rgb, RgbImage};
+use image::{DynamicImage, GenericImage, Rgb, RgbImage};
 use rayon::prelude::*;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
 
-fn gaussian(x: i32, y: i32, sigma: f32) -> f32 {
-    let sigma2 = sigma * sigma;
-    let x2 = x as f32 * x as f32;
-    let y2 = y as f32 * y as f32;
-    ((-x2 - y2) / (2.0 * sigma2)).exp()
+/// Mask size given on the command line, or the project one
+pub fn mask_size(project: &Project, args: &GenMaskArgs) -> [u32; 2] {
+    match args.size.as_deref() {
+        Some(&[w, h]) => [w, h],
+        _ => project.mask.size,
+    }
 }
 
-pub fn gen_masks(debug: bool) {
-    let i = AtomicU32::new(0);
-    let entries: Vec<_> = walkdir::WalkDir::new("data/bad_apple_frames")
-        .into_iter()
-        .enumerate()
-        .filter(|(i, _)| i % 5 == 0)
-        .map(|(_, entry)| entry)
-        .collect();
-
-    let to_process = entries.len();
-
-    std::fs::create_dir_all("data/bad_apple_masks").unwrap_or_else(|e| {
-        panic!("Could not create directory: {}", e);
-    });
-
-    let conv_size: i32 = 9;
-
-    let sigma_first = 2.0;
-    let sigma_second = 1.0;
-    let sigma_third = 2.5;
-    let sigma_fourth = 1.0;
-
-    let mut gfirst = Vec::new();
-    let mut gsecond = Vec::new();
-    let mut gthird = Vec::new();
-    let mut gfourth = Vec::new();
-
-    let mut total_first = 0.0;
-    let mut total_second = 0.0;
-    let mut total_third = 0.0;
-    let mut total_fourth = 0.0;
-
-    for dy in -conv_size..=conv_size {
-        for dx in -conv_size..=conv_size {
-            let g1 = gaussian(dx, dy, sigma_first);
-            gfirst.push(g1);
-            total_first += g1;
-
-            let g2 = gaussian(dx, dy, sigma_second);
-            gsecond.push(g2);
-            total_second += g2;
-
-            let g3 = gaussian(dx, dy, sigma_third);
-            gthird.push(g3);
-            total_third += g3;
-
-            let g4 = gaussian(dx, dy, sigma_fourth);
-            gfourth.push(g4);
-            total_fourth += g4;
+/// Frames streamed from an animation at once, see [`crate::animation`]
+const ANIMATION_CHUNK_SIZE: usize = 64;
+
+fn is_selected(n: u32, first: u32, stride: usize) -> bool {
+    n >= first && ((n - first) as usize).is_multiple_of(stride)
+}
+
+/// The frames of `layout` whose number is `first` plus a multiple of `stride`, by number
+pub fn select_frames(
+    layout: &DataLayout,
+    first: u32,
+    stride: usize,
+) -> Result<Vec<(u32, PathBuf)>> {
+    let dir = layout.frames_dir();
+    let pattern = layout.frame_names();
+    let mut frames = vec![];
+    for entry in std::fs::read_dir(&dir).map_err(|e| EarthfinderError::io(&dir, e))? {
+        let entry = entry.map_err(|e| EarthfinderError::io(&dir, e))?;
+        let Some(n) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| pattern.frame_number(name))
+        else {
+            continue;
+        };
+        if is_selected(n, first, stride) && entry.path().is_file() {
+            frames.push((n, entry.path()));
         }
     }
+    frames.sort();
+    Ok(frames)
+}
 
-    entries.par_iter().for_each(|entry| {
-        let Ok(entry) = entry else {
-            return;
-        };
-        if !entry.file_type().is_file() {
-            return;
+/// Radius of the gaussian blurs
+const CONV_SIZE: usize = 9;
+
+/// A single channel of an image, row by row
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    fn from_fn(image: &RgbImage, f: impl Fn(&Rgb<u8>) -> f32) -> Plane {
+        Plane {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            data: image.pixels().map(f).collect(),
         }
-        let path = entry.path();
+    }
+
+    fn row(&self, y: usize) -> &[f32] {
+        &self.data[y * self.width..(y + 1) * self.width]
+    }
+}
+
+/// Normalized 1D gaussian of radius [`CONV_SIZE`]. The 2D blur of the masks is the
+/// product of two of them.
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let r = CONV_SIZE as i32;
+    let kernel = (-r..=r)
+        .map(|d| (-(d * d) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let total = kernel.iter().sum::<f32>();
+    kernel.into_iter().map(|g| g / total).collect()
+}
 
-        if !path.display().to_string().ends_with(".png") {
-            return;
+/// Correlates the rows of `src` with each of `kernels` (odd lengths, centered), the
+/// pixels past the edges repeating the edge. Every kernel shares the reads of a row.
+fn convolve_rows(src: &Plane, kernels: &[&[f32]]) -> Vec<Plane> {
+    let radius = kernels.iter().map(|k| k.len() / 2).max().unwrap_or(0);
+    let mut outs = kernels
+        .iter()
+        .map(|_| vec![0.0; src.data.len()])
+        .collect::<Vec<_>>();
+    let mut padded = vec![0.0; src.width + 2 * radius];
+
+    for y in 0..src.height {
+        let row = src.row(y);
+        for (i, p) in padded.iter_mut().enumerate() {
+            *p = row[i.saturating_sub(radius).min(src.width - 1)];
+        }
+        for (kernel, out) in kernels.iter().zip(&mut outs) {
+            let out = &mut out[y * src.width..(y + 1) * src.width];
+            let start = radius - kernel.len() / 2;
+            for (tap, &k) in kernel.iter().enumerate() {
+                let shifted = &padded[start + tap..start + tap + src.width];
+                for (o, &p) in out.iter_mut().zip(shifted) {
+                    *o += k * p;
+                }
+            }
         }
+    }
 
-        let mask_id = path
-            .file_stem()
-            .unwrap()
-            .to_string_lossy()
-            .split('_')
-            .last()
-            .unwrap()
-            .parse::<u32>()
-            .unwrap();
+    outs.into_iter()
+        .map(|data| Plane { data, ..*src })
+        .collect()
+}
 
-        let v = i.fetch_add(1, Ordering::Relaxed);
-        if v % 500 == 0 {
-            eprintln!(
-                "{} bad apple images processed ({:.0}%)",
-                v,
-                v as f32 / to_process as f32 * 100.0
-            );
+/// Correlates the columns of `src` with `kernel`, like [`convolve_rows`]
+fn convolve_cols(src: &Plane, kernel: &[f32]) -> Plane {
+    let radius = kernel.len() / 2;
+    let mut data = vec![0.0; src.data.len()];
+
+    for (y, out) in data.chunks_exact_mut(src.width).enumerate() {
+        for (tap, &k) in kernel.iter().enumerate() {
+            let yy = (y + tap).saturating_sub(radius).min(src.height - 1);
+            for (o, &p) in out.iter_mut().zip(src.row(yy)) {
+                *o += k * p;
+            }
         }
+    }
 
-        let ba = match image::open(path) {
-            Ok(mut image) => {
-                image = image.resize(
-                    image.width() / 45,
-                    image.height() / 45,
-                    FilterType::Lanczos3,
-                );
+    Plane { data, ..*src }
+}
 
-                image.to_rgb8()
+/// 3x3 gradients of `src`, weighting the side rows and columns 3/4 of the center one
+fn gradients(src: &Plane) -> (Plane, Plane) {
+    let (w, h) = (src.width, src.height);
+    let mut gx = vec![0.0; src.data.len()];
+    let mut gy = vec![0.0; src.data.len()];
+
+    for y in 0..h {
+        let rows = [y.saturating_sub(1), y, (y + 1).min(h - 1)];
+        for x in 0..w {
+            let cols = [x.saturating_sub(1), x, (x + 1).min(w - 1)];
+            let (mut sx, mut sy) = (0.0, 0.0);
+            for (dy, &yy) in (-1i32..=1).zip(&rows) {
+                for (dx, &xx) in (-1i32..=1).zip(&cols) {
+                    if dx == 0 && dy == 0 {
+                        continue;
+                    }
+                    let p = src.data[yy * w + xx];
+                    let mult = ((1 << dx.abs()) + (1 << dy.abs())) as f32;
+                    if dx != 0 {
+                        sx += p / (mult * dx as f32);
+                    }
+                    if dy != 0 {
+                        sy += p / (mult * dy as f32);
+                    }
+                }
             }
-            Err(e) => panic!("Could not open image {}: {}", path.display(), e),
-        };
+            gx[y * w + x] = sx;
+            gy[y * w + x] = sy;
+        }
+    }
 
-        let mask_w = if debug { ba.width() * 2 } else { ba.width() };
+    (Plane { data: gx, ..*src }, Plane { data: gy, ..*src })
+}
 
-        let mut mask_image = RgbImage::new(mask_w, ba.height());
+/// Mask of a frame already at the size of the mask: its gradients, and the surround
+/// penalty of the silhouette, see [`crate::project::MaskConfig::sigmas`]
+pub fn frame_mask(frame: &RgbImage, sigmas: [f32; 4]) -> MaskChannels {
+    let (w, h) = frame.dimensions();
+
+    let red = Plane::from_fn(frame, |p| p.0[0] as f32 / 255.0);
+    let (gx, gy) = gradients(&red);
+    let mut channels = MaskChannels::from_gradients(w, h, &gx.data, &gy.data);
+
+    let silhouette = Plane::from_fn(frame, |p| (p.0[0] > 128) as u8 as f32);
+    let kernels = sigmas.map(gaussian_kernel);
+    let blurred = convolve_rows(&silhouette, &kernels.each_ref().map(Vec::as_slice))
+        .iter()
+        .zip(&kernels)
+        .map(|(rows, kernel)| convolve_cols(rows, kernel))
+        .collect::<Vec<_>>();
+
+    // the penalty is lifted where the frame has edges, as the kernels see them
+    let penalty = (0..h)
+        .flat_map(|y| (0..w).map(move |x| (x, y)))
+        .enumerate()
+        .map(|(i, (x, y))| {
+            let [first, second, third, fourth] = [0, 1, 2, 3].map(|s| blurred[s].data[i]);
+            let gn = channels.grad_l1(x, y);
+            ((third - fourth).abs() - (first - second).abs() * 2.0 + 0.1 - gn).clamp(0.0, 1.0)
+        })
+        .collect::<Vec<_>>();
+    channels.set_surround(&penalty);
+
+    channels
+}
 
-        if debug {
-            for y in 0..ba.height() {
-                for x in 0..ba.width() {
-                    let pixel = ba.get_pixel(x, y);
-                    mask_image.put_pixel(x + ba.width(), y, *pixel);
+pub fn gen_masks(layout: &DataLayout, project: &Project, args: &GenMaskArgs) -> Result<()> {
+    let debug = args.debug;
+    let stride = args.stride.unwrap_or(project.frames.stride);
+    let [mask_w, mask_h] = mask_size(project, args);
+    check_mask_size([mask_w, mask_h])
+        .map_err(|e| EarthfinderError::layout(layout.masks_dir(), format!("mask size {}", e)))?;
+    let first = project.frames.first;
+    let i = AtomicU32::new(0);
+    let entries = match layout.animation() {
+        Some(_) => vec![],
+        None => select_frames(layout, first, stride)?,
+    };
+
+    // frames of an animation are only known once streamed
+    let to_process = layout.animation().is_none().then_some(entries.len());
+    if to_process == Some(0) {
+        eprintln!(
+            "no frame named {} in {}",
+            layout.frame_names(),
+            layout.frames_dir().display()
+        );
+    }
+
+    std::fs::create_dir_all(layout.masks_dir())
+        .map_err(|e| EarthfinderError::io(layout.masks_dir(), e))?;
+
+    let sigmas = project.mask.sigmas;
+
+    let errors = Mutex::new(vec![]);
+    let report = |e: EarthfinderError| errors.lock().unwrap().push(e);
+
+    let gen_mask = |mask_id: u32, frame: DynamicImage| {
+        let v = i.fetch_add(1, Ordering::Relaxed);
+        if v.is_multiple_of(500) {
+            match to_process {
+                Some(total) => eprintln!(
+                    "{} frames processed ({:.0}%)",
+                    v,
+                    v as f32 / total as f32 * 100.0
+                ),
+                None => eprintln!("{} frames processed", v),
+            }
+        }
+
+        let ba = frame
+            .resize_exact(mask_w, mask_h, FilterType::Lanczos3)
+            .to_rgb8();
+
+        let channels = frame_mask(&ba, sigmas);
+
+        let mask_path = layout.mask(mask_id);
+        let saved = if debug {
+            let mut debug_image = RgbImage::new(ba.width() * 2, ba.height());
+            debug_image.copy_from(&channels.to_rgb(), 0, 0).unwrap();
+            debug_image.copy_from(&ba, ba.width(), 0).unwrap();
+            debug_image
+                .save(&mask_path)
+                .map_err(|e| EarthfinderError::image(&mask_path, e))
+        } else {
+            channels.save(&mask_path)
+        };
+        if let Err(e) = saved {
+            report(e);
+        }
+    };
+
+    match layout.animation() {
+        Some(path) => {
+            let mut frames = Animation::open(&path)?.filter(|frame| {
+                frame
+                    .as_ref()
+                    .map_or(true, |&(n, _)| is_selected(n, first, stride))
+            });
+            loop {
+                let chunk = frames
+                    .by_ref()
+                    .take(ANIMATION_CHUNK_SIZE)
+                    .collect::<Vec<_>>();
+                if chunk.is_empty() {
+                    break;
                 }
+                chunk.into_par_iter().for_each(|frame| match frame {
+                    Ok((mask_id, frame)) => gen_mask(mask_id, frame),
+                    Err(e) => report(e),
+                });
             }
         }
+        None => entries
+            .par_iter()
+            .for_each(|&(mask_id, ref path)| match open_image(path) {
+                Ok(frame) => gen_mask(mask_id, frame),
+                Err(e) => report(e),
+            }),
+    }
+
+    error::report("frames", &errors.into_inner().unwrap());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame_mask, select_frames};
+    use crate::layout::DataLayout;
+    use image::{GenericImage, GenericImageView, Rgb, RgbImage};
+
+    #[test]
+    fn frames_are_selected_by_number() {
+        let root = std::env::temp_dir().join("earthfinder_select_frames_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let layout = DataLayout::new(&root).with_frame_names("f{n}.png".parse().unwrap());
+        std::fs::create_dir_all(layout.frames_dir().join("f17.png")).unwrap();
+        for name in [
+            "f12.png",
+            "f2.png",
+            "f7.png",
+            "f1.png",
+            "f3.jpg",
+            "notes.txt",
+        ] {
+            std::fs::write(layout.frames_dir().join(name), b"").unwrap();
+        }
+
+        let frames = select_frames(&layout, 2, 5).unwrap();
+        let numbers = frames.iter().map(|&(n, _)| n).collect::<Vec<_>>();
+        // f17.png is a directory, f1.png comes before the first frame
+        assert_eq!(numbers, [2, 7, 12]);
+        assert_eq!(frames[1].1, layout.frames_dir().join("f7.png"));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    /// The 2D convolutions `frame_mask` replaced
+    fn gaussian(x: i32, y: i32, sigma: f32) -> f32 {
+        let sigma2 = sigma * sigma;
+        let x2 = x as f32 * x as f32;
+        let y2 = y as f32 * y as f32;
+        ((-x2 - y2) / (2.0 * sigma2)).exp()
+    }
+
+    /// `frame_mask` as it was, with full 2D convolutions
+    fn reference_mask(ba: &RgbImage, sigmas: [f32; 4]) -> RgbImage {
+        let conv_size: i32 = 9;
+
+        let [sigma_first, sigma_second, sigma_third, sigma_fourth] = sigmas;
+
+        let mut gfirst = Vec::new();
+        let mut gsecond = Vec::new();
+        let mut gthird = Vec::new();
+        let mut gfourth = Vec::new();
+
+        let mut total_first = 0.0;
+        let mut total_second = 0.0;
+        let mut total_third = 0.0;
+        let mut total_fourth = 0.0;
+
+        for dy in -conv_size..=conv_size {
+            for dx in -conv_size..=conv_size {
+                let g1 = gaussian(dx, dy, sigma_first);
+                gfirst.push(g1);
+                total_first += g1;
+
+                let g2 = gaussian(dx, dy, sigma_second);
+                gsecond.push(g2);
+                total_second += g2;
+
+                let g3 = gaussian(dx, dy, sigma_third);
+                gthird.push(g3);
+                total_third += g3;
+
+                let g4 = gaussian(dx, dy, sigma_fourth);
+                gfourth.push(g4);
+                total_fourth += g4;
+            }
+        }
+
+        let mut mask_image = RgbImage::new(ba.width(), ba.height());
 
         let conv_grad_size = 1;
 
@@ -221,10 +481,54 @@ pub fn gen_masks(debug: bool) {
             p.0[2] = (p.0[2] as f32 * blue_correcter) as u8;
         });
 
-        let path_string = format!("data/bad_apple_masks/bad_apple_{}.png", mask_id);
+        mask_image
+    }
 
-        mask_image.save(&path_string).unwrap_or_else(|e| {
-            panic!("Could not save image {}: {}", path_string, e);
-        });
-    });
+    /// A few blobs and stripes, with a noisy background
+    fn silhouette(w: u32, h: u32, seed: u64) -> RgbImage {
+        fastrand::seed(seed);
+        let blobs = (0..4)
+            .map(|_| {
+                let (x, y) = (fastrand::f32() * w as f32, fastrand::f32() * h as f32);
+                (x, y, 2.0 + fastrand::f32() * w as f32 / 4.0)
+            })
+            .collect::<Vec<_>>();
+        RgbImage::from_fn(w, h, |x, y| {
+            let inside = blobs
+                .iter()
+                .any(|&(bx, by, r)| (x as f32 - bx).powi(2) + (y as f32 - by).powi(2) < r * r)
+                || (x / 3) % 7 == 0;
+            let v = if inside { 230 } else { 20 } + fastrand::u8(0..20);
+            Rgb([v, v / 2, 255 - v])
+        })
+    }
+
+    #[test]
+    fn separable_masks_match_reference() {
+        let sigmas = [2.0, 1.0, 2.5, 1.0];
+        for (seed, (w, h)) in [(32, 24), (48, 36), (8, 4)].into_iter().enumerate() {
+            let frame = silhouette(w, h, seed as u64);
+            let expected = reference_mask(&frame, sigmas);
+            let mask = frame_mask(&frame, sigmas).to_rgb();
+            assert_eq!(mask.dimensions(), expected.dimensions());
+
+            let diffs = mask
+                .as_raw()
+                .iter()
+                .zip(expected.as_raw())
+                .map(|(&a, &b)| a.abs_diff(b))
+                .collect::<Vec<_>>();
+            let max = *diffs.iter().max().unwrap();
+            let off = diffs.iter().filter(|&&d| d > 0).count();
+            // the blurs sum in another order, a truncation to u8 may round the other way
+            assert!(max <= 1, "{}x{}: max difference {}", w, h, max);
+            assert!(
+                off * 100 <= diffs.len(),
+                "{}x{}: {} values differ",
+                w,
+                h,
+                off
+            );
+        }
+    }
 }
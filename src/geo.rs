@@ -0,0 +1,222 @@
+//! Conversions between tile coordinates and WGS84 longitude/latitude.
+//!
+//! The tiles (EOX s2maps) follow a plate carrée pyramid: at zoom `z` the world is
+//! `2^z` tiles wide and `2^(z-1)` tiles high, so every tile spans `360 / 2^z` degrees
+//! both ways, tile `(0, 0)` starting at 180°W, 90°N. Tile coordinates are fractional
+//! here, the integer part being the tile and the fractional part the position in it.
+//!
+//! The gradient tiles searched are shrunk horizontally by [`deformation`] so that
+//! their pixels are square on the ground, which the pixel conversions account for.
+
+use crate::data::TilePos;
+use crate::gpu::algorithm::{PosResult, STEP_SIZE};
+use crate::TILE_HEIGHT;
+
+/// Degrees spanned by a tile at zoom `z`, both in longitude and latitude
+pub fn tile_degrees(z: u32) -> f64 {
+    360.0 / (1u64 << z) as f64
+}
+
+pub fn tile_to_lon(x: f64, z: u32) -> f64 {
+    -180.0 + x * tile_degrees(z)
+}
+
+pub fn tile_to_lat(y: f64, z: u32) -> f64 {
+    90.0 - y * tile_degrees(z)
+}
+
+pub fn lon_to_tile(lon: f64, z: u32) -> f64 {
+    (lon + 180.0) / tile_degrees(z)
+}
+
+pub fn lat_to_tile(lat: f64, z: u32) -> f64 {
+    (90.0 - lat) / tile_degrees(z)
+}
+
+/// How much to warp width to get orthonormal distances from a tile, the cosine of
+/// the latitude of its top edge. Always between [0-1]
+pub fn deformation(y: u32, z: u32) -> f32 {
+    (tile_to_lat(y as f64, z) as f32).to_radians().cos()
+}
+
+/// Width of a gradient tile made from a tile `width` pixels wide
+pub fn deform_width(width: u32, y: u32, z: u32) -> u32 {
+    (width as f32 * deformation(y, z)).ceil() as u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatLon {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// Position of pixel `(x, y)` of a gradient tile, `x` being in deformed pixels.
+/// Pixels past the tile edges land in the next tiles, as if they had the same width.
+/// Tiles touching the north pole have no width, and so no meaningful pixels.
+pub fn pixel_to_lat_lon(tile: TilePos, x: f64, y: f64) -> LatLon {
+    let (tile_x, tile_y, z) = tile;
+    let deform_w = deform_width(TILE_HEIGHT, tile_y, z) as f64;
+    LatLon {
+        lat: tile_to_lat(tile_y as f64 + y / TILE_HEIGHT as f64, z),
+        lon: tile_to_lon(tile_x as f64 + x / deform_w, z),
+    }
+}
+
+/// The gradient tile of zoom `z` containing `pos`, and the pixel of `pos` in it
+pub fn lat_lon_to_pixel(pos: LatLon, z: u32) -> (TilePos, f64, f64) {
+    let x = lon_to_tile(pos.lon, z);
+    let y = lat_to_tile(pos.lat, z);
+    let (tile_x, tile_y) = (x.floor() as u32, y.floor() as u32);
+    let deform_w = deform_width(TILE_HEIGHT, tile_y, z) as f64;
+    (
+        (tile_x, tile_y, z),
+        x.fract() * deform_w,
+        y.fract() * TILE_HEIGHT as f64,
+    )
+}
+
+/// Longitude/latitude rectangle, in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub west: f64,
+    pub north: f64,
+    pub east: f64,
+    pub south: f64,
+}
+
+impl BoundingBox {
+    /// The place covered by a frame whose mask is `mask_size` pixels, matched at `result`
+    pub fn of_result(result: &PosResult, mask_size: (u32, u32)) -> Self {
+        let tile = (result.tile_x, result.tile_y, result.tile_z);
+        let (x, y) = (
+            (result.x * STEP_SIZE as u32) as f64,
+            (result.y * STEP_SIZE as u32) as f64,
+        );
+        let (w, h) = (mask_size.0 as f32, mask_size.1 as f32);
+        let corners = [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)]
+            .map(|(xx, yy)| result.mask_offset(xx, yy, mask_size, 1));
+        let extent = |coord: fn(&(f32, f32)) -> f32| {
+            let values = corners.iter().map(coord);
+            let min = values.clone().fold(f32::INFINITY, f32::min) as f64;
+            (min, values.fold(f32::NEG_INFINITY, f32::max) as f64)
+        };
+        let ((min_x, max_x), (min_y, max_y)) = (extent(|c| c.0), extent(|c| c.1));
+        let (left, right) = (x + min_x, x + max_x);
+        let (top, bottom) = (y + min_y, y + max_y);
+
+        let north_west = pixel_to_lat_lon(tile, left, top);
+        let south_east = pixel_to_lat_lon(tile, right, bottom);
+        BoundingBox {
+            west: north_west.lon,
+            north: north_west.lat,
+            east: south_east.lon,
+            south: south_east.lat,
+        }
+    }
+
+    pub fn center(&self) -> LatLon {
+        LatLon {
+            lat: (self.north + self.south) / 2.0,
+            lon: (self.west + self.east) / 2.0,
+        }
+    }
+
+    /// Closed counterclockwise ring of `[lon, lat]` corners, as GeoJSON wants it
+    pub fn ring(&self) -> [[f64; 2]; 5] {
+        [
+            [self.west, self.north],
+            [self.west, self.south],
+            [self.east, self.south],
+            [self.east, self.north],
+            [self.west, self.north],
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    fn pos(tile: TilePos, x: u32, y: u32, zoom: f32) -> PosResult {
+        PosResult {
+            tile_x: tile.0,
+            tile_y: tile.1,
+            tile_z: tile.2,
+            x,
+            y,
+            score: 0.0,
+            zoom,
+            angle: 0.0,
+            sub_x: 0.0,
+            sub_y: 0.0,
+            refined_score: None,
+        }
+    }
+
+    #[test]
+    fn tile_edges() {
+        for z in 1..=13 {
+            assert_close(tile_to_lon(0.0, z), -180.0);
+            assert_close(tile_to_lon((1u64 << z) as f64, z), 180.0);
+            assert_close(tile_to_lat(0.0, z), 90.0);
+            assert_close(tile_to_lat((1u64 << (z - 1)) as f64, z), -90.0);
+        }
+        assert_close(lon_to_tile(0.0, 3), 4.0);
+        assert_close(lat_to_tile(0.0, 3), 2.0);
+        assert_close(tile_to_lat(2.0, 4), 45.0);
+
+        // tiles at the poles are squashed to nothing
+        assert!(deformation(0, 5).abs() < 1e-6);
+        assert_eq!(deform_width(TILE_HEIGHT, 8, 5), TILE_HEIGHT);
+        assert!((deformation(4, 5) - 0.5f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pixel_roundtrip() {
+        // equator, mid latitude, and close to the poles where tiles are narrow
+        for (lat, lon) in [(0.5, 2.35), (-33.9, 151.2), (78.22, 15.65), (-84.7, -170.3)] {
+            for z in [5, 8, 13] {
+                let (tile, x, y) = lat_lon_to_pixel(LatLon { lat, lon }, z);
+                assert!(x < deform_width(TILE_HEIGHT, tile.1, z) as f64);
+                assert!(y < TILE_HEIGHT as f64);
+                let back = pixel_to_lat_lon(tile, x, y);
+                assert_close(back.lat, lat);
+                assert_close(back.lon, lon);
+            }
+        }
+    }
+
+    #[test]
+    fn result_bounding_box() {
+        // a whole tile just south of the equator
+        let bbox = BoundingBox::of_result(&pos((1, 1, 2), 0, 0, 1.0), (512, 512));
+        assert_eq!(
+            bbox,
+            BoundingBox {
+                west: -90.0,
+                north: 0.0,
+                east: 0.0,
+                south: -90.0
+            }
+        );
+        assert_close(bbox.center().lat, -45.0);
+        assert_close(bbox.center().lon, -45.0);
+
+        // a quarter of a tile at 45°N, where the gradient tiles are narrower
+        let tile = (10, 2, 4);
+        let deform_w = deform_width(TILE_HEIGHT, 2, 4) as f64;
+        assert_eq!(deform_w, 363.0);
+        let bbox = BoundingBox::of_result(&pos(tile, 64, 128, 0.5), (256, 512));
+        assert_close(bbox.west, tile_to_lon(10.0 + 64.0 / deform_w, 4));
+        assert_close(bbox.east, tile_to_lon(10.0 + 192.0 / deform_w, 4));
+        assert_close(bbox.north, tile_to_lat(2.25, 4));
+        assert_close(bbox.south, tile_to_lat(2.75, 4));
+
+        let ring = bbox.ring();
+        assert_eq!(ring[0], ring[4]);
+    }
+}
@@ -1,21 +1,27 @@
 #![allow(clippy::type_complexity)]
+// nanoserde expands `Option` fields of `PosResult` to a match
+#![allow(clippy::question_mark)]
 
-use crate::data::{deform_width, TilePos};
+use crate::data::{open_image, TilePos};
+use crate::error::Result;
+use crate::geo::deform_width;
+use crate::gpu::capture;
 use crate::gpu::framework::*;
 use crate::gpu::state::WGPUState;
 use crate::gpu::{GPUData, Tile};
+use crate::layout::DataLayout;
 use crate::mask::Mask;
 use crate::render::tiles_needed;
 use crate::TILE_HEIGHT;
 use image::imageops::FilterType;
 use image::{Rgb32FImage, RgbImage, Rgba, RgbaImage};
+use nanoserde::{DeJson, SerJson};
 use rustc_hash::FxHashMap;
-use std::path::PathBuf;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
-use wgpu::{Device, Extent3d, ImageCopyTexture, Maintain, MapMode, Origin3d, TextureFormat};
+use wgpu::{Device, Extent3d, ImageCopyTexture, Maintain, MapMode, Origin3d, Queue, TextureFormat};
 
-#[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq, DeJson, SerJson)]
 pub struct PosResult {
     pub tile_x: u32,
     pub tile_y: u32,
@@ -24,12 +30,46 @@ pub struct PosResult {
     pub y: u32,
     pub score: f32,
     pub zoom: f32,
+    /// Degrees the mask is turned clockwise around its center, see [`PosResult::mask_offset`]
+    #[nserde(default)]
+    pub angle: f32,
+    /// Fraction of a tile pixel added to `x` and `y` by [`crate::refine`], in [0, 1)
+    #[nserde(default)]
+    pub sub_x: f32,
+    #[nserde(default)]
+    pub sub_y: f32,
+    /// [`crate::refine::score`] of the place once refined. Only used to rank the refined
+    /// places, `score` stays the one of the kernel
+    #[nserde(default)]
+    pub refined_score: Option<f32>,
 }
 
 impl PosResult {
     pub fn tile_pos(&self) -> TilePos {
         (self.tile_x, self.tile_y, self.tile_z)
     }
+
+    /// Offset from (x, y) of the pixel under (xx, yy) of a mask of `size`, in pixels of
+    /// the tile drawn `scale` times larger (`size` being the mask drawn as large). Exactly
+    /// `(xx * zoom, yy * zoom)` when the mask isn't turned nor moved by a sub-pixel.
+    pub fn mask_offset(&self, xx: f32, yy: f32, size: (u32, u32), scale: u32) -> (f32, f32) {
+        let sub = (self.sub_x * scale as f32, self.sub_y * scale as f32);
+        if self.angle == 0.0 {
+            return (xx * self.zoom + sub.0, yy * self.zoom + sub.1);
+        }
+        let (sin, cos) = self.angle.to_radians().sin_cos();
+        let (cx, cy) = (size.0 as f32 / 2.0, size.1 as f32 / 2.0);
+        let (dx, dy) = (xx - cx, yy - cy);
+        (
+            (cos * dx - sin * dy + cx) * self.zoom + sub.0,
+            (sin * dx + cos * dy + cy) * self.zoom + sub.1,
+        )
+    }
+}
+
+/// `base` moved by `offset` pixels, clamped at 0
+pub fn offset_pixel(base: u32, offset: f32) -> u32 {
+    (base as i64 + offset.floor() as i64).max(0) as u32
 }
 
 impl Default for PosResult {
@@ -42,6 +82,10 @@ impl Default for PosResult {
             y: 0,
             score: f32::NEG_INFINITY,
             zoom: 1.0,
+            angle: 0.0,
+            sub_x: 0.0,
+            sub_y: 0.0,
+            refined_score: None,
         }
     }
 }
@@ -71,6 +115,11 @@ impl PosResults {
         &self.top_results
     }
 
+    /// Orders the results by `key`, best first, rather than by [`PosResult::score`]
+    pub fn sort_by(&mut self, key: impl Fn(&PosResult) -> f32) {
+        self.top_results.sort_by(|a, b| key(b).total_cmp(&key(a)));
+    }
+
     pub fn insert(&mut self, pos: PosResult) {
         let mut i = self.top_k - 1;
         if pos.score < self.top_results[i].score {
@@ -98,8 +147,10 @@ impl AlgoResult {
 }
 
 pub struct Algo {
-    pub render_frame:
-        Box<dyn FnMut(&WGPUState<GPUData>, &[Tile], &[GPUTexture], Vec<(Vec<u8>, Vec<u8>, u32)>)>,
+    /// Searches a chunk of tiles, laid out in the given atlas by [`upload_tiles`]
+    pub render_frame: Box<dyn FnMut(&WGPUState<GPUData>, &[Tile], &[GPUTexture], GPUTexture)>,
+    /// Atlas the tiles are streamed through when they are not resident
+    pub tile_tex: GPUTexture,
     pub finish: Box<dyn FnMut(&WGPUState<GPUData>) -> Vec<AlgoResult>>,
     pub result: Arc<Mutex<Vec<AlgoResult>>>,
 }
@@ -108,18 +159,123 @@ pub const STEP_SIZE: usize = 1;
 const TILE_BATCHES_IN_PARALLEL: usize = 20;
 const CHUNK_MULT: u32 = 4;
 pub const TILE_CHUNK_SIZE: usize = (CHUNK_MULT * CHUNK_MULT) as usize;
+/// Width and height of an atlas made by [`mk_tile_atlas`]
+pub const TILE_ATLAS_SIDE: u32 = CHUNK_MULT * TILE_HEIGHT;
+
+const MAIN_PASS: &str = "main_pass_zoom_bins";
+
+/// Most angles searched in one pass, `MAX_ROTATIONS` of the kernel
+pub const MAX_ROTATIONS: usize = 7;
+/// Most zooms searched in one pass, `MAX_ZOOMS` of the kernel
+pub const MAX_ZOOMS: usize = 8;
+
+/// The zooms and angles every position is searched at. [`MAIN_PASS`] keeps the best pair
+/// of each position, as the bin `zoom_index + angle_index * zooms.len()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchBins {
+    /// Tile pixels per mask pixel, see [`PosResult::zoom`]
+    pub zooms: Vec<f32>,
+    /// Degrees clockwise, see [`PosResult::angle`]
+    pub angles: Vec<f32>,
+}
+
+impl SearchBins {
+    /// Checks that the kernel can search these bins for masks of `mask_size`: the mask
+    /// must fit, at every zoom, in the area searched from each position (its extended
+    /// footprint, 3/2 of its size)
+    pub fn check(&self, mask_size: (u32, u32)) -> Result<(), String> {
+        if self.zooms.is_empty() || self.zooms.len() > MAX_ZOOMS {
+            return Err(format!("1 to {} zooms can be searched", MAX_ZOOMS));
+        }
+        if self.angles.is_empty() || self.angles.len() > MAX_ROTATIONS {
+            return Err(format!("1 to {} angles can be searched", MAX_ROTATIONS));
+        }
+        let max_zoom = |side: u32| (side * 3 / 2) as f32 / side as f32;
+        let max_zoom = max_zoom(mask_size.0).min(max_zoom(mask_size.1));
+        if let Some(zoom) = self.zooms.iter().find(|&&z| !(z > 0.0 && z <= max_zoom)) {
+            return Err(format!(
+                "zoom {} is not within 0-{} for {}x{} masks",
+                zoom, max_zoom, mask_size.0, mask_size.1
+            ));
+        }
+        if let Some(angle) = self.angles.iter().find(|a| !(-180.0..=180.0).contains(*a)) {
+            return Err(format!("angle {} is not within ±180", angle));
+        }
+        Ok(())
+    }
+
+    /// The `zooms` of the kernel: mask pixels per tile pixel
+    fn kernel_zooms(&self) -> [f32; MAX_ZOOMS] {
+        let mut zooms = [0.0; MAX_ZOOMS];
+        for (kernel_zoom, zoom) in zooms.iter_mut().zip(&self.zooms) {
+            *kernel_zoom = 1.0 / zoom;
+        }
+        zooms
+    }
+
+    /// Zoom and angle of the bin a result was found in
+    fn decode(&self, bin: u32) -> (f32, f32) {
+        let bin = bin as usize;
+        let zoom = self.zooms[bin % self.zooms.len()];
+        let angle = self
+            .angles
+            .get(bin / self.zooms.len())
+            .copied()
+            .unwrap_or(0.0);
+        (zoom, angle)
+    }
+}
+
+impl Default for SearchBins {
+    fn default() -> Self {
+        SearchBins {
+            zooms: vec![1.5, 1.3333, 1.1666, 1.0],
+            angles: vec![0.0],
+        }
+    }
+}
+
+/// Sets the zooms searched by [`MAIN_PASS`], which reads them from the uniform
+pub fn set_kernel_zooms(data: &mut GPUData, bins: &SearchBins) {
+    data.n_zooms = bins.zooms.len() as u32;
+    data.zooms = bins.kernel_zooms();
+}
+
+/// The push constants of [`MAIN_PASS`]: the tile widths, then the cos and sin of each
+/// angle and their count
+fn main_pass_push_constant(tiles: &[Tile], angles: &[f32]) -> Vec<u32> {
+    let mut words: Vec<u32> = tiles.iter().map(|v| v.width).collect();
+    words.resize(TILE_CHUNK_SIZE, 0);
+    for i in 0..MAX_ROTATIONS {
+        let (sin, cos) = angles
+            .get(i)
+            .map_or((0.0, 1.0), |a| a.to_radians().sin_cos());
+        words.extend([cos.to_bits(), sin.to_bits()]);
+    }
+    words.extend([angles.len() as u32, 0]);
+    words
+}
 
 impl Algo {
+    /// Searches the masks at each zoom and angle of `bins`, which must have been set in
+    /// the uniform with [`set_kernel_zooms`]
     pub fn new(
         device: Arc<Device>,
         mask_size: (u32, u32),
         n_masks: usize,
-        n_extra_positions: usize,
+        top_k: usize,
+        bins: &SearchBins,
     ) -> Algo {
+        crate::mask::check_kernel_profile(MAIN_PASS);
+        if let Err(e) = bins.check(mask_size) {
+            panic!("invalid search bins: {}", e);
+        }
+        let bins = bins.clone();
+
         let algo_result = Arc::new(Mutex::new(
             (0..n_masks)
                 .map(|_| AlgoResult {
-                    best_pos: PosResults::new(n_masks + n_extra_positions),
+                    best_pos: PosResults::new(top_k),
                     tile_max_scores: FxHashMap::default(),
                 })
                 .collect(),
@@ -150,13 +306,7 @@ impl Algo {
                 )
             })
             .collect::<Vec<_>>();
-        let batched_tile_tex = mk_tex_general(
-            &device,
-            (CHUNK_MULT * TILE_HEIGHT, CHUNK_MULT * TILE_HEIGHT),
-            TextureFormat::Rgba8Unorm,
-            1,
-            3,
-        );
+        let batched_tile_tex = mk_tile_atlas(&device);
 
         let free_buffers = Arc::new(Mutex::new(
             (0..(TILE_BATCHES_IN_PARALLEL + 1) * n_masks)
@@ -175,74 +325,10 @@ impl Algo {
 
         Algo {
             result: algo_result.clone(),
+            tile_tex: batched_tile_tex,
             render_frame: Box::new(
-                move |wgpu: &WGPUState<GPUData>, tile_paths: &[Tile], mask_texs, decoded_tiles| {
-                    decoded_tiles.into_iter().enumerate().for_each(
-                        |(batch_i, (entry, smol, width))| {
-                            wgpu.queue.write_texture(
-                                ImageCopyTexture {
-                                    texture: &batched_tile_tex.texture,
-                                    mip_level: 0,
-                                    origin: Origin3d {
-                                        x: (batch_i as u32) % CHUNK_MULT * TILE_HEIGHT,
-                                        y: (batch_i as u32) / CHUNK_MULT * TILE_HEIGHT,
-                                        z: 0,
-                                    },
-                                    aspect: Default::default(),
-                                },
-                                &entry,
-                                wgpu::ImageDataLayout {
-                                    offset: 0,
-                                    bytes_per_row: Some(
-                                        width
-                                            * batched_tile_tex
-                                                .format
-                                                .block_copy_size(None)
-                                                .unwrap(),
-                                    ),
-                                    rows_per_image: Some(TILE_HEIGHT),
-                                },
-                                Extent3d {
-                                    width,
-                                    height: TILE_HEIGHT,
-                                    depth_or_array_layers: 1,
-                                },
-                            );
-
-                            wgpu.queue.write_texture(
-                                ImageCopyTexture {
-                                    texture: &batched_tile_tex.texture,
-                                    mip_level: 2,
-                                    origin: Origin3d {
-                                        x: ((batch_i as u32) % CHUNK_MULT * TILE_HEIGHT) / 4,
-                                        y: ((batch_i as u32) / CHUNK_MULT * TILE_HEIGHT) / 4,
-                                        z: 0,
-                                    },
-                                    aspect: Default::default(),
-                                },
-                                &smol,
-                                wgpu::ImageDataLayout {
-                                    offset: 0,
-                                    bytes_per_row: Some(
-                                        (width / 4)
-                                            * batched_tile_tex
-                                                .format
-                                                .block_copy_size(None)
-                                                .unwrap(),
-                                    ),
-                                    rows_per_image: Some(TILE_HEIGHT / 4),
-                                },
-                                Extent3d {
-                                    width: width / 4,
-                                    height: TILE_HEIGHT / 4,
-                                    depth_or_array_layers: 1,
-                                },
-                            );
-                        },
-                    );
-
-                    wgpu.queue.submit([]);
-
+                move |wgpu: &WGPUState<GPUData>, tile_paths: &[Tile], mask_texs, tile_tex| {
+                    capture::begin(capture::Event::Pass(MAIN_PASS));
                     let mut enc = wgpu
                         .device
                         .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
@@ -250,25 +336,19 @@ impl Algo {
                         let mut pass_encoder =
                             PassEncoder::new(&wgpu.device, &mut enc, &wgpu.uni_bg);
 
-                        let mut widths_push_constant: Vec<u32> =
-                            tile_paths.iter().map(|v| v.width).collect();
-                        while widths_push_constant.len() < (CHUNK_MULT * CHUNK_MULT) as usize {
-                            widths_push_constant.push(0);
-                        }
+                        let push_constant = main_pass_push_constant(tile_paths, &bins.angles);
 
-                        let mut i = 0;
-                        for mask_tex in mask_texs {
-                            let result_tex = &result_frames[i];
+                        for (mask_tex, result_tex) in mask_texs.iter().zip(&result_frames) {
                             pass_encoder.pass(
-                                "main_pass_zoom_bins",
+                                MAIN_PASS,
                                 result_tex,
-                                &[mask_tex, batched_tile_tex],
-                                bytemuck::cast_slice(&widths_push_constant),
+                                &[mask_tex, tile_tex],
+                                bytemuck::cast_slice(&push_constant),
                             );
-                            i += 1;
                         }
                     }
                     wgpu.queue.submit(Some(enc.finish()));
+                    capture::end(capture::Event::Pass(MAIN_PASS));
 
                     let mut enc = wgpu
                         .device
@@ -285,7 +365,7 @@ impl Algo {
                         enc.copy_texture_to_buffer(
                             result_tex.texture.as_image_copy(),
                             wgpu::ImageCopyBuffer {
-                                buffer: &result_buf,
+                                buffer: result_buf,
                                 layout: wgpu::ImageDataLayout {
                                     offset: 0,
                                     bytes_per_row: Some(
@@ -303,12 +383,10 @@ impl Algo {
 
                     let wait_for_data = Arc::new(AtomicU32::new(0));
 
-                    let mut i_buf = 0;
-                    for mask_i in 0..n_masks {
+                    for (mask_i, result_buf) in buffers.iter().enumerate() {
                         wait_for_data.fetch_add(1, Ordering::SeqCst);
 
-                        let result_buf = buffers[i_buf].clone();
-                        i_buf += 1;
+                        let result_buf = result_buf.clone();
 
                         let result_buf_cpy = result_buf.clone();
                         let free_buffers = free_buffers.clone();
@@ -320,6 +398,7 @@ impl Algo {
                             .iter()
                             .map(|t| (t.pos(), t.width))
                             .collect::<Vec<_>>();
+                        let bins = bins.clone();
 
                         result_buf.slice(..).map_async(MapMode::Read, move |done| {
                             if done.is_err() {
@@ -365,7 +444,7 @@ impl Algo {
                                                     continue;
                                                 }
 
-                                                let zoom = pixel[1];
+                                                let (zoom, angle) = bins.decode(pixel[1] as u32);
 
                                                 let pos = &mut tile_best_poses[i_tile];
                                                 pos.tile_x = tile_x;
@@ -376,6 +455,7 @@ impl Algo {
                                                 pos.y = (y as u32) % (TILE_HEIGHT - mask_size.1);
                                                 pos.score = score;
                                                 pos.zoom = zoom;
+                                                pos.angle = angle;
                                             }
                                         }
                                     }
@@ -425,8 +505,7 @@ impl Algo {
                                                     continue;
                                                 }
 
-                                                let zoom_part = ((packed >> 16) & 0xFFFF) as u16;
-                                                let zoom = half::f16::from_bits(zoom_part).to_f32();
+                                                let (zoom, angle) = bins.decode(packed >> 16);
 
                                                 let pos = &mut tile_best_poses[i_tile];
                                                 pos.tile_x = tile_x;
@@ -437,6 +516,7 @@ impl Algo {
                                                 pos.y = (y as u32) % (TILE_HEIGHT - mask_size.1);
                                                 pos.score = score;
                                                 pos.zoom = zoom;
+                                                pos.angle = angle;
                                             }
                                         }
                                     }
@@ -446,7 +526,10 @@ impl Algo {
                                 free_buffers.lock().unwrap().push(result_buf_cpy);
 
                                 let mut algo_res = algo_result.lock().unwrap();
-                                for best_pos in tile_best_poses {
+                                // tiles with no width, forbidden ones included, found nothing
+                                for best_pos in
+                                    tile_best_poses.into_iter().filter(|p| p.tile_x != u32::MAX)
+                                {
                                     algo_res[mask_i].best_pos.insert(best_pos);
                                     algo_res[mask_i].tile_max_scores.insert(
                                         (best_pos.tile_x, best_pos.tile_y, best_pos.tile_z),
@@ -484,28 +567,105 @@ impl Algo {
     }
 }
 
-impl PosResult {
-    pub fn calc_error(&self, mask: &Mask, mut adderror: impl FnMut(u32, u32, f32)) {
-        let path_grad = format!(
-            "data/tiles_grad/{}/{}/{}.png",
-            self.tile_z, self.tile_y, self.tile_x
+/// Atlas of a chunk of tiles, [`CHUNK_MULT`] by [`CHUNK_MULT`] of them. Mip 0 holds the
+/// gradients and mip 2 the quarter resolution tiles.
+pub fn mk_tile_atlas(device: &Device) -> GPUTexture {
+    mk_tex_general(
+        device,
+        (TILE_ATLAS_SIDE, TILE_ATLAS_SIDE),
+        TextureFormat::Rgba8Unorm,
+        1,
+        3,
+    )
+}
+
+/// Bytes of VRAM taken by a tile atlas
+pub fn tile_atlas_size() -> u64 {
+    let side = TILE_ATLAS_SIDE as u64;
+    (0..3).map(|mip| (side >> mip) * (side >> mip) * 4).sum()
+}
+
+/// Writes a chunk of tiles, given with their gradient and quarter resolution pixels and
+/// their width, in an atlas made by [`mk_tile_atlas`]
+pub fn upload_tiles(queue: &Queue, atlas: GPUTexture, tiles: &[(&[u8], &[u8], u32)]) {
+    let pixel_size = atlas.format.block_copy_size(None).unwrap();
+    for (batch_i, &(entry, smol, width)) in tiles.iter().enumerate() {
+        let origin = Origin3d {
+            x: (batch_i as u32) % CHUNK_MULT * TILE_HEIGHT,
+            y: (batch_i as u32) / CHUNK_MULT * TILE_HEIGHT,
+            z: 0,
+        };
+
+        queue.write_texture(
+            ImageCopyTexture {
+                texture: &atlas.texture,
+                mip_level: 0,
+                origin,
+                aspect: Default::default(),
+            },
+            entry,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(width * pixel_size),
+                rows_per_image: Some(TILE_HEIGHT),
+            },
+            Extent3d {
+                width,
+                height: TILE_HEIGHT,
+                depth_or_array_layers: 1,
+            },
         );
-        let tile_grad = image::open(&path_grad).unwrap().to_rgb8();
+
+        queue.write_texture(
+            ImageCopyTexture {
+                texture: &atlas.texture,
+                mip_level: 2,
+                origin: Origin3d {
+                    x: origin.x / 4,
+                    y: origin.y / 4,
+                    z: 0,
+                },
+                aspect: Default::default(),
+            },
+            smol,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some((width / 4) * pixel_size),
+                rows_per_image: Some(TILE_HEIGHT / 4),
+            },
+            Extent3d {
+                width: width / 4,
+                height: TILE_HEIGHT / 4,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    queue.submit([]);
+}
+
+impl PosResult {
+    pub fn calc_error(
+        &self,
+        layout: &DataLayout,
+        mask: &Mask,
+        mut adderror: impl FnMut(u32, u32, f32),
+    ) -> Result<()> {
+        let path_grad = layout.tile_grad(self.tile_pos());
+        let tile_grad = open_image(&path_grad)?.to_rgb8();
 
         for yy in 0..mask.height() {
             for xx in 0..mask.width() {
+                let (off_x, off_y) = self.mask_offset(xx as f32, yy as f32, mask.dimensions(), 1);
                 let tile_pixel = *tile_grad.get_pixel(
-                    (self.x + (xx as f32 * self.zoom) as u32).min(tile_grad.width() - 1),
-                    (self.y + (yy as f32 * self.zoom) as u32).min(tile_grad.height() - 1),
+                    offset_pixel(self.x, off_x).min(tile_grad.width() - 1),
+                    offset_pixel(self.y, off_y).min(tile_grad.height() - 1),
                 );
-                let mask_pixel = mask.get_pixel(xx, yy);
-
                 let pr = tile_pixel[0] as f32 / 255.0;
                 let pg = tile_pixel[1] as f32 / 255.0;
 
-                let mr = mask_pixel[0] as f32 / 255.0;
-                let mg = mask_pixel[1] as f32 / 255.0;
-                let mb = mask_pixel[2] as f32 / 255.0;
+                let [mr, mg] = mask.grad(xx, yy);
+                let mb = mask.surround(xx, yy);
 
                 let diff = [
                     f32::max(0.0, mr - pr),
@@ -518,33 +678,40 @@ impl PosResult {
                 adderror(xx, yy, error);
             }
         }
+
+        Ok(())
     }
 
-    pub fn to_rgba_quarter(self, mask_dims: (u32, u32)) -> RgbaImage {
-        let path_tile = format!(
-            "data/tiles/{}/{}/{}.png",
-            self.tile_z, self.tile_y, self.tile_x
-        );
-        let mut tile = image::open(&path_tile).unwrap().to_rgb8();
+    pub fn to_rgba_quarter(self, layout: &DataLayout, mask_dims: (u32, u32)) -> Result<RgbaImage> {
+        let path_tile = layout.tile(self.tile_pos());
+        let mut tile = open_image(&path_tile)?.to_rgb8();
         let deform_w = deform_width(TILE_HEIGHT, self.tile_y, self.tile_z);
         tile = image::imageops::resize(&tile, deform_w / 4, TILE_HEIGHT / 4, FilterType::Triangle);
 
-        let mut mask_rgba = RgbaImage::new(mask_dims.0 / 4, mask_dims.1 / 4);
+        let quarter_dims = (mask_dims.0 / 4, mask_dims.1 / 4);
+        let mut mask_rgba = RgbaImage::new(quarter_dims.0, quarter_dims.1);
 
         for (xx, yy, pixel) in mask_rgba.enumerate_pixels_mut() {
+            let (off_x, off_y) = self.mask_offset(xx as f32, yy as f32, quarter_dims, 1);
             let pixel_tile = *tile.get_pixel(
-                (((self.x as f32 + xx as f32 * self.zoom) / 4.0) as u32).min(tile.width() - 1),
-                (((self.y as f32 + yy as f32 * self.zoom) / 4.0) as u32).min(tile.height() - 1),
+                (((self.x as f32 + off_x) / 4.0).max(0.0) as u32).min(tile.width() - 1),
+                (((self.y as f32 + off_y) / 4.0).max(0.0) as u32).min(tile.height() - 1),
             );
             *pixel = Rgba([pixel_tile[0], pixel_tile[1], pixel_tile[2], 255]);
         }
 
-        mask_rgba
+        Ok(mask_rgba)
     }
 
-    pub fn to_image(self, mask_data: &RgbaImage, avg_error: &Rgb32FImage, debug: bool) -> RgbImage {
+    pub fn to_image(
+        self,
+        layout: &DataLayout,
+        mask_data: &RgbaImage,
+        avg_error: &Rgb32FImage,
+        debug: bool,
+    ) -> Result<RgbImage> {
         if self.tile_x == u32::MAX {
-            return RgbImage::new(1, 1);
+            return Ok(RgbImage::new(1, 1));
         }
         const Z_UP: u32 = 2;
         const UPSCALE: u32 = 1 << Z_UP;
@@ -553,29 +720,20 @@ impl PosResult {
 
         let deform_w = deform_width(TILE_HEIGHT, self.tile_y, self.tile_z);
 
-        let mut path = PathBuf::new();
-        path.push("data");
-        path.push("tiles");
-        path.push((self.tile_z + Z_UP).to_string());
-
-        let path_grad = format!(
-            "data/tiles_grad/{}/{}/{}.png",
-            self.tile_z, self.tile_y, self.tile_x
-        );
-        let tile_grad = image::open(&path_grad).unwrap().to_rgb8();
+        let path_grad = layout.tile_grad(self.tile_pos());
+        let tile_grad = open_image(&path_grad)?.to_rgb8();
 
         let tiles_to_open = tiles_needed(mask_size, &self, Z_UP);
 
         let tiles = tiles_to_open
             .into_iter()
-            .map(|pos @ (x, y, z)| {
-                let path = format!("./data/tiles/{z}/{y}/{x}.png");
-                let mut image = image::open(path).unwrap().to_rgb8();
+            .map(|pos| {
+                let mut image = open_image(&layout.tile(pos))?.to_rgb8();
                 image =
                     image::imageops::resize(&image, deform_w, TILE_HEIGHT, FilterType::Lanczos3);
-                (pos, image)
+                Ok((pos, image))
             })
-            .collect::<FxHashMap<_, _>>();
+            .collect::<Result<FxHashMap<_, _>>>()?;
 
         let img_width = if debug {
             mask_size.0 * UPSCALE * 4
@@ -585,10 +743,13 @@ impl PosResult {
 
         let mut img = RgbImage::new(img_width, mask_size.1 * UPSCALE);
 
+        let up_size = (mask_size.0 * UPSCALE, mask_size.1 * UPSCALE);
+
         for yy in 0..mask_size.1 * UPSCALE {
             for xx in 0..mask_size.0 * UPSCALE {
-                let up_x = (self.x * STEP_SIZE as u32) * UPSCALE + (xx as f32 * self.zoom) as u32;
-                let up_y = (self.y * STEP_SIZE as u32) * UPSCALE + (yy as f32 * self.zoom) as u32;
+                let (off_x, off_y) = self.mask_offset(xx as f32, yy as f32, up_size, UPSCALE);
+                let up_x = offset_pixel((self.x * STEP_SIZE as u32) * UPSCALE, off_x);
+                let up_y = offset_pixel((self.y * STEP_SIZE as u32) * UPSCALE, off_y);
 
                 let up_tile_x = self.tile_x * UPSCALE + up_x / deform_w;
                 let up_tile_y = self.tile_y * UPSCALE + up_y / TILE_HEIGHT;
@@ -620,10 +781,11 @@ impl PosResult {
                         From::from([pixel_err, pixel_err, pixel_err]),
                     );
 
+                    let (off_x, off_y) = self.mask_offset(xx as f32, yy as f32, up_size, UPSCALE);
                     let pixel_grad = *tile_grad.get_pixel(
-                        ((self.x * STEP_SIZE as u32) + (xx as f32 * self.zoom) as u32 / UPSCALE)
+                        offset_pixel(self.x * STEP_SIZE as u32, off_x / UPSCALE as f32)
                             .min(tile_grad.width() - 1),
-                        ((self.y * STEP_SIZE as u32) + (yy as f32 * self.zoom) as u32 / UPSCALE)
+                        offset_pixel(self.y * STEP_SIZE as u32, off_y / UPSCALE as f32)
                             .min(tile_grad.height() - 1),
                     );
                     img.put_pixel(xx + mask_size.0 * UPSCALE * 2, yy, pixel_grad);
@@ -631,6 +793,71 @@ impl PosResult {
             }
         }
 
-        img
+        Ok(img)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{main_pass_push_constant, PosResult, SearchBins, MAX_ROTATIONS};
+
+    fn assert_close((ax, ay): (f32, f32), (bx, by): (f32, f32)) {
+        assert!(
+            (ax - bx).abs() < 1e-4 && (ay - by).abs() < 1e-4,
+            "{ax},{ay} != {bx},{by}"
+        );
+    }
+
+    #[test]
+    fn turned_masks() {
+        let mut pos = PosResult {
+            zoom: 0.75,
+            ..PosResult::default()
+        };
+        assert_eq!(pos.mask_offset(5.0, 3.0, (32, 24), 1), (3.75, 2.25));
+
+        // a quarter turn clockwise around the center (16, 12)
+        pos.angle = 90.0;
+        pos.zoom = 1.0;
+        assert_close(pos.mask_offset(16.0, 12.0, (32, 24), 1), (16.0, 12.0));
+        assert_close(pos.mask_offset(0.0, 0.0, (32, 24), 1), (28.0, -4.0));
+        assert_close(pos.mask_offset(32.0, 24.0, (32, 24), 1), (4.0, 28.0));
+
+        // a sub-pixel is as many pixels as the tile is drawn larger
+        pos.sub_x = 0.25;
+        assert_close(pos.mask_offset(64.0, 48.0, (128, 96), 4), (65.0, 48.0));
+
+        let words = main_pass_push_constant(&[], &[0.0, -90.0]);
+        assert_eq!(words.len() * 4, 128);
+        let float = |i: usize| f32::from_bits(words[i]);
+        assert_eq!((float(16), float(17)), (1.0, 0.0));
+        assert_close((float(18), float(19)), (0.0, -1.0));
+        assert_eq!(words[16 + 2 * MAX_ROTATIONS], 2);
+    }
+
+    #[test]
+    fn search_bins() {
+        let bins = SearchBins {
+            zooms: vec![1.25, 0.9, 1.1],
+            angles: vec![0.0, -90.0],
+        };
+        assert_eq!(bins.check((32, 24)), Ok(()));
+        assert_eq!(bins.decode(0), (1.25, 0.0));
+        assert_eq!(bins.decode(4), (0.9, -90.0));
+        assert_eq!(bins.kernel_zooms()[..4], [0.8, 1.0 / 0.9, 1.0 / 1.1, 0.0]);
+        assert_eq!(SearchBins::default().decode(1), (1.3333, 0.0));
+
+        for (zooms, angles) in [
+            (vec![], vec![0.0]),
+            (vec![1.0; 9], vec![0.0]),
+            (vec![1.0, 1.51], vec![0.0]),
+            (vec![0.0], vec![0.0]),
+            (vec![1.0], vec![]),
+            (vec![1.0], vec![200.0]),
+        ] {
+            assert!(SearchBins { zooms, angles }.check((32, 24)).is_err());
+        }
+        // 3/2 of 33 rounds down, the footprint is less than 1.5 masks wide
+        assert!(SearchBins::default().check((33, 24)).is_err());
     }
 }
@@ -0,0 +1,195 @@
+//! Opt-in RenderDoc captures of selected GPU work, see `--capture`.
+//!
+//! RenderDoc is only loaded when a capture is requested, and only in builds with the
+//! `renderdoc` feature. The process must be launched from RenderDoc for it to be found.
+
+use lazy_static::lazy_static;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// The GPU work to capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureTarget {
+    /// The N-th search (call to `State::run_on_image`) of the process, starting at 0
+    Run(usize),
+    /// The search of this frame
+    Frame(u32),
+    /// The first submission of this kernel
+    Pass(String),
+}
+
+impl FromStr for CaptureTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected run:N, frame:N or pass:NAME, got {:?}", s))?;
+        let number = |value: &str| {
+            value
+                .parse()
+                .map_err(|_| format!("invalid number {:?} in {:?}", value, s))
+        };
+        match kind {
+            "run" => Ok(CaptureTarget::Run(number(value)? as usize)),
+            "frame" => Ok(CaptureTarget::Frame(number(value)?)),
+            "pass" if !value.is_empty() => Ok(CaptureTarget::Pass(value.to_string())),
+            _ => Err(format!("expected run:N, frame:N or pass:NAME, got {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for CaptureTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureTarget::Run(n) => write!(f, "run:{}", n),
+            CaptureTarget::Frame(n) => write!(f, "frame:{}", n),
+            CaptureTarget::Pass(name) => write!(f, "pass:{}", name),
+        }
+    }
+}
+
+/// GPU work that can be captured, reported by the code submitting it
+pub(crate) enum Event<'a> {
+    /// A search over all the tiles for masks of these frames
+    Run { frames: &'a [u32] },
+    /// The submission of a kernel
+    Pass(&'a str),
+}
+
+struct Capture {
+    target: CaptureTarget,
+    runs: usize,
+    capturing: bool,
+    done: bool,
+    #[cfg(feature = "renderdoc")]
+    rd: renderdoc::RenderDoc<renderdoc::V141>,
+}
+
+impl Capture {
+    fn matches(&self, event: &Event) -> bool {
+        match (&self.target, event) {
+            (CaptureTarget::Run(n), Event::Run { .. }) => *n == self.runs,
+            (CaptureTarget::Frame(frame), Event::Run { frames }) => frames.contains(frame),
+            (CaptureTarget::Pass(name), Event::Pass(pass)) => name == pass,
+            _ => false,
+        }
+    }
+
+    fn start(&mut self) {
+        eprintln!("Starting RenderDoc capture of {}", self.target);
+        #[cfg(feature = "renderdoc")]
+        self.rd
+            .start_frame_capture(std::ptr::null(), std::ptr::null());
+        self.capturing = true;
+    }
+
+    fn end(&mut self) {
+        #[cfg(feature = "renderdoc")]
+        self.rd
+            .end_frame_capture(std::ptr::null(), std::ptr::null());
+        self.capturing = false;
+        self.done = true;
+        // leave RenderDoc the time to write the capture
+        std::thread::sleep(std::time::Duration::from_millis(500));
+    }
+}
+
+lazy_static! {
+    static ref CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+}
+
+/// Captures `target` once it runs
+pub fn enable(target: CaptureTarget) -> Result<(), String> {
+    #[cfg(feature = "renderdoc")]
+    {
+        let rd = renderdoc::RenderDoc::new().map_err(|e| {
+            format!(
+                "RenderDoc not found, launch earthfinder from RenderDoc to capture: {}",
+                e
+            )
+        })?;
+        *CAPTURE.lock().unwrap() = Some(Capture {
+            target,
+            runs: 0,
+            capturing: false,
+            done: false,
+            rd,
+        });
+        Ok(())
+    }
+    #[cfg(not(feature = "renderdoc"))]
+    {
+        let _ = target;
+        Err("captures need earthfinder to be built with the `renderdoc` feature".to_string())
+    }
+}
+
+pub(crate) fn begin(event: Event) {
+    let mut capture = CAPTURE.lock().unwrap();
+    let Some(capture) = capture.as_mut() else {
+        return;
+    };
+    if !capture.done && !capture.capturing && capture.matches(&event) {
+        capture.start();
+    }
+    if let Event::Run { .. } = event {
+        capture.runs += 1;
+    }
+}
+
+pub(crate) fn end(event: Event) {
+    let mut capture = CAPTURE.lock().unwrap();
+    let Some(capture) = capture.as_mut() else {
+        return;
+    };
+    let same_kind = matches!(
+        (&capture.target, &event),
+        (
+            CaptureTarget::Run(_) | CaptureTarget::Frame(_),
+            Event::Run { .. }
+        ) | (CaptureTarget::Pass(_), Event::Pass(_))
+    );
+    if capture.capturing && same_kind {
+        capture.end();
+    }
+}
+
+/// Ends a capture still in progress, and warns if the target never ran
+pub fn finish() {
+    let mut capture = CAPTURE.lock().unwrap();
+    let Some(capture) = capture.as_mut() else {
+        return;
+    };
+    if capture.capturing {
+        capture.end();
+    }
+    if !capture.done {
+        eprintln!(
+            "/!\\ Warning: {} never ran, nothing was captured",
+            capture.target
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CaptureTarget;
+
+    #[test]
+    fn parse_capture_target() {
+        assert_eq!("run:3".parse(), Ok(CaptureTarget::Run(3)));
+        assert_eq!("frame:1200".parse(), Ok(CaptureTarget::Frame(1200)));
+        assert_eq!(
+            "pass:main_pass_zoom_bins".parse(),
+            Ok(CaptureTarget::Pass("main_pass_zoom_bins".to_string()))
+        );
+        assert!("frame:x".parse::<CaptureTarget>().is_err());
+        assert!("pass:".parse::<CaptureTarget>().is_err());
+        assert!("everything".parse::<CaptureTarget>().is_err());
+
+        let target = CaptureTarget::Pass("main_pass_zoom_bins".to_string());
+        assert_eq!(target.to_string().parse(), Ok(target));
+    }
+}
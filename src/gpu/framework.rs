@@ -271,8 +271,12 @@ fn get_compute_pipeline(
     }
 }
 
-fn get_shader_source(kernel_name: &str) -> String {
-    std::fs::read_to_string(format!("kernels/{}.wgsl", kernel_name)).expect("Kernel not found")
+pub fn get_shader_source(kernel_name: &str) -> String {
+    std::fs::read_to_string(format!(
+        concat!(env!("CARGO_MANIFEST_DIR"), "/kernels/{}.wgsl"),
+        kernel_name
+    ))
+    .expect("Kernel not found")
 }
 
 fn mk_compute_pipeline(
@@ -348,7 +352,7 @@ fn mk_pipeline(
         ],
         push_constant_ranges: &[PushConstantRange {
             stages: ShaderStages::FRAGMENT,
-            range: 0..64,
+            range: 0..128,
         }],
     });
 
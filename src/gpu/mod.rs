@@ -1,37 +1,48 @@
 pub mod algorithm;
+pub mod capture;
 pub mod framework;
 pub mod state;
+pub mod tile_cache;
 
-use crate::data::{deform_width, extract_tile_pos, TilePos};
-use crate::gpu::algorithm::{AlgoResult, TILE_CHUNK_SIZE};
+use crate::data::TilePos;
+use crate::error::{self, EarthfinderError, Result};
+use crate::geo::deform_width;
+use crate::gpu::algorithm::{AlgoResult, SearchBins, TILE_CHUNK_SIZE};
 use crate::gpu::state::WGPUState;
+use crate::layout::DataLayout;
+use crate::search::TileIndex;
+use crate::tile_pack::TileBytes;
 use crate::TILE_HEIGHT;
 use algorithm::Algo;
 use bytemuck::Zeroable;
 use framework::*;
 use image::RgbaImage;
-use rustc_hash::FxHashSet;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::borrow::Cow;
 use std::sync::Arc;
-use walkdir::DirEntry;
+use tile_cache::{DecodedTiles, TileGrad};
 use wgpu::{ImageCopyTexture, TextureFormat};
 
 #[derive(Default, Copy, Clone)]
+#[repr(C)]
 pub struct GPUData {
     pub total1: f32,
     pub total2: f32,
+    /// See [`algorithm::set_kernel_zooms`]
+    pub n_zooms: u32,
+    _pad: u32,
+    pub zooms: [f32; algorithm::MAX_ZOOMS],
 }
 
 unsafe impl Zeroable for GPUData {}
 unsafe impl bytemuck::Pod for GPUData {}
 
-#[derive(Clone)]
+#[derive(Copy, Clone)]
 pub struct Tile {
     pub x: u32,
     pub y: u32,
     pub z: u32,
     pub width: u32,
-    pub data: Arc<Vec<u8>>,
-    pub smol_data: Arc<Vec<u8>>,
 }
 
 impl Tile {
@@ -45,65 +56,184 @@ pub struct State {
     algo: Algo,
     n_masks: usize,
     tiles: Vec<Tile>,
+    /// Index in `tiles` of each tile
+    by_pos: FxHashMap<TilePos, usize>,
+    /// Pixels of `tiles`, in the same order, released once they are resident
+    decoded: Option<DecodedTiles>,
+    /// Encoded gradient of `tiles`, to refine the places found once the pixels are released
+    encoded: Vec<TileBytes>,
+    /// Atlases holding `tiles` by chunks of [`TILE_CHUNK_SIZE`], see [`State::make_resident`]
+    resident: Vec<GPUTexture>,
 }
 
 impl State {
-    pub async fn new(mask_size: (u32, u32), n_masks: usize, n_extra_positions: usize) -> State {
-        let wgpu = WGPUState::new().await;
+    /// Searches `n_masks` masks at a time, at each zoom and angle of `bins`
+    pub async fn new(
+        mask_size: (u32, u32),
+        n_masks: usize,
+        top_k: usize,
+        bins: &SearchBins,
+    ) -> State {
+        let wgpu = WGPUState::<GPUData>::new().await;
         let device = &wgpu.device;
 
-        let algo = Algo::new(device.clone(), mask_size, n_masks, n_extra_positions);
+        WGPUState::modify_user_data(&wgpu.queue, &wgpu.user_data, &|u| {
+            algorithm::set_kernel_zooms(u, bins);
+        });
+        let algo = Algo::new(device.clone(), mask_size, n_masks, top_k, bins);
 
         Self {
             wgpu,
             algo,
             n_masks,
             tiles: Default::default(),
+            by_pos: Default::default(),
+            decoded: None,
+            encoded: vec![],
+            resident: vec![],
         }
     }
 
-    pub fn prepare(&mut self, tile_paths: &[DirEntry]) {
+    /// Reads and decodes the preprocessed tiles, keeping up to `cache_budget` bytes of
+    /// pixels in RAM, see [`DecodedTiles`]. Tiles that can't be read are reported and
+    /// left out of the search.
+    pub fn prepare(
+        &mut self,
+        layout: &DataLayout,
+        index: &TileIndex,
+        cache_budget: u64,
+    ) -> Result<()> {
+        let tile_paths = index.entries();
         use rayon::prelude::*;
-        eprint!("Reading {} tiles data from disk...", tile_paths.len());
-        self.tiles = tile_paths
+        eprint!("Reading {} tiles data from disk...", index.len());
+        let (mut tiles, mut errors): (Vec<_>, Vec<_>) = tile_paths
             .par_iter()
-            .map(|entry| {
-                let path = entry.path();
-
-                let tile_image_data =
-                    std::fs::read(path).expect("could not read preprocessed data");
-
-                let tile_smol_path = path
-                    .display()
-                    .to_string()
-                    .replace("tiles_grad", "tiles_smol");
-                let tile_smol_data =
-                    std::fs::read(tile_smol_path).expect("could not read preprocessed data");
-
-                let path_str = path.to_string_lossy();
-
-                let (x, y, z) = extract_tile_pos(&path_str);
-
-                Tile {
-                    x,
-                    y,
-                    z,
-                    width: deform_width(TILE_HEIGHT, y, z),
-                    data: Arc::new(tile_image_data),
-                    smol_data: Arc::new(tile_smol_data),
-                }
-            })
-            .collect::<Vec<_>>();
+            .map(|entry| read_tile(layout, entry.path()))
+            .partition_map(|tile| match tile {
+                Ok(tile) => rayon::iter::Either::Left(tile),
+                Err(e) => rayon::iter::Either::Right(e),
+            });
+        for pack in index.packs() {
+            tiles.extend(
+                pack.tiles()
+                    .map(|((x, y, z), data, smol_data)| (tile(x, y, z), (data, smol_data))),
+            );
+        }
+        eprintln!("done");
 
-        #[cfg(not(windows))]
-        const GPU_RESULTS_FOLDER: &str = "data/results/gpu";
+        eprint!("Decoding {} tiles...", tiles.len());
+        let (tiles, encoded): (Vec<_>, Vec<_>) = tiles.into_iter().unzip();
+        let (decoded, decode_errors) =
+            DecodedTiles::decode(&encoded, cache_budget, &layout.decoded_tiles_cache())?;
+        errors.extend(
+            decode_errors
+                .into_iter()
+                .map(|(i, e)| EarthfinderError::image(layout.tile_grad(tiles[i].pos()), e)),
+        );
+        self.tiles = decoded.sources().iter().map(|&i| tiles[i]).collect();
+        self.by_pos = self
+            .tiles
+            .iter()
+            .enumerate()
+            .map(|(i, tile)| (tile.pos(), i))
+            .collect();
+        self.encoded = decoded
+            .sources()
+            .iter()
+            .map(|&i| encoded[i].0.clone())
+            .collect();
+        drop(encoded);
+        self.decoded = Some(decoded);
+
+        let gpu_results_folder = layout.gpu_results_dir();
+
+        let _ = std::fs::remove_dir_all(&gpu_results_folder);
+        std::fs::create_dir_all(&gpu_results_folder)
+            .map_err(|e| EarthfinderError::io(&gpu_results_folder, e))?;
+        eprintln!("done");
+        error::report("tiles", &errors);
+        Ok(())
+    }
 
-        #[cfg(windows)]
-        const GPU_RESULTS_FOLDER: &str = "data\\results\\gpu";
+    /// Uploads every prepared tile to the GPU once, in atlases taking up to `budget` bytes
+    /// of VRAM, so that each search only uploads its masks. Returns false, keeping the
+    /// tiles streamed from RAM for every search, when they don't fit.
+    pub fn make_resident(&mut self, budget: u64) -> bool {
+        if budget == 0 {
+            return false;
+        }
+        let max_side = self.wgpu.device.limits().max_texture_dimension_2d;
+        if algorithm::TILE_ATLAS_SIDE > max_side {
+            eprintln!(
+                "Tile atlases over the {} pixels textures of the device, streaming them",
+                max_side
+            );
+            return false;
+        }
+
+        let n_atlases = self.tiles.len().div_ceil(TILE_CHUNK_SIZE);
+        let needed = n_atlases as u64 * algorithm::tile_atlas_size();
+        if needed > budget {
+            eprintln!(
+                "{} MB of resident tiles over the {} MB budget, streaming them",
+                needed >> 20,
+                budget >> 20
+            );
+            return false;
+        }
+        let Some(decoded) = &self.decoded else {
+            return false;
+        };
+
+        eprint!(
+            "Uploading {} tiles ({} MB)...",
+            self.tiles.len(),
+            needed >> 20
+        );
+        let device = &self.wgpu.device;
+        device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+        let chunks = (0..self.tiles.len()).collect::<Vec<_>>();
+        for chunk in chunks.chunks(TILE_CHUNK_SIZE) {
+            let atlas = algorithm::mk_tile_atlas(device);
+            let pixels = chunk
+                .iter()
+                .map(|&i| {
+                    let (data, smol) = decoded.get(i);
+                    (data, smol, self.tiles[i].width)
+                })
+                .collect::<Vec<_>>();
+            algorithm::upload_tiles(&self.wgpu.queue, atlas, &pixels);
+            self.resident.push(atlas);
+        }
+        device.poll(wgpu::Maintain::Wait);
 
-        let _ = std::fs::remove_dir_all(GPU_RESULTS_FOLDER);
-        std::fs::create_dir_all(GPU_RESULTS_FOLDER).unwrap();
+        if let Some(e) = pollster::block_on(device.pop_error_scope()) {
+            eprintln!("failed ({}), streaming them", e);
+            for atlas in self.resident.drain(..) {
+                atlas.texture.destroy();
+            }
+            return false;
+        }
         eprintln!("done");
+        // the pixels are only needed to stream the tiles
+        self.decoded = None;
+        true
+    }
+
+    /// Number of tiles searched, tiles that failed to load in [`State::prepare`] excluded
+    pub fn n_tiles(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Gradient pixels of the tile at `pos`, None if it isn't searched. Decoded again once
+    /// the tiles are resident.
+    pub fn tile_grad(&self, pos: TilePos) -> Option<TileGrad<'_>> {
+        let &i = self.by_pos.get(&pos)?;
+        let data = match &self.decoded {
+            Some(decoded) => Cow::Borrowed(decoded.get(i).0),
+            None => Cow::Owned(tile_cache::decode(&self.encoded[i]).ok()?),
+        };
+        TileGrad::from_raw(self.tiles[i].width, TILE_HEIGHT, data)
     }
 
     pub fn run_on_image(
@@ -116,6 +246,9 @@ impl State {
         }
         let t_start = std::time::Instant::now();
 
+        let frames = masks.iter().map(|&(_, i, _)| i).collect::<Vec<_>>();
+        capture::begin(capture::Event::Run { frames: &frames });
+
         self.algo
             .result
             .lock()
@@ -185,7 +318,7 @@ impl State {
                     mip_level: 2,
                     ..mask_tex.texture.as_image_copy()
                 },
-                &last_tile_rgba,
+                last_tile_rgba,
                 wgpu::ImageDataLayout {
                     offset: 0,
                     bytes_per_row: Some(
@@ -209,66 +342,90 @@ impl State {
             u.total2 = 0.0;
         });
 
-        let filtered_tiles = Arc::new(
-            self.tiles
-                .iter()
-                .cloned()
-                .filter(|tile| !forbidden_tiles.contains(&tile.pos()))
-                .collect::<Vec<_>>(),
-        );
-
-        // image decoding thread
-        let filtered_tiles_2 = filtered_tiles.clone();
-        let (decoded_tiles_tx, decoded_tiles_rx) =
-            crossbeam_channel::bounded::<Vec<(Vec<u8>, Vec<u8>, u32)>>(10);
-        rayon::spawn(move || {
-            use rayon::prelude::*;
-
-            for data_chunk in filtered_tiles_2.chunks(TILE_CHUNK_SIZE) {
-                let decoded_chunk = data_chunk
-                    .par_iter()
-                    .map(|entry| {
-                        let pixel_smol = image::load_from_memory(&entry.smol_data)
-                            .expect("could not decode pixels");
-
-                        let pixel_data =
-                            image::load_from_memory(&entry.data).expect("could not decode pixels");
-
-                        /*
-                        let mut pixels_rg =
-                            Vec::with_capacity(TILE_SIZE as usize * TILE_SIZE as usize * 2);
-
-                        for pixel in pixel_data.to_rgb8().chunks_exact(3) {
-                            pixels_rg.push(pixel[0]);
-                            pixels_rg.push(pixel[1]);
-                        }*/
-
-                        (
-                            pixel_data.to_rgba8().into_raw(),
-                            pixel_smol.to_rgba8().into_raw(),
-                            entry.width,
-                        )
+        if self.resident.is_empty() {
+            let decoded = self.decoded.as_ref().expect("tiles are decoded in prepare");
+            let filtered_tiles = (0..self.tiles.len())
+                .filter(|&i| !forbidden_tiles.contains(&self.tiles[i].pos()))
+                .collect::<Vec<_>>();
+
+            for chunk in filtered_tiles.chunks(TILE_CHUNK_SIZE) {
+                let tile_chunk = chunk.iter().map(|&i| self.tiles[i]).collect::<Vec<_>>();
+                let pixels = chunk
+                    .iter()
+                    .map(|&i| {
+                        let (data, smol) = decoded.get(i);
+                        (data, smol, self.tiles[i].width)
                     })
-                    .collect();
-                let Ok(()) = decoded_tiles_tx.send(decoded_chunk) else {
-                    eprintln!("Error sending decoded tiles");
-                    break;
-                };
+                    .collect::<Vec<_>>();
+                algorithm::upload_tiles(&self.wgpu.queue, self.algo.tile_tex, &pixels);
+                (self.algo.render_frame)(&self.wgpu, &tile_chunk, &mask_texs, self.algo.tile_tex);
+            }
+        } else {
+            // forbidden tiles stay in their atlas with no width, so nothing is read back for
+            // them, and atlases left with no tile to search are not dispatched
+            let atlases = self
+                .tiles
+                .chunks(TILE_CHUNK_SIZE)
+                .enumerate()
+                .filter_map(|(i, chunk)| {
+                    let tile_chunk = chunk
+                        .iter()
+                        .map(|&tile| {
+                            if forbidden_tiles.contains(&tile.pos()) {
+                                Tile { width: 0, ..tile }
+                            } else {
+                                tile
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    tile_chunk
+                        .iter()
+                        .any(|tile| tile.width > 0)
+                        .then_some((i, tile_chunk))
+                })
+                .collect::<Vec<_>>();
+
+            for (i, tile_chunk) in atlases {
+                (self.algo.render_frame)(&self.wgpu, &tile_chunk, &mask_texs, self.resident[i]);
             }
-        });
-
-        for (tile_chunk, decoded_tiles) in filtered_tiles
-            .chunks(TILE_CHUNK_SIZE)
-            .zip(decoded_tiles_rx.iter())
-        {
-            (self.algo.render_frame)(&self.wgpu, &tile_chunk, &mask_texs, decoded_tiles);
         }
 
         let best_pos = (self.algo.finish)(&self.wgpu);
+        capture::end(capture::Event::Run { frames: &frames });
 
         (
-            mask_idx.into_iter().zip(best_pos.into_iter()).collect(),
+            mask_idx.into_iter().zip(best_pos).collect(),
             t_start.elapsed(),
         )
     }
 }
+
+fn tile(x: u32, y: u32, z: u32) -> Tile {
+    Tile {
+        x,
+        y,
+        z,
+        width: deform_width(TILE_HEIGHT, y, z),
+    }
+}
+
+fn read_tile(
+    layout: &DataLayout,
+    path: &std::path::Path,
+) -> Result<(Tile, (TileBytes, TileBytes))> {
+    let pos @ (x, y, z) = layout.tile_scheme().parse(path)?;
+
+    let tile_image_data = std::fs::read(path).map_err(|e| EarthfinderError::io(path, e))?;
+
+    let smol_path = layout.tile_smol(pos);
+    let tile_smol_data =
+        std::fs::read(&smol_path).map_err(|e| EarthfinderError::io(smol_path, e))?;
+
+    Ok((
+        tile(x, y, z),
+        (
+            TileBytes::Owned(Arc::new(tile_image_data)),
+            TileBytes::Owned(Arc::new(tile_smol_data)),
+        ),
+    ))
+}
@@ -0,0 +1,220 @@
+//! Decoded tiles, kept for the whole run so that each search only uploads them.
+//!
+//! Tiles are decoded once in [`super::State::prepare`]. They stay in RAM until the
+//! budget is spent, the rest is written to a raw file mapped in memory, leaving the OS
+//! to page them in and out.
+
+use crate::error::{EarthfinderError, Result};
+use crate::tile_pack::TileBytes;
+use image::{ImageBuffer, ImageError, Rgba};
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// Tiles decoded at once
+const DECODE_CHUNK_SIZE: usize = 256;
+
+enum Slot {
+    Ram {
+        data: Vec<u8>,
+        smol: Vec<u8>,
+    },
+    Spilled {
+        data: Range<usize>,
+        smol: Range<usize>,
+    },
+}
+
+/// Gradient pixels of a decoded tile, see [`super::State::tile_grad`]
+pub type TileGrad<'a> = ImageBuffer<Rgba<u8>, Cow<'a, [u8]>>;
+
+/// RGBA pixels of the gradient and quarter resolution tiles
+pub struct DecodedTiles {
+    slots: Vec<Slot>,
+    /// Index in the tiles given to [`DecodedTiles::decode`] of each slot
+    sources: Vec<usize>,
+    spill: Option<Mmap>,
+}
+
+impl DecodedTiles {
+    /// Decodes `tiles`, keeping up to `budget` bytes in RAM and spilling the rest to
+    /// `spill_path`. Tiles that can't be decoded are left out and returned with their
+    /// index in `tiles`, see [`DecodedTiles::sources`].
+    pub fn decode(
+        tiles: &[(TileBytes, TileBytes)],
+        budget: u64,
+        spill_path: &Path,
+    ) -> Result<(DecodedTiles, Vec<(usize, ImageError)>)> {
+        use rayon::prelude::*;
+
+        let mut slots = Vec::with_capacity(tiles.len());
+        let mut sources = Vec::with_capacity(tiles.len());
+        let mut errors = vec![];
+        let mut in_ram = 0;
+        let mut spill = None;
+        let mut spilled = 0;
+        let io_err = |e| EarthfinderError::io(spill_path, e);
+
+        for (chunk_i, chunk) in tiles.chunks(DECODE_CHUNK_SIZE).enumerate() {
+            let pixels = chunk
+                .par_iter()
+                .map(|(data, smol)| Ok((decode(data)?, decode(smol)?)))
+                .collect::<Vec<std::result::Result<_, ImageError>>>();
+
+            for (i, tile) in pixels.into_iter().enumerate() {
+                let i = chunk_i * DECODE_CHUNK_SIZE + i;
+                let (data, smol) = match tile {
+                    Ok(tile) => tile,
+                    Err(e) => {
+                        errors.push((i, e));
+                        continue;
+                    }
+                };
+                sources.push(i);
+
+                let size = (data.len() + smol.len()) as u64;
+                if in_ram + size <= budget {
+                    in_ram += size;
+                    slots.push(Slot::Ram { data, smol });
+                    continue;
+                }
+
+                let out = match &mut spill {
+                    Some(out) => out,
+                    None => {
+                        if let Some(dir) = spill_path.parent() {
+                            std::fs::create_dir_all(dir).map_err(io_err)?;
+                        }
+                        // readable too, to be mapped once written
+                        let file = File::options()
+                            .read(true)
+                            .write(true)
+                            .create(true)
+                            .truncate(true)
+                            .open(spill_path)
+                            .map_err(io_err)?;
+                        spill.insert(BufWriter::new(file))
+                    }
+                };
+                out.write_all(&data).map_err(io_err)?;
+                out.write_all(&smol).map_err(io_err)?;
+                let (data_end, smol_end) =
+                    (spilled + data.len(), spilled + data.len() + smol.len());
+                slots.push(Slot::Spilled {
+                    data: spilled..data_end,
+                    smol: data_end..smol_end,
+                });
+                spilled = smol_end;
+            }
+        }
+
+        let spill = match spill {
+            Some(mut out) => {
+                out.flush().map_err(io_err)?;
+                let file = out.into_inner().map_err(|e| io_err(e.into_error()))?;
+                // SAFETY: the file was just written by this process, and is removed below
+                // so nothing else can open it
+                let mmap = unsafe { Mmap::map(&file) }.map_err(io_err)?;
+                // the mapping outlives the file on unix, elsewhere it is overwritten next run
+                let _ = std::fs::remove_file(spill_path);
+                eprint!(
+                    "({} MB in RAM, {} MB mapped from {})...",
+                    in_ram >> 20,
+                    spilled >> 20,
+                    spill_path.display()
+                );
+                Some(mmap)
+            }
+            None => None,
+        };
+
+        let decoded = DecodedTiles {
+            slots,
+            sources,
+            spill,
+        };
+        Ok((decoded, errors))
+    }
+
+    /// Index in the tiles decoded of each decoded tile
+    pub fn sources(&self) -> &[usize] {
+        &self.sources
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Gradient and quarter resolution pixels of the `i`-th tile decoded
+    pub fn get(&self, i: usize) -> (&[u8], &[u8]) {
+        match &self.slots[i] {
+            Slot::Ram { data, smol } => (data, smol),
+            Slot::Spilled { data, smol } => {
+                let spill = self.spill.as_ref().expect("spilled tiles are mapped");
+                (&spill[data.clone()], &spill[smol.clone()])
+            }
+        }
+    }
+}
+
+/// RGBA pixels of an encoded tile
+pub fn decode(bytes: &[u8]) -> std::result::Result<Vec<u8>, ImageError> {
+    Ok(image::load_from_memory(bytes)?.to_rgba8().into_raw())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DecodedTiles;
+    use crate::tile_pack::TileBytes;
+    use image::{ImageFormat, RgbaImage};
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    fn png(w: u32, h: u32, value: u8) -> TileBytes {
+        let image = RgbaImage::from_pixel(w, h, image::Rgba([value, 0, 0, 255]));
+        let mut bytes = Cursor::new(vec![]);
+        image.write_to(&mut bytes, ImageFormat::Png).unwrap();
+        TileBytes::Owned(Arc::new(bytes.into_inner()))
+    }
+
+    #[test]
+    fn decoded_tiles_spill_past_budget() {
+        let spill_path = std::env::temp_dir().join("earthfinder_tile_cache_test.raw");
+        let tiles = (0..4)
+            .map(|i| {
+                if i == 2 {
+                    (
+                        TileBytes::Owned(Arc::new(b"not a png".to_vec())),
+                        png(2, 2, i),
+                    )
+                } else {
+                    (png(8, 8, i), png(2, 2, i))
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // room for one tile in RAM
+        let (decoded, errors) = DecodedTiles::decode(&tiles, 300, &spill_path).unwrap();
+        assert_eq!(decoded.sources(), [0, 1, 3]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 2);
+        assert_eq!(decoded.len(), 3);
+
+        for (i, &tile) in decoded.sources().iter().enumerate() {
+            let (data, smol) = decoded.get(i);
+            assert_eq!(data.len(), 8 * 8 * 4);
+            assert_eq!(smol.len(), 2 * 2 * 4);
+            assert_eq!((data[0], smol[4]), (tile as u8, tile as u8));
+        }
+
+        let (decoded, _) = DecodedTiles::decode(&tiles, u64::MAX, &spill_path).unwrap();
+        assert!(decoded.spill.is_none());
+    }
+}
@@ -1,45 +1,89 @@
-use crate::data;
-use crate::data::{parse_csv, sanity_check, TilePos};
+use crate::candidates::{self, candidates_path, FrameCandidates};
+use crate::cli::{refine_config, search_bins, zooms_or, GpuArgs};
+use crate::data::TilePos;
+use crate::error::{self, EarthfinderError, Result};
 use crate::gpu::algorithm::{AlgoResult, PosResult};
-use crate::gpu::State;
-use crate::mask::Mask;
+use crate::layout::DataLayout;
+use crate::mask_similarity::{SimilarityMatrix, MIN_SIMILARITY};
+use crate::mask_store::MaskStore;
+use crate::pipeline::{check_upstream, Stage};
+use crate::project::Project;
+use crate::refine;
+use crate::results::{self, AppendResults, FrameData};
+use crate::search::{Search, TileIndex};
+use crate::shots::Shots;
 use image::{GrayImage, Rgb32FImage, RgbaImage};
 use ordered_float::OrderedFloat;
 use rustc_hash::{FxHashMap, FxHashSet};
-use std::fs::File;
-use std::io::{Read, Seek, Write};
 use std::time::Instant;
 
-static SAVE_ERROR: bool = false;
-
-pub fn gpu_all(zs: &[u32]) {
-    sanity_check();
-    let _ = std::fs::create_dir_all("data/results/frames");
-    let _ = std::fs::create_dir_all("data/results/frames_debug");
-
-    let mask_example = data::mask_i(5);
-    let mask_dims = (mask_example.width(), mask_example.height());
-    let mask_chunk_size = 1;
-    let mut state = pollster::block_on(State::new(mask_dims, mask_chunk_size, 1));
+/// Results of earlier frames kept to exclude tiles, the least similar to the current
+/// frame are dropped first
+const RECENT_RESULTS: usize = 100;
+/// Results that a later frame lists as similar in the [`SimilarityMatrix`] are kept past
+/// [`RECENT_RESULTS`], up to this many
+const MAX_KEPT_RESULTS: usize = 400;
+
+/// Colours of the last tile before the first match, so that the search starts in the sea
+fn sea_rgb(mask_dims: (u32, u32)) -> RgbaImage {
+    RgbaImage::from_pixel(
+        mask_dims.0 / 4,
+        mask_dims.1 / 4,
+        image::Rgba([1, 1, 32, 255]),
+    )
+}
 
-    let mask_idxs = (1..=6562).collect::<Vec<_>>();
+pub fn gpu_all(layout: &DataLayout, project: &Project, args: &GpuArgs) -> Result<()> {
+    check_upstream(layout, project, Stage::Gpu);
+    let frames_dir = layout.result_frames_dir();
+    let frames_debug_dir = layout.result_frames_debug_dir();
+    let _ = std::fs::create_dir_all(&frames_dir);
+    let _ = std::fs::create_dir_all(&frames_debug_dir);
+
+    let first = args.first.unwrap_or(project.frames.first);
+    let last = args.last.unwrap_or(project.frames.last);
+
+    let (masks, mask_errors) = MaskStore::load(layout, first..=last);
+    let mask_dims = masks.dimensions().ok_or_else(|| {
+        EarthfinderError::layout(
+            layout.masks_dir(),
+            format!("no masks for frames {}-{}", first, last),
+        )
+    })?;
+    let mask_idxs = masks.frames().to_vec();
+
+    let similarity_path = layout.mask_similarity();
+    let similar = if std::fs::exists(&similarity_path).unwrap_or(false) {
+        let (matrix, matrix_errors) = SimilarityMatrix::read(&similarity_path)?;
+        error::report("lines", &matrix_errors);
+        Some(matrix)
+    } else {
+        None
+    };
 
-    let entries = data::tile_grad_entries(zs);
+    let bins = search_bins(args, project);
+    let refine = refine_config(args, project);
+    bins.check(mask_dims)
+        .map_err(|e| EarthfinderError::layout(layout.masks_dir(), format!("search: {}", e)))?;
 
-    let n_tiles = entries.len();
+    let index = TileIndex::build(layout, zooms_or(&args.zooms, project))?;
+    let mut search = Search::new(
+        layout,
+        &index,
+        mask_dims,
+        args.top_k.unwrap_or(project.search.top_k),
+        args.tile_cache_mb.unwrap_or(project.search.tile_cache_mb),
+        args.resident_tiles_mb
+            .unwrap_or(project.search.resident_tiles_mb),
+        &bins,
+    )?;
+    drop(index);
 
-    state.prepare(&entries);
-    drop(entries);
+    let n_tiles = search.n_tiles();
 
-    let mut last_tile_rgb = RgbaImage::new(mask_dims.0 / 4, mask_dims.1 / 4);
+    let shots = Shots::read_if_any(layout)?;
 
-    // force blue to start in the sea
-    last_tile_rgb.pixels_mut().for_each(|p| {
-        p.0[0] = 1;
-        p.0[1] = 1;
-        p.0[2] = 32;
-        p.0[3] = 255;
-    });
+    let mut last_tile_rgb = sea_rgb(mask_dims);
 
     let mut avg_error = Rgb32FImage::new(mask_dims.0, mask_dims.1);
     avg_error.fill(0.5);
@@ -48,71 +92,36 @@ pub fn gpu_all(zs: &[u32]) {
 
     let mut forbidden_tiles = FxHashSet::default();
 
-    let mut result_csv = File::options()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open("data/results/out.csv")
-        .unwrap();
-
-    let csv_content = {
-        let mut bufreader = std::io::BufReader::new(&result_csv);
-        let mut content = String::new();
-        bufreader.read_to_string(&mut content).unwrap();
-        content
-    };
-
-    let file_existed = !csv_content.is_empty();
+    let csv_path = args.output.clone().unwrap_or_else(|| layout.results_csv());
 
-    let mut frames_already_done = parse_csv(&csv_content);
+    let AppendResults {
+        writer: mut results_writer,
+        frames: mut frames_already_done,
+        errors,
+    } = results::open_append(&csv_path)?;
+    // the frames of the bad lines are computed again
+    error::report("lines", &errors);
 
     frames_already_done.sort_unstable_by_key(|f| f.frame);
 
-    for (last, next) in frames_already_done
-        .iter()
-        .zip(frames_already_done.iter().skip(1))
-    {
-        if last.frame == next.frame {
-            panic!("Frame {} was calculated twice. Please fix CSV", last.frame);
-        }
-    }
-
     if !frames_already_done.is_empty() {
         eprintln!("Some frames were already calculated and will be skipped:");
-        let mut ranges_already_done = vec![];
-        let mut end = frames_already_done[0].frame;
-        let mut begin = end;
-        for frame in frames_already_done.iter().skip(1).map(|f| f.frame) {
-            if frame != end + 1 {
-                ranges_already_done.push((begin, end));
-                begin = frame;
-            }
-            end = frame;
-        }
-        ranges_already_done.push((begin, end));
-
-        for (begin, end) in ranges_already_done {
-            if begin == end {
-                eprintln!("  Frame  {}", begin);
+        for range in results::to_ranges(frames_already_done.iter().map(|f| f.frame)) {
+            if range.first == range.last {
+                eprintln!("  Frame  {}", range);
             } else {
-                eprintln!("  Frames {}-{}", begin, end);
+                eprintln!("  Frames {}", range);
             }
         }
     }
 
-    result_csv.seek(std::io::SeekFrom::End(0)).unwrap();
+    let csv_err = |e: std::io::Error| EarthfinderError::io(&csv_path, e);
 
-    let mut bufwriter = std::io::BufWriter::new(result_csv);
+    let candidates_path = candidates_path(&csv_path);
+    let mut candidates_writer = candidates::open_append(&candidates_path)?;
 
-    if !file_existed {
-        writeln!(
-            &mut bufwriter,
-            "Frame,tile_x,tile_y,tile_z,zoom,x,y,score,time"
-        )
-        .unwrap();
-    } else {
-        writeln!(&mut bufwriter, "").unwrap();
-    }
+    // masks that couldn't be loaded are reported with the frames that failed
+    let mut errors = mask_errors;
 
     let mut prev_results: Vec<(u32, AlgoResult, PosResult)> = vec![];
     let mut prev_times = vec![];
@@ -121,11 +130,27 @@ pub fn gpu_all(zs: &[u32]) {
 
     let n_masks = mask_idxs.len();
     for (ii, mask_idx) in mask_idxs.into_iter().enumerate() {
-        if !std::fs::exists(format!("data/bad_apple_masks/bad_apple_{}.png", mask_idx)).unwrap() {
+        let Some(mask) = masks.get(mask_idx) else {
             continue;
-        }
+        };
 
-        let mut mask = Mask::new(mask_idx);
+        let needed_later = |frame| {
+            similar
+                .as_ref()
+                .is_some_and(|s| s.needed_after(frame, mask_idx))
+        };
+
+        // what was matched before a cut says nothing about the next shot, only the
+        // similar frames listed by the similarity matrix are still worth looking at
+        let cut = shots.as_ref().is_some_and(|s| s.is_cut(mask_idx));
+        if cut {
+            eprintln!("Frame {}: cut, starting a new shot", mask_idx);
+            last_tile_rgb = sea_rgb(mask_dims);
+            avg_error.fill(0.5);
+            forbidden_tile_ring.clear();
+            forbidden_tiles.clear();
+            prev_results.retain(|(prev_mask_idx, _, _)| needed_later(*prev_mask_idx));
+        }
 
         if let Ok(idx) = frames_already_done.binary_search_by_key(&mask_idx, |f| f.frame) {
             let f = &frames_already_done[idx];
@@ -135,15 +160,20 @@ pub fn gpu_all(zs: &[u32]) {
             if forbidden_tile_ring.len() >= 10 {
                 forbidden_tiles.remove(&forbidden_tile_ring.remove(0));
             }
-            last_tile_rgb = f.result.to_rgba_quarter(mask_dims);
+            match f.result.to_rgba_quarter(layout, mask_dims) {
+                Ok(rgba) => last_tile_rgb = rgba,
+                Err(e) => errors.push(e),
+            }
 
             avg_error.pixels_mut().for_each(|p| {
                 p.0[0] *= 0.5;
             });
 
-            f.result.calc_error(&mask, |x, y, err| {
+            if let Err(e) = f.result.calc_error(layout, mask, |x, y, err| {
                 avg_error.get_pixel_mut(x, y).0[0] += err;
-            });
+            }) {
+                errors.push(e);
+            }
 
             continue;
         }
@@ -152,10 +182,12 @@ pub fn gpu_all(zs: &[u32]) {
             p.0[0] *= 0.5;
         });
 
-        if let Some((_, _, prev_best_pos)) = prev_results.last() {
-            prev_best_pos.calc_error(&mask, |x, y, err| {
+        if let Some((_, _, prev_best_pos)) = prev_results.last().filter(|_| !cut) {
+            if let Err(e) = prev_best_pos.calc_error(layout, mask, |x, y, err| {
                 avg_error.get_pixel_mut(x, y).0[0] += err;
-            })
+            }) {
+                errors.push(e);
+            }
         };
 
         //mask.enumerate_pixels_mut().for_each(|(x, y, p)| {
@@ -170,13 +202,11 @@ pub fn gpu_all(zs: &[u32]) {
 
         let mut forbidden_tiles: FxHashSet<TilePos> = forbidden_tile_ring.iter().cloned().collect();
 
-        let mask_clean = Mask::new(mask_idx);
-
         let dots = prev_results
             .iter()
             .map(|(prev_mask_idx, _, _)| {
-                let prev_mask = Mask::new(*prev_mask_idx);
-                (*prev_mask_idx, mask_clean.dot(&prev_mask))
+                let dot = masks.similarity(mask_idx, *prev_mask_idx).unwrap_or(0.0);
+                (*prev_mask_idx, dot)
             })
             .collect::<FxHashMap<_, _>>();
 
@@ -185,7 +215,7 @@ pub fn gpu_all(zs: &[u32]) {
 
         for (prev_mask_idx, prev_algo, prev_best_pos) in prev_results.iter().rev() {
             let dot = dots[prev_mask_idx];
-            if dot < 0.8 {
+            if dot < MIN_SIMILARITY {
                 break;
             }
 
@@ -206,13 +236,28 @@ pub fn gpu_all(zs: &[u32]) {
             forbidden_tiles = forbidden_tile_ring.iter().cloned().collect();
         }
 
-        if prev_results.len() > 100 {
-            prev_results.remove(0);
+        while prev_results.len() > RECENT_RESULTS {
+            let least_similar = prev_results
+                .iter()
+                .position(|(prev_mask_idx, _, _)| !needed_later(*prev_mask_idx));
+            match least_similar {
+                Some(i) => prev_results.remove(i),
+                None if prev_results.len() > MAX_KEPT_RESULTS => prev_results.remove(0),
+                None => break,
+            };
         }
 
-        let (results, elapsed_gpu) =
-            state.run_on_image(&[(&mask, mask_idx, &last_tile_rgb)], &forbidden_tiles);
-        let algo_res = results.into_iter().nth(0).unwrap().1;
+        let (mut algo_res, elapsed_gpu) =
+            search.run_with(mask.image(), mask_idx, &last_tile_rgb, &forbidden_tiles);
+        if refine.iterations > 0 {
+            algo_res.best_pos = refine::refine_results(
+                mask,
+                &algo_res.best_pos,
+                |pos| search.tile_grad(pos),
+                &bins,
+                &refine,
+            );
+        }
         let best_pos = algo_res.best_pos.results()[0];
 
         forbidden_tile_ring.push(best_pos.tile_pos());
@@ -252,48 +297,70 @@ pub fn gpu_all(zs: &[u32]) {
              ((forbidden_tiles.len() as f32 / n_tiles as f32) * 100.0) as u32
         );
 
-        writeln!(
-            &mut bufwriter,
-            "{},{},{},{},{},{},{},{:.6},{:.2}",
-            mask_idx,
-            best_pos.tile_x,
-            best_pos.tile_y,
-            best_pos.tile_z,
-            best_pos.zoom,
-            best_pos.x,
-            best_pos.y,
-            best_pos.score,
-            elapsed_gpu.as_secs_f32()
-        )
-        .unwrap();
-
-        let _ = bufwriter.flush();
-
-        last_tile_rgb = best_pos.to_rgba_quarter(mask.dimensions());
+        results_writer
+            .write(&FrameData {
+                frame: mask_idx,
+                result: best_pos,
+                time: Some(elapsed_gpu.as_secs_f32()),
+            })
+            .map_err(csv_err)?;
+
+        let _ = results_writer.flush();
+
+        candidates_writer
+            .write(&FrameCandidates::new(
+                mask_idx,
+                &algo_res,
+                args.save_tile_scores,
+            ))
+            .map_err(|e| EarthfinderError::io(&candidates_path, e))?;
+        let _ = candidates_writer.flush();
+
+        match best_pos.to_rgba_quarter(layout, mask.dimensions()) {
+            Ok(rgba) => last_tile_rgb = rgba,
+            Err(e) => errors.push(e),
+        }
 
         let avg_error_cpy = avg_error.clone();
+        let mask_image = mask.image().clone();
+        let layout = layout.clone();
+        let frames_dir = frames_dir.clone();
+        let frames_debug_dir = frames_debug_dir.clone();
+        let save_error = args.save_error;
 
         rayon::spawn(move || {
-            if SAVE_ERROR {
-                let mut error_show = GrayImage::new(mask_dims.0, mask_dims.1);
-                avg_error_cpy.enumerate_pixels().for_each(|(x, y, p)| {
-                    error_show.put_pixel(x, y, image::Luma([((p.0[0] * 255.0) as u8).min(255)]));
-                });
-
-                error_show
-                    .save(format!("data/results/frames/{}_avg_error.png", mask_idx))
-                    .unwrap();
+            let save = || -> Result<()> {
+                if save_error {
+                    let mut error_show = GrayImage::new(mask_dims.0, mask_dims.1);
+                    avg_error_cpy.enumerate_pixels().for_each(|(x, y, p)| {
+                        error_show.put_pixel(x, y, image::Luma([(p.0[0] * 255.0) as u8]));
+                    });
+
+                    let path = frames_dir.join(format!("{}_avg_error.png", mask_idx));
+                    error_show
+                        .save(&path)
+                        .map_err(|e| EarthfinderError::image(path, e))?;
+                }
+                let img_debug = best_pos.to_image(&layout, &mask_image, &avg_error_cpy, true)?;
+                let path = frames_debug_dir.join(format!("{}.png", mask_idx));
+                img_debug
+                    .save(&path)
+                    .map_err(|e| EarthfinderError::image(path, e))?;
+
+                let img = best_pos.to_image(&layout, &mask_image, &avg_error_cpy, false)?;
+                let path = frames_dir.join(format!("{}.png", mask_idx));
+                img.save(&path)
+                    .map_err(|e| EarthfinderError::image(path, e))
+            };
+            // the result is already in the CSV, only the preview is missing
+            if let Err(e) = save() {
+                eprintln!("Frame {}: could not save preview: {}", mask_idx, e);
             }
-            let img_debug = best_pos.to_image(&mask, &avg_error_cpy, true);
-            img_debug
-                .save(format!("data/results/frames_debug/{}.png", mask_idx))
-                .unwrap();
-
-            let img = best_pos.to_image(&mask, &avg_error_cpy, false);
-            img.save(format!("data/results/frames/{}.png", mask_idx))
-                .unwrap();
         });
 
         prev_results.push((mask_idx, algo_res, best_pos));
     }
+
+    error::report("frames", &errors);
+    Ok(())
 }
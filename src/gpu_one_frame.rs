@@ -1,17 +1,24 @@
-use crate::data;
-use crate::data::sanity_check;
+use crate::cli::{zooms_or, GpuOneFrameArgs};
+use crate::error::{EarthfinderError, Result};
 use crate::gpu::State;
+use crate::layout::DataLayout;
+use crate::mask::MaskChannels;
+use crate::pipeline::{check_upstream, Stage};
+use crate::project::Project;
+use crate::refine;
+use crate::search::TileIndex;
 use image::Rgb32FImage;
 
-pub fn gpu_one_frame(zs: &[u32]) {
-    sanity_check();
-    let mask_ids = vec![329, 2340];
-    let mut masks = mask_ids
+pub fn gpu_one_frame(layout: &DataLayout, project: &Project, args: &GpuOneFrameArgs) -> Result<()> {
+    check_upstream(layout, project, Stage::Gpu);
+    let mut masks = args
+        .masks
         .iter()
-        .map(|&i| (data::mask_i(i), i))
-        .collect::<Vec<_>>();
+        .map(|&i| Ok((MaskChannels::load(&layout.mask(i))?, i)))
+        .collect::<Result<Vec<_>>>()?;
     let mask_size = masks[0].0.dimensions();
-    let mut state = pollster::block_on(State::new(mask_size, masks.len(), 10));
+    let bins = project.search.bins();
+    let mut state = pollster::block_on(State::new(mask_size, masks.len(), args.top_k, &bins));
 
     #[allow(unused_variables)]
     let first_result = crate::gpu::algorithm::PosResult {
@@ -22,13 +29,18 @@ pub fn gpu_one_frame(zs: &[u32]) {
         y: 95,
         score: -1.97,
         zoom: 0.874,
+        angle: 0.0,
+        sub_x: 0.0,
+        sub_y: 0.0,
+        refined_score: None,
     };
     //let last_tile_rgb = first_result.to_rgba_quarter(mask_size);
     let last_tile_rgb = image::RgbaImage::new(mask_size.0 / 4, mask_size.1 / 4);
 
-    let entries = data::tile_grad_entries(zs);
-    //let entries = data::debug_entry(69, 40, 7);
-    state.prepare(&entries);
+    let index = TileIndex::build(layout, zooms_or(&args.zooms, project))?;
+    //let index = TileIndex::from_entries(crate::data::debug_entry(layout, 69, 40, 7));
+    state.prepare(layout, &index, project.search.tile_cache_mb << 20)?;
+    state.make_resident(project.search.resident_tiles_mb << 20);
 
     let mut avg_error = Rgb32FImage::new(mask_size.0, mask_size.1);
     avg_error.fill(0.5);
@@ -37,27 +49,32 @@ pub fn gpu_one_frame(zs: &[u32]) {
     });
 
     for (mask, _) in &mut masks {
-        mask.enumerate_pixels_mut().for_each(|(x, y, p)| {
-            let apply_error = |v| {
-                let err = avg_error.get_pixel(x, y).0[0];
-                (v as f32 * (0.7 + err * 0.3)) as u8
-            };
-
-            p.0[0] = apply_error(p.0[0]);
-            p.0[1] = apply_error(p.0[1]);
-        });
+        mask.scale_gradients(|x, y| 0.7 + avg_error.get_pixel(x, y).0[0] * 0.3);
     }
 
-    let (results, elapsed_gpu) = state.run_on_image(
+    let (mut results, elapsed_gpu) = state.run_on_image(
         &masks
             .iter()
-            .map(|(data, i)| (data, *i, &last_tile_rgb))
+            .map(|(mask, i)| (mask.image(), *i, &last_tile_rgb))
             .collect::<Vec<_>>(),
         &Default::default(),
     );
     eprintln!("processing took: {:.2}s", elapsed_gpu.as_secs_f32());
 
-    let _ = std::fs::create_dir_all("data/results").unwrap();
+    if project.refine.iterations > 0 {
+        for (mask_i, (_, result)) in results.iter_mut().enumerate() {
+            result.best_pos = refine::refine_results(
+                &masks[mask_i].0,
+                &result.best_pos,
+                |pos| state.tile_grad(pos),
+                &bins,
+                &project.refine,
+            );
+        }
+    }
+
+    let output = args.output.clone().unwrap_or_else(|| layout.results_dir());
+    std::fs::create_dir_all(&output).map_err(|e| EarthfinderError::io(&output, e))?;
 
     //let mut forbidden_tiles = FxHashSet::default();
     for (mask_i, (mask_idx, results)) in results.iter().enumerate() {
@@ -73,9 +90,12 @@ pub fn gpu_one_frame(zs: &[u32]) {
                 result.x,
                 result.y
             );
-            let img = result.to_image(&masks[mask_i].0, &avg_error, true);
-            img.save(format!("data/results/gpu_of_{}_{}.png", mask_idx, i))
-                .unwrap();
+            let img = result.to_image(layout, masks[mask_i].0.image(), &avg_error, true)?;
+            let path = output.join(format!("gpu_of_{}_{}.png", mask_idx, i));
+            img.save(&path)
+                .map_err(|e| EarthfinderError::image(path, e))?;
         }
     }
+
+    Ok(())
 }
@@ -0,0 +1,276 @@
+use crate::data::TilePos;
+use crate::name_pattern::NamePattern;
+use crate::tile_scheme::TileScheme;
+use std::path::{Path, PathBuf};
+
+/// Where every file read or written by the commands lives.
+///
+/// Tile trees (raw, gradient and downscaled) live under `tiles`, while everything
+/// specific to an animation (frames, masks, results, renders) lives under `root`.
+/// Both default to the same directory, but pointing them to different places
+/// allows sharing one tile set between several projects.
+#[derive(Debug, Clone)]
+pub struct DataLayout {
+    root: PathBuf,
+    tiles: PathBuf,
+    prefix: String,
+    scheme: TileScheme,
+    frames: Option<PathBuf>,
+    animation: Option<PathBuf>,
+    frame_names: NamePattern,
+    mask_names: NamePattern,
+}
+
+impl DataLayout {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        Self {
+            tiles: root.clone(),
+            root,
+            prefix: "bad_apple_".to_string(),
+            scheme: TileScheme::default(),
+            frames: None,
+            animation: None,
+            frame_names: "{prefix}{n:03}.png".parse().unwrap(),
+            mask_names: "{prefix}{n}.png".parse().unwrap(),
+        }
+    }
+
+    /// Prefix of the frame and mask files, see [`crate::project::FramesConfig::prefix`]
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    pub fn with_tiles_root(mut self, tiles: impl Into<PathBuf>) -> Self {
+        self.tiles = tiles.into();
+        self
+    }
+
+    /// How the tiles of every tile tree are named
+    pub fn with_tile_scheme(mut self, scheme: TileScheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    pub fn tile_scheme(&self) -> TileScheme {
+        self.scheme
+    }
+
+    /// Directory of the source animation, relative to `root` unless absolute
+    pub fn with_frames_dir(mut self, frames: impl Into<PathBuf>) -> Self {
+        self.frames = Some(frames.into());
+        self
+    }
+
+    /// Animated file the frames are read from instead of the frames directory, relative to
+    /// `root` unless absolute, see [`crate::animation`]
+    pub fn with_animation(mut self, animation: impl Into<PathBuf>) -> Self {
+        self.animation = Some(animation.into());
+        self
+    }
+
+    pub fn animation(&self) -> Option<PathBuf> {
+        self.animation
+            .as_ref()
+            .map(|animation| self.root.join(animation))
+    }
+
+    /// How the frames of the source animation are named
+    pub fn with_frame_names(mut self, pattern: NamePattern) -> Self {
+        self.frame_names = pattern;
+        self
+    }
+
+    /// How the masks produced by `gen_mask` are named
+    pub fn with_mask_names(mut self, pattern: NamePattern) -> Self {
+        self.mask_names = pattern;
+        self
+    }
+
+    pub fn frame_names(&self) -> NamePattern {
+        self.frame_names.with_prefix(&self.prefix)
+    }
+
+    pub fn mask_names(&self) -> NamePattern {
+        self.mask_names.with_prefix(&self.prefix)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn tiles_root(&self) -> &Path {
+        &self.tiles
+    }
+
+    /// Raw satellite tiles, as fetched from s2maps
+    pub fn tiles_dir(&self) -> PathBuf {
+        self.tiles.join("tiles")
+    }
+
+    /// Gradient tiles produced by `tiles_grad`
+    pub fn tiles_grad_dir(&self) -> PathBuf {
+        self.tiles.join("tiles_grad")
+    }
+
+    /// Quarter resolution tiles produced by `tiles_grad`
+    pub fn tiles_smol_dir(&self) -> PathBuf {
+        self.tiles.join("tiles_smol")
+    }
+
+    /// Tile packs produced by `pack`, see [`crate::tile_pack`]
+    pub fn tile_packs_dir(&self) -> PathBuf {
+        self.tiles.join("tiles_packed")
+    }
+
+    pub fn tile_pack(&self, z: u32) -> PathBuf {
+        self.tile_packs_dir().join(format!("{z}.pack"))
+    }
+
+    pub fn tile(&self, pos: TilePos) -> PathBuf {
+        self.scheme.path(&self.tiles_dir(), pos, "png")
+    }
+
+    /// Where a tile is downloaded to before being converted to png
+    pub fn tile_tif(&self, pos: TilePos) -> PathBuf {
+        self.scheme.path(&self.tiles_dir(), pos, "tif")
+    }
+
+    pub fn tile_grad(&self, pos: TilePos) -> PathBuf {
+        self.scheme.path(&self.tiles_grad_dir(), pos, "png")
+    }
+
+    pub fn tile_smol(&self, pos: TilePos) -> PathBuf {
+        self.scheme.path(&self.tiles_smol_dir(), pos, "png")
+    }
+
+    /// Frames of the source animation, `<prefix>frames` unless set
+    pub fn frames_dir(&self) -> PathBuf {
+        match &self.frames {
+            Some(frames) => self.root.join(frames),
+            None => self.root.join(format!("{}frames", self.prefix)),
+        }
+    }
+
+    pub fn frame(&self, i: u32) -> PathBuf {
+        self.frames_dir().join(self.frame_names().format(i))
+    }
+
+    /// Masks produced by `gen_mask`
+    pub fn masks_dir(&self) -> PathBuf {
+        self.root.join(format!("{}masks", self.prefix))
+    }
+
+    pub fn mask(&self, i: u32) -> PathBuf {
+        self.masks_dir().join(self.mask_names().format(i))
+    }
+
+    /// Most similar masks of each frame, see [`crate::mask_similarity`]
+    pub fn mask_similarity(&self) -> PathBuf {
+        self.root
+            .join(format!("{}mask_similarity.jsonl", self.prefix))
+    }
+
+    /// Shots of the animation, see [`crate::shots`]
+    pub fn shots(&self) -> PathBuf {
+        self.root.join(format!("{}shots.txt", self.prefix))
+    }
+
+    pub fn results_dir(&self) -> PathBuf {
+        self.root.join("results")
+    }
+
+    /// Project file, see [`crate::project::Project`]
+    pub fn project_file(&self) -> PathBuf {
+        self.root.join("project.json")
+    }
+
+    /// Manifests of the stages, see [`crate::pipeline`]
+    pub fn manifests_dir(&self) -> PathBuf {
+        self.root.join("manifests")
+    }
+
+    /// Manifests of the stages producing tiles, shared by the projects using these tiles
+    pub fn tiles_manifests_dir(&self) -> PathBuf {
+        self.tiles.join("manifests")
+    }
+
+    /// Default results CSV of `gpu`, read by `render`
+    pub fn results_csv(&self) -> PathBuf {
+        self.results_dir().join("out.csv")
+    }
+
+    pub fn result_frames_dir(&self) -> PathBuf {
+        self.results_dir().join("frames")
+    }
+
+    pub fn result_frames_debug_dir(&self) -> PathBuf {
+        self.results_dir().join("frames_debug")
+    }
+
+    pub fn gpu_results_dir(&self) -> PathBuf {
+        self.results_dir().join("gpu")
+    }
+
+    /// Decoded tiles that didn't fit in RAM during a search, see [`crate::gpu::tile_cache`]
+    pub fn decoded_tiles_cache(&self) -> PathBuf {
+        self.root.join("cache").join("decoded_tiles.raw")
+    }
+
+    /// Final frames produced by `render`
+    pub fn render_dir(&self) -> PathBuf {
+        self.root.join("render_final")
+    }
+
+    #[allow(dead_code)]
+    pub fn debug_dir(&self) -> PathBuf {
+        self.root.join("debug")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DataLayout;
+    use crate::tile_scheme::TileScheme;
+    use std::path::Path;
+
+    #[test]
+    fn tile_paths_roundtrip() {
+        let layout = DataLayout::new("some/project").with_tiles_root("/shared");
+
+        let path = layout.tile_grad((35, 27, 7));
+        assert_eq!(path, Path::new("/shared/tiles_grad/7/27/35.png"));
+        assert_eq!(layout.tile_scheme().parse(&path).unwrap(), (35, 27, 7));
+
+        assert_eq!(
+            layout.tile_smol((35, 27, 7)),
+            Path::new("/shared/tiles_smol/7/27/35.png")
+        );
+
+        assert_eq!(
+            layout.mask(5),
+            Path::new("some/project/bad_apple_masks/bad_apple_5.png")
+        );
+
+        let layout = layout.with_prefix("cat_").with_tile_scheme(TileScheme::Zxy);
+        assert_eq!(
+            layout.tile((35, 27, 7)),
+            Path::new("/shared/tiles/7/35/27.png")
+        );
+        assert_eq!(
+            layout.frame(7),
+            Path::new("some/project/cat_frames/cat_007.png")
+        );
+
+        let layout = layout
+            .with_frames_dir("/anim/cat")
+            .with_frame_names("cat {n}.jpg".parse().unwrap())
+            .with_mask_names("{prefix}mask_{n:04}.png".parse().unwrap());
+        assert_eq!(layout.frame(7), Path::new("/anim/cat/cat 7.jpg"));
+        assert_eq!(
+            layout.mask(7),
+            Path::new("some/project/cat_masks/cat_mask_0007.png")
+        );
+    }
+}
@@ -0,0 +1,43 @@
+//! Finds places on satellite imagery that look like the frames of an animation.
+//!
+//! The commands of the `earthfinder` binary are built on this library, which can also
+//! be used directly:
+//! - [`search::TileIndex::build`] lists the tiles preprocessed by `tiles_grad` (or `pack`)
+//! - [`mask::Mask::new`] loads a mask produced by `gen_mask`
+//! - [`mask_store::MaskStore::load`] keeps the masks of a range of frames in memory
+//! - [`search::Search::run`] finds the best places for a mask as [`PosResults`]
+//! - [`refine::refine_results`] moves them by sub-pixels and between the zooms searched
+//! - [`render::render_result`] renders one [`PosResult`] from the satellite tiles
+//!
+//! [`PosResults`]: gpu::algorithm::PosResults
+//! [`PosResult`]: gpu::algorithm::PosResult
+
+pub mod animation;
+pub mod candidates;
+pub mod cli;
+pub mod data;
+pub mod error;
+pub mod export_geo;
+pub mod gen_mask;
+pub mod geo;
+pub mod gpu;
+pub mod gpu_all;
+pub mod gpu_one_frame;
+pub mod layout;
+pub mod mask;
+pub mod mask_similarity;
+pub mod mask_store;
+pub mod name_pattern;
+pub mod pipeline;
+pub mod project;
+pub mod refine;
+pub mod render;
+pub mod results;
+pub mod results_cmd;
+pub mod search;
+pub mod shots;
+pub mod tile_pack;
+pub mod tile_scheme;
+pub mod tiles_grad;
+
+pub const TILE_HEIGHT: u32 = 512;
@@ -1,30 +1,13 @@
-use renderdoc::{RenderDoc, V141};
+use clap::Parser;
+use earthfinder::cli::{zooms_or, Cli, Command};
+use earthfinder::gpu::capture;
+use earthfinder::layout::DataLayout;
+use earthfinder::pipeline::{self, StageArgs};
+use earthfinder::project::Project;
+use earthfinder::{export_geo, gpu_one_frame, mask_similarity, results_cmd, shots, tile_pack};
+use nanoserde::SerJson;
 use std::process::ExitCode;
 
-mod data;
-mod gen_mask;
-mod gpu;
-mod gpu_all;
-mod gpu_one_frame;
-mod mask;
-mod render;
-mod tiles_grad;
-
-pub const TILE_HEIGHT: u32 = 512;
-
-fn parse_zoom_levels() -> Vec<u32> {
-    let mut zs = vec![7, 8, 9];
-
-    if std::env::args().len() > 2 {
-        zs = std::env::args()
-            .skip(2)
-            .map(|arg| arg.parse::<u32>().expect("Invalid zoom level"))
-            .collect::<Vec<_>>();
-    }
-
-    zs
-}
-
 fn main() -> ExitCode {
     /*
     let n_threads = std::thread::available_parallelism().unwrap();
@@ -34,67 +17,81 @@ fn main() -> ExitCode {
         .build_global()
         .unwrap();*/
 
-    if !std::fs::exists("data").unwrap_or(false) {
-        eprintln!("data directory not found, are you at the root of the project?");
-        return ExitCode::FAILURE;
-    }
-
-    let command = std::env::args().nth(1).expect("No command provided");
+    let cli = Cli::parse();
 
-    let mut rd: Option<RenderDoc<V141>> = RenderDoc::new().ok();
+    let project_path = cli
+        .project
+        .clone()
+        .unwrap_or_else(|| DataLayout::new(&cli.data).project_file());
+    let project = match Project::load(&project_path) {
+        Ok(project) => project,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    if let Some(rd) = &mut rd {
-        rd.start_frame_capture(std::ptr::null(), std::ptr::null());
-    } else {
-        eprintln!("RenderDoc not found, skipping frame capture");
+    if let Command::Project = &cli.command {
+        println!("{}", project.serialize_json());
+        return ExitCode::SUCCESS;
     }
 
-    match command.as_str() {
-        "tiles_grad" => {
-            let zs = parse_zoom_levels();
-            if zs.len() == 0 {
-                eprintln!("No zoom levels provided");
-                return ExitCode::FAILURE;
-            }
+    let mut layout = DataLayout::new(&cli.data)
+        .with_prefix(&project.frames.prefix)
+        .with_frame_names(project.frames.name_pattern())
+        .with_mask_names(project.mask.name_pattern())
+        .with_tile_scheme(project.tiles.tile_scheme());
+    if !project.frames.dir.is_empty() {
+        layout = layout.with_frames_dir(&project.frames.dir);
+    }
+    if !project.frames.animation.is_empty() {
+        layout = layout.with_animation(&project.frames.animation);
+    }
+    if let Some(tiles) = &cli.tiles {
+        layout = layout.with_tiles_root(tiles);
+    }
 
-            for z in zs {
-                tiles_grad::gen_tiles_grad(z);
-            }
-        }
-        "gen_mask" => {
-            let mut debug = false;
-            if std::env::args().len() > 2 {
-                let arg = std::env::args().nth(2).unwrap();
-                if arg == "debug" {
-                    debug = true;
-                } else {
-                    eprintln!("Unknown argument: {} (valid is: debug or nothing)", arg);
-                    return ExitCode::FAILURE;
-                }
-            }
-            gen_mask::gen_masks(debug);
-        }
-        "gpu_one_frame" => gpu_one_frame::gpu_one_frame(&parse_zoom_levels()),
-        "gpu" => gpu_all::gpu_all(&parse_zoom_levels()),
-        "render" => {
-            let path = std::env::args().nth(2).unwrap_or_else(|| {
-                static DEFAULT_PATH: &str = "data/results/out.csv";
-                eprintln!("No path provided, using default: {}", DEFAULT_PATH);
-                DEFAULT_PATH.to_string()
-            });
-            render::render(&path);
-        }
-        _ => {
-            eprintln!("Unknown command: {}", command);
-            eprintln!("Available commands are: tiles_grad, gen_mask, gpu_one_frame, gpu");
-            std::process::exit(1);
+    for dir in [layout.root(), layout.tiles_root()] {
+        if !std::fs::exists(dir).unwrap_or(false) {
+            eprintln!(
+                "data directory {} not found, use --data to point to it",
+                dir.display()
+            );
+            return ExitCode::FAILURE;
         }
     }
 
-    if let Some(rd) = &mut rd {
-        rd.end_frame_capture(std::ptr::null(), std::ptr::null());
-        std::thread::sleep(std::time::Duration::from_millis(500));
+    if let Some(target) = &cli.capture {
+        if let Err(e) = capture::enable(target.clone()) {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
     }
 
-    ExitCode::SUCCESS
+    let result = match &cli.command {
+        Command::TilesGrad(args) => {
+            pipeline::run_stage(&layout, &project, StageArgs::TilesGrad(args))
+        }
+        Command::Pack(args) => tile_pack::pack(&layout, zooms_or(&args.zooms, &project)),
+        Command::GenMask(args) => pipeline::run_stage(&layout, &project, StageArgs::GenMask(args)),
+        Command::MaskSimilarity(args) => mask_similarity::mask_similarity(&layout, &project, args),
+        Command::Shots(args) => shots::shots(&layout, &project, args),
+        Command::GpuOneFrame(args) => gpu_one_frame::gpu_one_frame(&layout, &project, args),
+        Command::Gpu(args) => pipeline::run_stage(&layout, &project, StageArgs::Gpu(args)),
+        Command::Render(args) => pipeline::run_stage(&layout, &project, StageArgs::Render(args)),
+        Command::Pipeline(args) => pipeline::pipeline(&layout, &project, args),
+        Command::Results(args) => results_cmd::results_cmd(&layout, &project, args),
+        Command::ExportGeo(args) => export_geo::export_geo(&layout, args),
+        Command::Project => unreachable!(),
+    };
+
+    capture::finish();
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
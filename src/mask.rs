@@ -1,62 +1,300 @@
-use crate::data;
-use image::RgbaImage;
-use std::ops::{Deref, DerefMut};
+//! What the pixels of a mask mean, see [`MaskChannels`].
 
+use crate::error::{EarthfinderError, Result};
+use crate::gpu::framework::get_shader_source;
+use crate::layout::DataLayout;
+use image::{RgbImage, Rgba, RgbaImage};
+use std::fs::File;
+use std::io::BufWriter;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Once;
+
+/// Version of the encoding of [`MaskChannels`]. Kernels reading masks declare the
+/// version they were written for as `const MASK_PROFILE: u32 = <version>u;`, see
+/// [`check_kernel_profile`]. Masks record the version they were written in, see
+/// [`MaskChannels::load`].
+pub const MASK_PROFILE: u32 = 1;
+
+/// Keyword of the PNG text chunk holding the profile of a mask
+const PROFILE_KEY: &str = "earthfinder-mask-profile";
+
+/// Stored value of a gradient of 1
+pub const GRADIENT_SCALE: f32 = 170.0;
+/// Stored value of a surround penalty of 1, before the gain of the mask
+pub const SURROUND_SCALE: f32 = 500.0;
+/// Average penalty the surround gain aims for
+const SURROUND_TARGET: f32 = 0.09;
+const MAX_SURROUND_GAIN: f32 = 2.0;
+
+/// The channels of a mask, stored in an RGB PNG (profile 1):
+/// - R: |horizontal gradient| × [`GRADIENT_SCALE`]
+/// - G: |vertical gradient| × [`GRADIENT_SCALE`]
+/// - B: surround penalty × [`SURROUND_SCALE`], clamped to 255 then multiplied by the
+///   gain of the mask, see [`MaskChannels::set_surround`]
+///
+/// Values are truncated to u8. The kernels and the accessors see them divided by 255.
+#[derive(Clone)]
+pub struct MaskChannels {
+    image: RgbaImage,
+}
+
+impl MaskChannels {
+    /// Encodes the gradients of a frame, with no surround penalty
+    pub fn from_gradients(width: u32, height: u32, grad_x: &[f32], grad_y: &[f32]) -> Self {
+        let image = RgbaImage::from_fn(width, height, |x, y| {
+            let i = (y * width + x) as usize;
+            Rgba([
+                (grad_x[i].abs() * GRADIENT_SCALE) as u8,
+                (grad_y[i].abs() * GRADIENT_SCALE) as u8,
+                0,
+                255,
+            ])
+        });
+        Self { image }
+    }
+
+    /// Encodes the surround penalty, between 0 and 1 for every pixel. The penalty of
+    /// every mask is scaled to average [`SURROUND_TARGET`], but at most doubled.
+    pub fn set_surround(&mut self, penalty: &[f32]) {
+        let avg = penalty.iter().sum::<f32>() / penalty.len() as f32;
+        let gain = (SURROUND_TARGET / avg).min(MAX_SURROUND_GAIN);
+        for (pixel, &p) in self.image.pixels_mut().zip(penalty) {
+            let stored = (p * SURROUND_SCALE) as u8;
+            pixel.0[2] = (stored as f32 * gain) as u8;
+        }
+    }
+
+    /// Multiplies the gradients of every pixel by `factor(x, y)`
+    pub fn scale_gradients(&mut self, factor: impl Fn(u32, u32) -> f32) {
+        for (x, y, pixel) in self.image.enumerate_pixels_mut() {
+            let factor = factor(x, y);
+            pixel.0[0] = (pixel.0[0] as f32 * factor) as u8;
+            pixel.0[1] = (pixel.0[1] as f32 * factor) as u8;
+        }
+    }
+
+    /// Reads a mask written by [`MaskChannels::save`]. Masks of another profile are an
+    /// error, masks that don't record theirs are read as the current one with a warning.
+    pub fn load(path: &Path) -> Result<Self> {
+        static UNKNOWN_PROFILE: Once = Once::new();
+
+        let bytes = std::fs::read(path).map_err(|e| EarthfinderError::io(path, e))?;
+        match read_profile(&bytes) {
+            Some(MASK_PROFILE) => {}
+            Some(profile) => {
+                return Err(EarthfinderError::layout(
+                    path,
+                    format!(
+                        "mask written in profile {}, expected {}, run gen_mask again",
+                        profile, MASK_PROFILE
+                    ),
+                ))
+            }
+            None => UNKNOWN_PROFILE.call_once(|| {
+                eprintln!(
+                    "/!\\ Warning: {} doesn't record its mask profile, run gen_mask again if \
+                     it was written by an older earthfinder",
+                    path.display()
+                )
+            }),
+        }
+        let image =
+            image::load_from_memory(&bytes).map_err(|e| EarthfinderError::image(path, e))?;
+        Ok(Self {
+            image: image.to_rgba8(),
+        })
+    }
+
+    /// Writes the mask as an RGB PNG recording [`MASK_PROFILE`]
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_png(path, &self.to_rgb(), MASK_PROFILE)
+    }
+
+    /// The encoded pixels, as uploaded to the kernels
+    pub fn image(&self) -> &RgbaImage {
+        &self.image
+    }
+
+    pub fn to_rgb(&self) -> RgbImage {
+        image::DynamicImage::ImageRgba8(self.image.clone()).to_rgb8()
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.image.dimensions()
+    }
+
+    pub fn width(&self) -> u32 {
+        self.image.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.image.height()
+    }
+
+    /// Horizontal and vertical gradients of a pixel, as the kernels see them
+    pub fn grad(&self, x: u32, y: u32) -> [f32; 2] {
+        let p = self.image.get_pixel(x, y).0;
+        [p[0] as f32 / 255.0, p[1] as f32 / 255.0]
+    }
+
+    /// Sum of the gradients of a pixel, as the kernels see them
+    pub fn grad_l1(&self, x: u32, y: u32) -> f32 {
+        let p = self.image.get_pixel(x, y).0;
+        (p[0] as f32 + p[1] as f32) / 255.0
+    }
+
+    /// Surround penalty of a pixel, as the kernels see it
+    pub fn surround(&self, x: u32, y: u32) -> f32 {
+        self.image.get_pixel(x, y).0[2] as f32 / 255.0
+    }
+}
+
+/// Profile recorded in the text chunks of a PNG, before its pixels
+fn read_profile(png: &[u8]) -> Option<u32> {
+    let reader = png::Decoder::new(png).read_info().ok()?;
+    let chunk = reader
+        .info()
+        .uncompressed_latin1_text
+        .iter()
+        .find(|chunk| chunk.keyword == PROFILE_KEY)?;
+    chunk.text.parse().ok()
+}
+
+fn write_png(path: &Path, image: &RgbImage, profile: u32) -> Result<()> {
+    let png_err = |e: png::EncodingError| match e {
+        png::EncodingError::IoError(e) => EarthfinderError::io(path, e),
+        e => EarthfinderError::layout(path, format!("could not encode png: {}", e)),
+    };
+    let file = File::create(path).map_err(|e| EarthfinderError::io(path, e))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), image.width(), image.height());
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .add_text_chunk(PROFILE_KEY.to_string(), profile.to_string())
+        .map_err(png_err)?;
+    let mut writer = encoder.write_header().map_err(png_err)?;
+    writer.write_image_data(image.as_raw()).map_err(png_err)?;
+    writer.finish().map_err(png_err)
+}
+
+/// Panics if a kernel reading masks was not written for [`MASK_PROFILE`], rather than
+/// letting it misread them
+pub fn check_kernel_profile(kernel_name: &str) {
+    let source = get_shader_source(kernel_name);
+    let declared = source.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("const MASK_PROFILE: u32 = ")?
+            .strip_suffix("u;")?
+            .parse::<u32>()
+            .ok()
+    });
+    assert_eq!(
+        declared,
+        Some(MASK_PROFILE),
+        "kernel {} was written for mask profile {:?}, masks are in profile {}",
+        kernel_name,
+        declared,
+        MASK_PROFILE
+    );
+}
+
+/// A mask produced by `gen_mask`
 pub struct Mask {
-    pub img: RgbaImage,
+    channels: MaskChannels,
 }
 
 impl Mask {
-    pub fn new(mask_idx: u32) -> Self {
-        let img = data::mask_i(mask_idx);
-
-        Self { img }
+    pub fn new(layout: &DataLayout, mask_idx: u32) -> Result<Self> {
+        Ok(MaskChannels::load(&layout.mask(mask_idx))?.into())
     }
 
+    /// Cosine similarity of the gradients of two masks
     pub fn dot(&self, b: &Mask) -> f32 {
-        let mut sum_exact = 0u64;
-        let mut norm1_exact = 0u64;
-        let mut norm2_exact = 0u64;
-
-        for (p_a, p_b) in self.pixels().zip(b.pixels()) {
-            let p_a_0 = p_a.0[0] as u64;
-            let p_a_1 = p_a.0[1] as u64;
+        let mut sum = 0.0;
+        let mut norm1 = 0.0;
+        let mut norm2 = 0.0;
 
-            let p_b_0 = p_b.0[0] as u64;
-            let p_b_1 = p_b.0[1] as u64;
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let [a_x, a_y] = self.grad(x, y).map(f64::from);
+                let [b_x, b_y] = b.grad(x, y).map(f64::from);
 
-            sum_exact += p_a_0 * p_b_0 + p_a_1 * p_b_1;
-            norm1_exact += p_a_0 * p_a_0 + p_a_1 * p_a_1;
-            norm2_exact += p_b_0 * p_b_0 + p_b_1 * p_b_1;
+                sum += a_x * b_x + a_y * b_y;
+                norm1 += a_x * a_x + a_y * a_y;
+                norm2 += b_x * b_x + b_y * b_y;
+            }
         }
 
-        let norm1 = (norm1_exact as f64).sqrt();
-        let norm2 = (norm2_exact as f64).sqrt();
+        (sum / (norm1.sqrt() * norm2.sqrt())) as f32
+    }
+}
 
-        ((sum_exact as f64) / (norm1 * norm2)) as f32
+impl From<MaskChannels> for Mask {
+    fn from(channels: MaskChannels) -> Self {
+        Self { channels }
     }
 }
 
 impl Deref for Mask {
-    type Target = RgbaImage;
+    type Target = MaskChannels;
 
     fn deref(&self) -> &Self::Target {
-        &self.img
-    }
-}
-
-impl DerefMut for Mask {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.img
+        &self.channels
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{write_png, MaskChannels, MASK_PROFILE};
+    use crate::layout::DataLayout;
+
+    #[test]
+    fn channels_roundtrip_through_png() {
+        let grad_x = [0.0, 0.5, -1.0, 1.5];
+        let grad_y = [1.0, -0.25, 0.0, 0.0];
+        let mut channels = MaskChannels::from_gradients(2, 2, &grad_x, &grad_y);
+        // averages 0.0225, so the gain is capped at 2
+        channels.set_surround(&[0.0, 0.09, 0.0, 0.0]);
+
+        let path = std::env::temp_dir().join("earthfinder_mask_test.png");
+        channels.save(&path).unwrap();
+        let loaded = MaskChannels::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.image(), channels.image());
+        assert_eq!(loaded.grad(1, 0), [85.0 / 255.0, 42.0 / 255.0]);
+        assert_eq!(loaded.grad(0, 1), [170.0 / 255.0, 0.0]);
+        assert_eq!(loaded.grad_l1(0, 0), 170.0 / 255.0);
+        assert_eq!(loaded.surround(1, 0), 90.0 / 255.0);
+        assert_eq!(loaded.surround(0, 0), 0.0);
+    }
+
+    #[test]
+    fn masks_of_another_profile_are_rejected() {
+        let channels = MaskChannels::from_gradients(2, 2, &[0.0, 0.5, 1.0, 1.5], &[0.0; 4]);
+        let path = std::env::temp_dir().join("earthfinder_mask_profile_test.png");
+
+        write_png(&path, &channels.to_rgb(), MASK_PROFILE + 1).unwrap();
+        let err = MaskChannels::load(&path).err().unwrap().to_string();
+        assert!(err.contains("run gen_mask again"), "{}", err);
+
+        // masks written before profiles were recorded are still read
+        channels.to_rgb().save(&path).unwrap();
+        assert_eq!(MaskChannels::load(&path).unwrap().image(), channels.image());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn main_pass_reads_current_profile() {
+        super::check_kernel_profile("main_pass_zoom_bins");
+    }
+
     #[test]
     fn test_lol() {
-        let mask1 = super::Mask::new(2349);
-        let mask2 = super::Mask::new(2350);
+        let layout = DataLayout::new("data");
+        let mask1 = super::Mask::new(&layout, 2349).unwrap();
+        let mask2 = super::Mask::new(&layout, 2350).unwrap();
 
         println!("dot self: {}", mask1.dot(&mask1));
         println!("dot lol: {}", mask1.dot(&mask2));
@@ -66,15 +304,16 @@ mod tests {
     fn test_mask_dot() {
         fastrand::seed(0);
 
-        let _ = std::fs::remove_dir_all("data/debug");
-        let _ = std::fs::create_dir_all("data/debug");
+        let layout = DataLayout::new("data");
+        let _ = std::fs::remove_dir_all(layout.debug_dir());
+        let _ = std::fs::create_dir_all(layout.debug_dir());
 
         for i in 0..1000 {
             let r1 = fastrand::u32(1..6100);
             let dr = fastrand::u32(1..60);
 
-            let mask1 = super::Mask::new(r1);
-            let mask2 = super::Mask::new(r1 + dr);
+            let mask1 = super::Mask::new(&layout, r1).unwrap();
+            let mask2 = super::Mask::new(&layout, r1 + dr).unwrap();
 
             let diff = mask1.dot(&mask2);
             if diff > 0.8 {
@@ -83,16 +322,16 @@ mod tests {
 
             let mut image_both = image::RgbaImage::new(mask1.width() * 2, mask1.height());
 
-            for (x, y, p) in mask1.enumerate_pixels() {
+            for (x, y, p) in mask1.image().enumerate_pixels() {
                 image_both.put_pixel(x, y, *p);
             }
 
-            for (x, y, p) in mask2.enumerate_pixels() {
+            for (x, y, p) in mask2.image().enumerate_pixels() {
                 image_both.put_pixel(x + mask1.width(), y, *p);
             }
 
             image_both
-                .save(format!("data/debug/mask_{}.png", i))
+                .save(layout.debug_dir().join(format!("mask_{}.png", i)))
                 .unwrap();
         }
     }
@@ -0,0 +1,248 @@
+//! The most similar masks of every frame, precomputed over the whole animation by
+//! `mask_similarity` so that `gpu` can reuse the results of similar frames that are
+//! far apart. One JSON object per line and per frame, in
+//! [`DataLayout::mask_similarity`].
+
+use crate::cli::MaskSimilarityArgs;
+use crate::error::{self, EarthfinderError, Result};
+use crate::layout::DataLayout;
+use crate::mask_store::MaskStore;
+use crate::project::Project;
+use nanoserde::{DeJson, SerJson};
+use rustc_hash::FxHashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Masks less similar than this don't share excluded tiles in `gpu`
+pub const MIN_SIMILARITY: f32 = 0.8;
+
+/// The masks most similar to the one of a frame
+#[derive(Debug, Clone, PartialEq, DeJson, SerJson)]
+pub struct SimilarMasks {
+    pub frame: u32,
+    /// Most similar first
+    pub similar: Vec<SimilarMask>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, DeJson, SerJson)]
+pub struct SimilarMask {
+    pub frame: u32,
+    /// See [`crate::mask::Mask::dot`]
+    pub similarity: f32,
+}
+
+/// Sparse matrix of the `k` most similar masks of each frame
+#[derive(Debug, Default)]
+pub struct SimilarityMatrix {
+    rows: FxHashMap<u32, Vec<SimilarMask>>,
+    /// Last frame listing each frame as similar, among the frames after it
+    last_needed: FxHashMap<u32, u32>,
+}
+
+impl SimilarityMatrix {
+    /// Compares every mask of `store` with every other one, keeping the `k` most
+    /// similar masks of each frame with a similarity of at least `min_similarity`
+    pub fn compute(store: &MaskStore, k: usize, min_similarity: f32) -> SimilarityMatrix {
+        use rayon::prelude::*;
+
+        let frames = store.frames();
+        let rows = (0..frames.len())
+            .into_par_iter()
+            .map(|a| {
+                let mut similar = (0..frames.len())
+                    .filter(|&b| b != a)
+                    .map(|b| SimilarMask {
+                        frame: frames[b],
+                        similarity: store.similarity_at(a, b),
+                    })
+                    .filter(|s| s.similarity >= min_similarity)
+                    .collect::<Vec<_>>();
+                similar.sort_unstable_by(|x, y| {
+                    y.similarity
+                        .total_cmp(&x.similarity)
+                        .then(x.frame.cmp(&y.frame))
+                });
+                similar.truncate(k);
+                SimilarMasks {
+                    frame: frames[a],
+                    similar,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        SimilarityMatrix::from_rows(rows)
+    }
+
+    pub fn from_rows(rows: Vec<SimilarMasks>) -> SimilarityMatrix {
+        let mut last_needed = FxHashMap::<u32, u32>::default();
+        for row in &rows {
+            for s in row.similar.iter().filter(|s| s.frame < row.frame) {
+                let last = last_needed.entry(s.frame).or_default();
+                *last = (*last).max(row.frame);
+            }
+        }
+
+        SimilarityMatrix {
+            rows: rows
+                .into_iter()
+                .map(|row| (row.frame, row.similar))
+                .collect(),
+            last_needed,
+        }
+    }
+
+    /// Masks most similar to the one of `frame`, most similar first
+    pub fn similar(&self, frame: u32) -> &[SimilarMask] {
+        self.rows.get(&frame).map_or(&[], Vec::as_slice)
+    }
+
+    /// Whether a frame after `current` lists `frame` among its similar masks
+    pub fn needed_after(&self, frame: u32, current: u32) -> bool {
+        self.last_needed
+            .get(&frame)
+            .is_some_and(|&last| last > current)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let io_err = |e| EarthfinderError::io(path, e);
+
+        let mut frames = self.rows.keys().copied().collect::<Vec<_>>();
+        frames.sort_unstable();
+
+        let mut out = BufWriter::new(File::create(path).map_err(io_err)?);
+        for frame in frames {
+            let row = SimilarMasks {
+                frame,
+                similar: self.rows[&frame].clone(),
+            };
+            writeln!(out, "{}", row.serialize_json()).map_err(io_err)?;
+        }
+        out.flush().map_err(io_err)
+    }
+
+    /// Reads a matrix written by [`SimilarityMatrix::write`], lines that can't be parsed
+    /// are returned as errors
+    pub fn read(path: &Path) -> Result<(SimilarityMatrix, Vec<EarthfinderError>)> {
+        let content = std::fs::read_to_string(path).map_err(|e| EarthfinderError::io(path, e))?;
+
+        let mut rows = vec![];
+        let mut errors = vec![];
+        for (i, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match SimilarMasks::deserialize_json(line) {
+                Ok(row) => rows.push(row),
+                Err(e) => errors.push(EarthfinderError::parse(path, i + 1, e.msg)),
+            }
+        }
+        Ok((SimilarityMatrix::from_rows(rows), errors))
+    }
+}
+
+pub fn mask_similarity(
+    layout: &DataLayout,
+    project: &Project,
+    args: &MaskSimilarityArgs,
+) -> Result<()> {
+    let first = args.first.unwrap_or(project.frames.first);
+    let last = args.last.unwrap_or(project.frames.last);
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| layout.mask_similarity());
+
+    let (store, errors) = MaskStore::load(layout, first..=last);
+    error::report("masks", &errors);
+    eprintln!("Comparing {} masks...", store.len());
+
+    let matrix = SimilarityMatrix::compute(&store, args.k, args.min_similarity);
+    matrix.write(&output)?;
+
+    let n_similar = store
+        .frames()
+        .iter()
+        .map(|&frame| matrix.similar(frame).len())
+        .sum::<usize>();
+    eprintln!(
+        "{} similar masks ({:.1} per frame) written to {}",
+        n_similar,
+        n_similar as f32 / store.len().max(1) as f32,
+        output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SimilarMask, SimilarMasks, SimilarityMatrix};
+    use crate::mask::MaskChannels;
+    use crate::mask_store::MaskStore;
+
+    fn mask(grad_x: [f32; 4], grad_y: [f32; 4]) -> MaskChannels {
+        MaskChannels::from_gradients(2, 2, &grad_x, &grad_y)
+    }
+
+    #[test]
+    fn nearest_masks_are_kept() {
+        let store = MaskStore::from_masks(vec![
+            (1, mask([1.0, 0.0, 0.0, 0.0], [0.0; 4])),
+            (2, mask([0.0; 4], [1.0, 1.0, 0.0, 0.0])),
+            (3, mask([1.0, 0.1, 0.0, 0.0], [0.0; 4])),
+            (4, mask([1.0, 0.2, 0.0, 0.0], [0.0; 4])),
+            (9, mask([1.0, 0.0, 0.0, 0.0], [0.0; 4])),
+        ]);
+
+        let matrix = SimilarityMatrix::compute(&store, 2, 0.8);
+        let frames = |frame| {
+            matrix
+                .similar(frame)
+                .iter()
+                .map(|s| s.frame)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(frames(1), [9, 3]);
+        assert_eq!(frames(2), [] as [u32; 0]);
+        assert_eq!(frames(4), [3, 1]);
+        assert_eq!(frames(9), [1, 3]);
+        assert!((matrix.similar(9)[0].similarity - 1.0).abs() < 1e-6);
+
+        // 1 is similar to 9, 3 to 4 and 9
+        assert!(matrix.needed_after(1, 8));
+        assert!(!matrix.needed_after(1, 9));
+        assert!(matrix.needed_after(3, 4));
+        assert!(!matrix.needed_after(2, 0));
+        assert!(!matrix.needed_after(9, 0));
+    }
+
+    #[test]
+    fn matrix_roundtrip() {
+        let matrix = SimilarityMatrix::from_rows(vec![
+            SimilarMasks {
+                frame: 4,
+                similar: vec![SimilarMask {
+                    frame: 2,
+                    similarity: 0.912_345_6,
+                }],
+            },
+            SimilarMasks {
+                frame: 2,
+                similar: vec![],
+            },
+        ]);
+
+        let path = std::env::temp_dir().join("earthfinder_mask_similarity_test.jsonl");
+        matrix.write(&path).unwrap();
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str("{\"frame\": 7, \"sim\n");
+        std::fs::write(&path, content).unwrap();
+
+        let (read, errors) = SimilarityMatrix::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(read.similar(4), matrix.similar(4));
+        assert!(read.similar(2).is_empty());
+        assert!(read.needed_after(2, 3));
+    }
+}
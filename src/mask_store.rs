@@ -0,0 +1,197 @@
+//! Masks decoded once and kept in memory for a whole run, rather than read again from
+//! their PNG every time they are compared.
+
+use crate::error::{EarthfinderError, Result};
+use crate::layout::DataLayout;
+use crate::mask::{Mask, MaskChannels};
+
+/// Masks of a range of frames, with their gradients scaled to unit length so that
+/// comparing two masks is a single dot product
+pub struct MaskStore {
+    /// Sorted
+    frames: Vec<u32>,
+    masks: Vec<Mask>,
+    /// Gradients of each mask, x and y interleaved, `grad_len` values per mask
+    unit_grads: Vec<f32>,
+    grad_len: usize,
+}
+
+impl MaskStore {
+    /// Loads the masks of `frames`. Frames with no mask are left out, masks that can't
+    /// be read or whose size differs from the first one are returned as errors.
+    pub fn load(
+        layout: &DataLayout,
+        frames: impl IntoIterator<Item = u32>,
+    ) -> (MaskStore, Vec<EarthfinderError>) {
+        use rayon::prelude::*;
+
+        let frames = frames.into_iter().collect::<Vec<_>>();
+        let loaded = frames
+            .par_iter()
+            .filter_map(|&frame| {
+                let path = layout.mask(frame);
+                if !std::fs::exists(&path).unwrap_or(false) {
+                    return None;
+                }
+                Some(MaskChannels::load(&path).map(|mask| (frame, mask)))
+            })
+            .collect::<Vec<Result<_>>>();
+
+        let mut masks = vec![];
+        let mut errors = vec![];
+        for mask in loaded {
+            match mask {
+                Ok(mask) => masks.push(mask),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        let size = masks.first().map(|(_, mask)| mask.dimensions());
+        masks.retain(|(frame, mask)| {
+            let same_size = Some(mask.dimensions()) == size;
+            if !same_size {
+                errors.push(EarthfinderError::layout(
+                    layout.mask(*frame),
+                    format!("mask is {:?}, expected {:?}", mask.dimensions(), size),
+                ));
+            }
+            same_size
+        });
+
+        (MaskStore::from_masks(masks), errors)
+    }
+
+    /// Masks of the same size, with their frame number
+    pub fn from_masks(mut masks: Vec<(u32, MaskChannels)>) -> MaskStore {
+        masks.sort_unstable_by_key(|(frame, _)| *frame);
+        masks.dedup_by_key(|(frame, _)| *frame);
+
+        let grad_len = masks
+            .first()
+            .map_or(0, |(_, mask)| (mask.width() * mask.height() * 2) as usize);
+        let mut unit_grads = Vec::with_capacity(masks.len() * grad_len);
+        for (_, mask) in &masks {
+            let start = unit_grads.len();
+            for y in 0..mask.height() {
+                for x in 0..mask.width() {
+                    unit_grads.extend(mask.grad(x, y));
+                }
+            }
+            let norm = dot(&unit_grads[start..], &unit_grads[start..]).sqrt();
+            // a blank mask is similar to nothing
+            if norm > 0.0 {
+                unit_grads[start..].iter_mut().for_each(|v| *v /= norm);
+            }
+        }
+
+        let (frames, masks) = masks
+            .into_iter()
+            .map(|(frame, mask)| (frame, Mask::from(mask)))
+            .unzip();
+        MaskStore {
+            frames,
+            masks,
+            unit_grads,
+            grad_len,
+        }
+    }
+
+    /// Frames that have a mask, in order
+    pub fn frames(&self) -> &[u32] {
+        &self.frames
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Size of the masks, none if the store is empty
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.masks.first().map(|mask| mask.dimensions())
+    }
+
+    pub fn get(&self, frame: u32) -> Option<&Mask> {
+        Some(&self.masks[self.index(frame)?])
+    }
+
+    /// Cosine similarity of the gradients of two masks, as [`Mask::dot`]
+    pub fn similarity(&self, a: u32, b: u32) -> Option<f32> {
+        Some(self.similarity_at(self.index(a)?, self.index(b)?))
+    }
+
+    /// [`MaskStore::similarity`] of the `a`-th and `b`-th masks of [`MaskStore::frames`]
+    pub fn similarity_at(&self, a: usize, b: usize) -> f32 {
+        dot(self.unit_grad(a), self.unit_grad(b))
+    }
+
+    fn index(&self, frame: u32) -> Option<usize> {
+        self.frames.binary_search(&frame).ok()
+    }
+
+    fn unit_grad(&self, i: usize) -> &[f32] {
+        &self.unit_grads[i * self.grad_len..(i + 1) * self.grad_len]
+    }
+}
+
+/// Dot product, summed in lanes so that it gets vectorized
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    const LANES: usize = 8;
+    let mut sums = [0.0; LANES];
+    let (a_chunks, b_chunks) = (a.chunks_exact(LANES), b.chunks_exact(LANES));
+    let tail = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(a, b)| a * b)
+        .sum::<f32>();
+    for (a, b) in a_chunks.zip(b_chunks) {
+        for ((sum, a), b) in sums.iter_mut().zip(a).zip(b) {
+            *sum += a * b;
+        }
+    }
+    sums.iter().sum::<f32>() + tail
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MaskStore;
+    use crate::mask::MaskChannels;
+
+    fn random_mask(w: u32, h: u32) -> MaskChannels {
+        let n = (w * h) as usize;
+        let grad = || (0..n).map(|_| fastrand::f32() * 1.5).collect::<Vec<_>>();
+        MaskChannels::from_gradients(w, h, &grad(), &grad())
+    }
+
+    #[test]
+    fn similarities_match_mask_dot() {
+        fastrand::seed(21);
+        let masks = [7, 3, 12]
+            .into_iter()
+            .map(|frame| (frame, random_mask(12, 9)))
+            .chain([(
+                5,
+                MaskChannels::from_gradients(12, 9, &[0.0; 108], &[0.0; 108]),
+            )])
+            .collect();
+        let store = MaskStore::from_masks(masks);
+
+        assert_eq!(store.frames(), [3, 5, 7, 12]);
+        assert_eq!(store.dimensions(), Some((12, 9)));
+        assert!(store.get(4).is_none());
+
+        for a in [3, 7, 12] {
+            for b in [3, 7, 12] {
+                let expected = store.get(a).unwrap().dot(store.get(b).unwrap());
+                let similarity = store.similarity(a, b).unwrap();
+                assert!((similarity - expected).abs() < 1e-5, "{a} {b}");
+            }
+        }
+        assert_eq!(store.similarity(3, 5), Some(0.0));
+        assert_eq!(store.similarity(3, 4), None);
+    }
+}
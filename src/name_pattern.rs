@@ -0,0 +1,124 @@
+//! File names holding a frame number, such as `bad_apple_{n:03}.png`.
+//!
+//! A pattern is literal text around a single `{n}`, or `{n:0W}` to pad the number to
+//! `W` digits. When reading names the number can have any width. `{prefix}` stands for
+//! [`crate::project::FramesConfig::prefix`], see [`NamePattern::with_prefix`].
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamePattern {
+    head: String,
+    width: usize,
+    tail: String,
+}
+
+impl NamePattern {
+    /// The pattern with `{prefix}` replaced by `prefix`
+    pub fn with_prefix(&self, prefix: &str) -> NamePattern {
+        NamePattern {
+            head: self.head.replace("{prefix}", prefix),
+            width: self.width,
+            tail: self.tail.replace("{prefix}", prefix),
+        }
+    }
+
+    /// Name of frame `n`
+    pub fn format(&self, n: u32) -> String {
+        format!(
+            "{}{:0width$}{}",
+            self.head,
+            n,
+            self.tail,
+            width = self.width
+        )
+    }
+
+    /// Frame number of a file name, if it matches the pattern
+    pub fn frame_number(&self, name: &str) -> Option<u32> {
+        let digits = name.strip_prefix(&self.head)?.strip_suffix(&self.tail)?;
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    }
+}
+
+impl FromStr for NamePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: &str| format!("invalid name pattern {:?}: {}", s, why);
+
+        let start = s.find("{n").ok_or_else(|| invalid("no {n}"))?;
+        let len = s[start..].find('}').ok_or_else(|| invalid("unclosed {n"))? + 1;
+        let (head, tail) = (&s[..start], &s[start + len..]);
+        if tail.contains("{n") {
+            return Err(invalid("more than one {n}"));
+        }
+        if [head, tail].iter().any(|part| part.contains(['/', '\\'])) {
+            return Err(invalid("not a file name"));
+        }
+
+        let width = match &s[start + 2..start + len - 1] {
+            "" => 0,
+            spec => spec
+                .strip_prefix(":0")
+                .and_then(|width| width.parse().ok())
+                .ok_or_else(|| invalid("expected {n} or {n:0W}"))?,
+        };
+
+        Ok(NamePattern {
+            head: head.to_string(),
+            width,
+            tail: tail.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for NamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.width {
+            0 => write!(f, "{}{{n}}{}", self.head, self.tail),
+            width => write!(f, "{}{{n:0{}}}{}", self.head, width, self.tail),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NamePattern;
+
+    #[test]
+    fn patterns_format_and_match() {
+        let pattern: NamePattern = "{prefix}{n:03}.png".parse().unwrap();
+        assert_eq!(pattern.to_string(), "{prefix}{n:03}.png");
+
+        let pattern = pattern.with_prefix("bad_apple_");
+        assert_eq!(pattern.format(7), "bad_apple_007.png");
+        assert_eq!(pattern.format(6562), "bad_apple_6562.png");
+        assert_eq!(pattern.frame_number("bad_apple_007.png"), Some(7));
+        assert_eq!(pattern.frame_number("bad_apple_12.png"), Some(12));
+        assert_eq!(pattern.frame_number("bad_apple_.png"), None);
+        assert_eq!(pattern.frame_number("bad_apple_1a.png"), None);
+        assert_eq!(pattern.frame_number("bad_apple_007.jpg"), None);
+
+        let pattern: NamePattern = "cat-{n}-gray.jpg".parse().unwrap();
+        assert_eq!(pattern.format(42), "cat-42-gray.jpg");
+        assert_eq!(pattern.frame_number("cat-0042-gray.jpg"), Some(42));
+    }
+
+    #[test]
+    fn bad_patterns_are_rejected() {
+        for pattern in [
+            "frame.png",
+            "{n}_{n}.png",
+            "{n:3}.png",
+            "{n.png",
+            "frames/{n}.png",
+        ] {
+            assert!(pattern.parse::<NamePattern>().is_err(), "{}", pattern);
+        }
+    }
+}
@@ -0,0 +1,487 @@
+use crate::candidates::candidates_path;
+use crate::cli::{
+    refine_config, search_bins, zooms_or, GenMaskArgs, GpuArgs, PipelineArgs, RenderArgs,
+    TilesGradArgs,
+};
+use crate::error::{EarthfinderError, Result};
+use crate::layout::DataLayout;
+use crate::project::Project;
+use crate::{gen_mask, gpu_all, render, tiles_grad};
+use nanoserde::{DeJson, SerJson};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The stages of the pipeline, in the order they run
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum Stage {
+    GenMask,
+    TilesGrad,
+    Gpu,
+    Render,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::GenMask, Stage::TilesGrad, Stage::Gpu, Stage::Render];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::GenMask => "gen_mask",
+            Stage::TilesGrad => "tiles_grad",
+            Stage::Gpu => "gpu",
+            Stage::Render => "render",
+        }
+    }
+
+    /// Bump when the output of a stage changes for the same inputs and parameters,
+    /// so that it gets recomputed
+    fn version(self) -> u32 {
+        match self {
+            Stage::GenMask => 2,
+            Stage::TilesGrad => 1,
+            Stage::Gpu => 1,
+            Stage::Render => 1,
+        }
+    }
+
+    fn upstream(self) -> &'static [Stage] {
+        match self {
+            Stage::GenMask | Stage::TilesGrad => &[],
+            Stage::Gpu => &[Stage::GenMask, Stage::TilesGrad],
+            Stage::Render => &[Stage::Gpu],
+        }
+    }
+}
+
+/// Arguments of a stage when it is run
+pub enum StageArgs<'a> {
+    GenMask(&'a GenMaskArgs),
+    TilesGrad(&'a TilesGradArgs),
+    Gpu(&'a GpuArgs),
+    Render(&'a RenderArgs),
+}
+
+impl StageArgs<'_> {
+    pub fn stage(&self) -> Stage {
+        match self {
+            StageArgs::GenMask(_) => Stage::GenMask,
+            StageArgs::TilesGrad(_) => Stage::TilesGrad,
+            StageArgs::Gpu(_) => Stage::Gpu,
+            StageArgs::Render(_) => Stage::Render,
+        }
+    }
+
+    /// Every setting the output of the stage depends on, with the project defaults applied
+    fn params(&self, project: &Project) -> String {
+        let params = match self {
+            StageArgs::GenMask(args) => format!(
+                "{:?}",
+                (
+                    &project.frames.prefix,
+                    &project.frames.dir,
+                    &project.frames.pattern,
+                    &project.frames.animation,
+                    project.frames.first,
+                    args.stride.unwrap_or(project.frames.stride),
+                    gen_mask::mask_size(project, args),
+                    &project.mask.name,
+                    project.mask.sigmas,
+                    crate::mask::MASK_PROFILE,
+                    args.debug,
+                )
+            ),
+            StageArgs::TilesGrad(args) => format!(
+                "{:?}",
+                (
+                    zooms_or(&args.zooms, project),
+                    args.max_latitude.unwrap_or(project.tiles.max_latitude),
+                    args.show_original,
+                )
+            ),
+            StageArgs::Gpu(args) => format!(
+                "{:?}",
+                (
+                    zooms_or(&args.zooms, project),
+                    args.first.unwrap_or(project.frames.first),
+                    args.last.unwrap_or(project.frames.last),
+                    args.top_k.unwrap_or(project.search.top_k),
+                    &args.output,
+                    args.save_tile_scores,
+                    search_bins(args, project),
+                    refine_config(args, project),
+                )
+            ),
+            StageArgs::Render(args) => format!(
+                "{:?}",
+                (
+                    &args.csv,
+                    &args.output,
+                    args.z_up,
+                    args.max_z,
+                    args.thumbnail_height,
+                    args.cut_fade,
+                )
+            ),
+        };
+        format!("v{} {}", self.stage().version(), params)
+    }
+
+    /// Hash of the files the stage reads. Stages reading the output of another stage
+    /// hash its manifest instead of walking its output.
+    fn inputs_hash(&self, layout: &DataLayout, project: &Project) -> u64 {
+        let mut hasher = StableHasher::default();
+        for &up in self.stage().upstream() {
+            read_manifest(layout, up)
+                .map(|m| m.serialize_json())
+                .hash(&mut hasher);
+        }
+        match self {
+            StageArgs::GenMask(_) => match layout.animation() {
+                Some(animation) => hash_files(&mut hasher, &animation),
+                None => hash_files(&mut hasher, &layout.frames_dir()),
+            },
+            StageArgs::TilesGrad(args) => {
+                let scheme = layout.tile_scheme();
+                for &z in zooms_or(&args.zooms, project) {
+                    hash_files(&mut hasher, &scheme.zoom_dir(&layout.tiles_dir(), z));
+                }
+            }
+            StageArgs::Gpu(_) => {
+                hash_files(&mut hasher, &layout.mask_similarity());
+                hash_files(&mut hasher, &layout.shots());
+            }
+            StageArgs::Render(args) => {
+                let csv = args.csv.clone().unwrap_or_else(|| layout.results_csv());
+                hash_files(&mut hasher, &csv);
+                if args.cut_fade > 0 {
+                    hash_files(&mut hasher, &layout.shots());
+                }
+            }
+        }
+        hasher.finish()
+    }
+
+    fn manifest(&self, layout: &DataLayout, project: &Project) -> Manifest {
+        let mut params_hasher = StableHasher::default();
+        self.params(project).hash(&mut params_hasher);
+        Manifest {
+            stage: self.stage().name().to_string(),
+            params: format!("{:016x}", params_hasher.finish()),
+            inputs: format!("{:016x}", self.inputs_hash(layout, project)),
+        }
+    }
+}
+
+/// Written next to the output of a stage once it completed, records what it was computed from
+#[derive(Debug, Clone, PartialEq, DeJson, SerJson)]
+pub struct Manifest {
+    pub stage: String,
+    /// Hash of the parameters of the stage
+    pub params: String,
+    /// Hash of the inputs of the stage
+    pub inputs: String,
+}
+
+fn manifest_path(layout: &DataLayout, stage: Stage) -> std::path::PathBuf {
+    match stage {
+        // tiles can be shared between projects, so their manifest lives with them
+        Stage::TilesGrad => layout.tiles_manifests_dir(),
+        _ => layout.manifests_dir(),
+    }
+    .join(format!("{}.json", stage.name()))
+}
+
+/// Written before a stage runs and removed once it completed, so that the output of an
+/// interrupted run is known to come from these parameters
+fn running_manifest_path(layout: &DataLayout, stage: Stage) -> std::path::PathBuf {
+    manifest_path(layout, stage).with_extension("running.json")
+}
+
+pub fn read_manifest(layout: &DataLayout, stage: Stage) -> Option<Manifest> {
+    read_manifest_at(&manifest_path(layout, stage))
+}
+
+fn read_manifest_at(path: &Path) -> Option<Manifest> {
+    let content = std::fs::read_to_string(path).ok()?;
+    Manifest::deserialize_json(&content).ok()
+}
+
+fn write_manifest(path: &Path, manifest: &Manifest) -> Result<()> {
+    let _ = std::fs::create_dir_all(path.parent().unwrap());
+    std::fs::write(path, manifest.serialize_json()).map_err(|e| EarthfinderError::io(path, e))
+}
+
+/// Runs a stage and records its manifest once it completed
+pub fn run_stage(layout: &DataLayout, project: &Project, stage_args: StageArgs) -> Result<()> {
+    let stage = stage_args.stage();
+    let running = running_manifest_path(layout, stage);
+    write_manifest(&running, &stage_args.manifest(layout, project))?;
+
+    match &stage_args {
+        StageArgs::GenMask(args) => gen_mask::gen_masks(layout, project, args)?,
+        StageArgs::TilesGrad(args) => {
+            for &z in zooms_or(&args.zooms, project) {
+                tiles_grad::gen_tiles_grad(layout, project, z, args);
+            }
+        }
+        StageArgs::Gpu(args) => gpu_all::gpu_all(layout, project, args)?,
+        StageArgs::Render(args) => render::render(layout, project, args)?,
+    }
+    write_manifest(
+        &manifest_path(layout, stage),
+        &stage_args.manifest(layout, project),
+    )?;
+    let _ = std::fs::remove_file(&running);
+    Ok(())
+}
+
+/// Warns about the stages `stage` depends on that are missing or out of date
+pub fn check_upstream(layout: &DataLayout, project: &Project, stage: Stage) {
+    let defaults = DefaultArgs::default();
+    for &up in stage.upstream() {
+        let expected = defaults.args(up).manifest(layout, project);
+        match read_manifest(layout, up) {
+            None => eprintln!("/!\\ Warning: {} has never been run", up.name()),
+            Some(m) if m != expected => {
+                eprintln!(
+                    "/!\\ Warning: {} is out of date with the project",
+                    up.name()
+                )
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+/// Arguments used for the stages run by `pipeline`, everything comes from the project
+struct DefaultArgs {
+    gen_mask: GenMaskArgs,
+    tiles_grad: TilesGradArgs,
+    gpu: GpuArgs,
+    render: RenderArgs,
+}
+
+impl Default for DefaultArgs {
+    fn default() -> Self {
+        use clap::Parser;
+
+        /// Gets the arguments of a command as if it was called without any
+        #[derive(Parser)]
+        struct Defaults<T: clap::Args> {
+            #[command(flatten)]
+            args: T,
+        }
+
+        fn defaults<T: clap::Args>() -> T {
+            Defaults::<T>::parse_from(["pipeline"]).args
+        }
+
+        Self {
+            gen_mask: defaults(),
+            tiles_grad: defaults(),
+            gpu: defaults(),
+            render: defaults(),
+        }
+    }
+}
+
+impl DefaultArgs {
+    fn args(&self, stage: Stage) -> StageArgs<'_> {
+        match stage {
+            Stage::GenMask => StageArgs::GenMask(&self.gen_mask),
+            Stage::TilesGrad => StageArgs::TilesGrad(&self.tiles_grad),
+            Stage::Gpu => StageArgs::Gpu(&self.gpu),
+            Stage::Render => StageArgs::Render(&self.render),
+        }
+    }
+}
+
+/// Runs gen_mask → tiles_grad → gpu → render, skipping the stages that are up to date
+pub fn pipeline(layout: &DataLayout, project: &Project, args: &PipelineArgs) -> Result<()> {
+    let defaults = DefaultArgs::default();
+
+    for stage in Stage::ALL {
+        let stage_args = defaults.args(stage);
+        let expected = stage_args.manifest(layout, project);
+        let current = read_manifest(layout, stage);
+
+        let forced = args.force.contains(&stage);
+        if !forced && current.as_ref() == Some(&expected) {
+            eprintln!("{}: up to date", stage.name());
+        } else {
+            // the output of the last run, completed or interrupted
+            let last_run = read_manifest_at(&running_manifest_path(layout, stage)).or(current);
+            let resumed = !forced && last_run.as_ref() == Some(&expected);
+            let reason = if forced {
+                "forced"
+            } else if resumed {
+                "interrupted"
+            } else if last_run.is_none() {
+                "never run"
+            } else {
+                "out of date"
+            };
+
+            if args.dry_run {
+                eprintln!("{}: would run ({})", stage.name(), reason);
+            } else {
+                eprintln!("{}: running ({})", stage.name(), reason);
+
+                if stage == Stage::Gpu && last_run.is_some() && !resumed {
+                    // gpu skips the frames already in the CSV, they are stale now
+                    let csv = layout.results_csv();
+                    for path in [candidates_path(&csv), csv] {
+                        if let Some(stale) = move_stale(&path) {
+                            eprintln!("moved stale results to {}", stale.display());
+                        }
+                    }
+                }
+
+                run_stage(layout, project, stage_args)?;
+            }
+        }
+
+        if args.until == Some(stage) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves `path` to `<path>.stale`, or `<path>.stale.<N>` so that no earlier stale file
+/// is overwritten. Returns where it went, None if there was nothing to move.
+fn move_stale(path: &Path) -> Option<PathBuf> {
+    if !path.exists() {
+        return None;
+    }
+    let stale = (1..)
+        .map(|n| {
+            let mut stale = path.as_os_str().to_owned();
+            stale.push(".stale");
+            if n > 1 {
+                stale.push(format!(".{}", n));
+            }
+            PathBuf::from(stale)
+        })
+        .find(|stale| !stale.exists())?;
+    std::fs::rename(path, &stale).ok()?;
+    Some(stale)
+}
+
+/// Hashes the path, size and modification time of every file under `path`
+fn hash_files(hasher: &mut StableHasher, path: &Path) {
+    let mut entries = walkdir::WalkDir::new(path)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .peekable();
+
+    if entries.peek().is_none() {
+        "empty".hash(hasher);
+    }
+
+    for entry in entries {
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        entry.path().hash(hasher);
+        meta.len().hash(hasher);
+        meta.modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .hash(hasher);
+    }
+}
+
+/// FNV-1a, so that hashes stay the same between builds
+struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{move_stale, DefaultArgs, Stage, StageArgs};
+    use crate::cli::GenMaskArgs;
+    use crate::layout::DataLayout;
+    use crate::project::Project;
+
+    #[test]
+    fn manifest_tracks_params_and_inputs() {
+        let root = std::env::temp_dir().join("earthfinder_manifest_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let layout = DataLayout::new(&root);
+        std::fs::create_dir_all(layout.frames_dir()).unwrap();
+
+        let mut project = Project::default();
+        let defaults = DefaultArgs::default();
+
+        let m1 = defaults.args(Stage::GenMask).manifest(&layout, &project);
+        assert_eq!(
+            m1,
+            defaults.args(Stage::GenMask).manifest(&layout, &project)
+        );
+
+        project.mask.size = [48, 36];
+        let m2 = defaults.args(Stage::GenMask).manifest(&layout, &project);
+        assert_ne!(m1.params, m2.params);
+        assert_eq!(m1.inputs, m2.inputs);
+
+        let args = GenMaskArgs {
+            debug: false,
+            stride: None,
+            size: Some(vec![48, 36]),
+        };
+        assert_eq!(StageArgs::GenMask(&args).manifest(&layout, &project), m2);
+
+        std::fs::write(layout.frame(1), b"frame").unwrap();
+        let m3 = defaults.args(Stage::GenMask).manifest(&layout, &project);
+        assert_eq!(m2.params, m3.params);
+        assert_ne!(m2.inputs, m3.inputs);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn stale_results_are_never_overwritten() {
+        let dir = std::env::temp_dir().join("earthfinder_stale_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let csv = dir.join("out.csv");
+
+        assert_eq!(move_stale(&csv), None);
+        for (run, stale) in ["out.csv.stale", "out.csv.stale.2", "out.csv.stale.3"]
+            .into_iter()
+            .enumerate()
+        {
+            std::fs::write(&csv, run.to_string()).unwrap();
+            assert_eq!(move_stale(&csv), Some(dir.join(stale)));
+            assert!(!csv.exists());
+        }
+        assert_eq!(
+            std::fs::read_to_string(dir.join("out.csv.stale")).unwrap(),
+            "0"
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
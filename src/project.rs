@@ -0,0 +1,343 @@
+use crate::gpu::algorithm::SearchBins;
+use crate::name_pattern::NamePattern;
+use crate::tile_scheme::TileScheme;
+use crate::TILE_HEIGHT;
+use nanoserde::{DeJson, SerJson};
+use std::path::Path;
+
+/// Everything that defines a run: which animation is turned into masks and how,
+/// which tiles are searched and with which parameters.
+///
+/// Loaded from `<data>/project.json`. Every field is optional, missing ones take the
+/// values used for Bad Apple.
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct Project {
+    #[nserde(default)]
+    pub frames: FramesConfig,
+    #[nserde(default)]
+    pub mask: MaskConfig,
+    #[nserde(default)]
+    pub tiles: TilesConfig,
+    #[nserde(default)]
+    pub search: SearchConfig,
+    #[nserde(default)]
+    pub shots: ShotsConfig,
+    #[nserde(default)]
+    pub refine: RefineConfig,
+}
+
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct FramesConfig {
+    /// Prefix of the frame and mask file names, also names their directories
+    /// (`<prefix>frames` and `<prefix>masks`)
+    #[nserde(default = "bad_apple_")]
+    pub prefix: String,
+    /// Directory of the frames, relative to the data directory. Empty for `<prefix>frames`
+    #[nserde(default = "")]
+    pub dir: String,
+    /// Names of the frames, see [`NamePattern`]
+    #[nserde(default = "{prefix}{n:03}.png")]
+    pub pattern: String,
+    /// Animated file (`.y4m`, `.gif` or APNG) the frames are read from instead of `dir`,
+    /// relative to the data directory. Frames are numbered from 1
+    #[nserde(default = "")]
+    pub animation: String,
+    /// First frame searched by `gpu`
+    #[nserde(default = 1)]
+    pub first: u32,
+    /// Last frame searched by `gpu` (inclusive)
+    #[nserde(default = 6562)]
+    pub last: u32,
+    /// Only the frames whose number is `first` plus a multiple of `stride` are turned
+    /// into masks
+    #[nserde(default = 5)]
+    pub stride: usize,
+}
+
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct MaskConfig {
+    /// Width and height of the masks, the frames are resized to it. Multiples of 4
+    #[nserde(default_with = "default_mask_size")]
+    pub size: [u32; 2],
+    /// Names of the masks, see [`NamePattern`]
+    #[nserde(default = "{prefix}{n}.png")]
+    pub name: String,
+    /// Sigmas of the four gaussian blurs of the silhouette. The surround penalty
+    /// (blue channel) is `|g3 - g4| - 2 * |g1 - g2|`
+    #[nserde(default_with = "default_sigmas")]
+    pub sigmas: [f32; 4],
+}
+
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct TilesConfig {
+    /// Zoom levels of the tiles that are preprocessed and searched
+    #[nserde(default_with = "default_zooms")]
+    pub zooms: Vec<u32>,
+    /// Tiles further from the equator than this latitude (in degrees) are skipped
+    #[nserde(default = 70.0)]
+    pub max_latitude: f32,
+    /// How the tile files are named: `z/y/x` (s2maps), `z/x/y`, `tms` or `quadkey`
+    #[nserde(default = "z/y/x")]
+    pub scheme: String,
+}
+
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct SearchConfig {
+    /// Number of results kept per frame
+    #[nserde(default = 2)]
+    pub top_k: usize,
+    /// Decoded tiles kept in RAM during a search, in MB. The rest is mapped from a file
+    #[nserde(default = 8192)]
+    pub tile_cache_mb: u64,
+    /// VRAM the tiles can take to be uploaded once rather than for every frame, in MB.
+    /// 0 streams them, as do searches whose tiles don't fit
+    #[nserde(default = 0)]
+    pub resident_tiles_mb: u64,
+    /// Angles the masks are turned by, in degrees clockwise. Each one adds a pass over
+    /// the tiles, at most [`MAX_ROTATIONS`](crate::gpu::algorithm::MAX_ROTATIONS) of them
+    #[nserde(default_with = "default_angles")]
+    pub angles: Vec<f32>,
+    /// Zooms the masks are searched at, in tile pixels per mask pixel, at most
+    /// [`MAX_ZOOMS`](crate::gpu::algorithm::MAX_ZOOMS) of them. A mask must fit in 3/2 of
+    /// its size, so up to 1.5
+    #[nserde(default_with = "default_mask_zooms")]
+    pub mask_zooms: Vec<f32>,
+}
+
+/// How `shots` finds the cuts between two consecutive masks
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct ShotsConfig {
+    /// Masks at least this similar are in the same shot, see [`crate::mask::Mask::dot`]
+    #[nserde(default = 0.25)]
+    pub max_similarity: f32,
+    /// Masks whose edges are closer than this are in the same shot, from 0 (edges in the
+    /// same places) to 1, see [`crate::shots::edge_histogram`]
+    #[nserde(default = 0.5)]
+    pub min_histogram_distance: f32,
+    /// Shots with fewer masks are merged with their neighbours
+    #[nserde(default = 3)]
+    pub min_length: usize,
+}
+
+/// How `gpu` refines the best places found by the kernel, see [`crate::refine`]
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct RefineConfig {
+    /// Steps of the Nelder–Mead search of each place, 0 keeps the places of the kernel
+    #[nserde(default = 60)]
+    pub iterations: usize,
+    /// Farthest a place is moved, in tile pixels
+    #[nserde(default = 1.0)]
+    pub max_shift: f32,
+    /// Largest change of the zoom, relative to the zoom found by the kernel. The zoom stays
+    /// within the ones searched, see [`SearchConfig::mask_zooms`]
+    #[nserde(default = 0.1)]
+    pub max_zoom_change: f32,
+}
+
+fn default_sigmas() -> [f32; 4] {
+    [2.0, 1.0, 2.5, 1.0]
+}
+
+fn default_mask_size() -> [u32; 2] {
+    [32, 24]
+}
+
+fn default_zooms() -> Vec<u32> {
+    vec![7, 8, 9]
+}
+
+fn default_angles() -> Vec<f32> {
+    SearchBins::default().angles
+}
+
+fn default_mask_zooms() -> Vec<f32> {
+    SearchBins::default().zooms
+}
+
+macro_rules! default_from_json {
+    ($($ty:ty),*) => {
+        $(
+            impl Default for $ty {
+                fn default() -> Self {
+                    <$ty>::deserialize_json("{}").expect("defaults are always valid")
+                }
+            }
+        )*
+    };
+}
+
+default_from_json!(
+    Project,
+    FramesConfig,
+    MaskConfig,
+    TilesConfig,
+    SearchConfig,
+    ShotsConfig,
+    RefineConfig
+);
+
+impl FramesConfig {
+    pub fn name_pattern(&self) -> NamePattern {
+        self.pattern.parse().expect("checked by Project::validate")
+    }
+}
+
+impl MaskConfig {
+    pub fn name_pattern(&self) -> NamePattern {
+        self.name.parse().expect("checked by Project::validate")
+    }
+}
+
+impl SearchConfig {
+    pub fn bins(&self) -> SearchBins {
+        SearchBins {
+            zooms: self.mask_zooms.clone(),
+            angles: self.angles.clone(),
+        }
+    }
+}
+
+impl TilesConfig {
+    pub fn tile_scheme(&self) -> TileScheme {
+        self.scheme.parse().expect("checked by Project::validate")
+    }
+}
+
+impl Project {
+    /// Loads the project file, falling back to the defaults if it doesn't exist
+    pub fn load(path: &Path) -> Result<Project, String> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Project::default()),
+            Err(e) => return Err(format!("could not read {}: {}", path.display(), e)),
+        };
+
+        let project = Project::deserialize_json(&content)
+            .map_err(|e| format!("invalid project file {}: {}", path.display(), e))?;
+        project.validate()?;
+        Ok(project)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.frames.first > self.frames.last {
+            return Err(format!(
+                "frames.first ({}) is after frames.last ({})",
+                self.frames.first, self.frames.last
+            ));
+        }
+        if self.frames.stride == 0 {
+            return Err("frames.stride must be at least 1".to_string());
+        }
+        self.frames
+            .pattern
+            .parse::<NamePattern>()
+            .map_err(|e| format!("frames.pattern: {}", e))?;
+        check_mask_size(self.mask.size).map_err(|e| format!("mask.size: {}", e))?;
+        self.mask
+            .name
+            .parse::<NamePattern>()
+            .map_err(|e| format!("mask.name: {}", e))?;
+        if self.tiles.zooms.is_empty() {
+            return Err("tiles.zooms must not be empty".to_string());
+        }
+        self.tiles
+            .scheme
+            .parse::<TileScheme>()
+            .map_err(|e| format!("tiles.scheme: {}", e))?;
+        if self.search.top_k == 0 {
+            return Err("search.top_k must be at least 1".to_string());
+        }
+        self.search
+            .bins()
+            .check((self.mask.size[0], self.mask.size[1]))
+            .map_err(|e| format!("search: {}", e))?;
+        if self.shots.min_length == 0 {
+            return Err("shots.min_length must be at least 1".to_string());
+        }
+        if !(0.0..).contains(&self.refine.max_shift) {
+            return Err("refine.max_shift must not be negative".to_string());
+        }
+        if !(0.0..1.0).contains(&self.refine.max_zoom_change) {
+            return Err("refine.max_zoom_change must be within 0-1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Masks are sampled at a quarter of their resolution and must leave room to move
+/// inside a tile
+pub fn check_mask_size([w, h]: [u32; 2]) -> Result<(), String> {
+    if w == 0 || h == 0 || w % 4 != 0 || h % 4 != 0 {
+        return Err(format!("{}x{} is not a multiple of 4", w, h));
+    }
+    if w >= TILE_HEIGHT || h >= TILE_HEIGHT {
+        return Err(format!(
+            "{}x{} does not fit in a {}px tile",
+            w, h, TILE_HEIGHT
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Project;
+    use crate::tile_scheme::TileScheme;
+    use nanoserde::{DeJson, SerJson};
+
+    #[test]
+    fn partial_project_uses_defaults() {
+        let project = Project::deserialize_json(
+            r#"{"frames": {"prefix": "cat_", "last": 100}, "tiles": {"zooms": [8]}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(project.frames.prefix, "cat_");
+        assert_eq!(project.frames.first, 1);
+        assert_eq!(project.frames.last, 100);
+        assert_eq!(project.tiles.zooms, vec![8]);
+        assert_eq!(project.tiles.max_latitude, 70.0);
+        assert_eq!(project.mask.sigmas, [2.0, 1.0, 2.5, 1.0]);
+        assert_eq!(project.search.top_k, 2);
+        assert_eq!(project.search.angles, [0.0]);
+        assert_eq!(project.search.mask_zooms, [1.5, 1.3333, 1.1666, 1.0]);
+        assert_eq!(project.shots.min_length, 3);
+        assert_eq!(project.refine.iterations, 60);
+        assert_eq!(project.tiles.tile_scheme(), TileScheme::Zyx);
+        assert!(project.validate().is_ok());
+
+        let project = Project::deserialize_json(r#"{"tiles": {"scheme": "y/x/z"}}"#).unwrap();
+        assert!(project.validate().is_err());
+
+        for bad in [
+            r#"{"mask": {"size": [30, 24]}}"#,
+            r#"{"mask": {"size": [512, 24]}}"#,
+            r#"{"mask": {"name": "mask.png"}}"#,
+            r#"{"frames": {"pattern": "{n}_{n}.png"}}"#,
+            r#"{"shots": {"min_length": 0}}"#,
+            r#"{"search": {"angles": []}}"#,
+            r#"{"search": {"angles": [0, 5, 10, 15, 20, 25, 30, 35]}}"#,
+            r#"{"search": {"angles": [0, 270]}}"#,
+            r#"{"search": {"mask_zooms": [1.0, 1.6]}}"#,
+            r#"{"search": {"mask_zooms": []}}"#,
+            r#"{"refine": {"max_shift": -1}}"#,
+            r#"{"refine": {"max_zoom_change": 1.5}}"#,
+        ] {
+            let project = Project::deserialize_json(bad).unwrap();
+            assert!(project.validate().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn project_roundtrip() {
+        let mut project = Project::default();
+        project.mask.size = [48, 36];
+        project.frames.stride = 2;
+
+        let back = Project::deserialize_json(&project.serialize_json()).unwrap();
+        assert_eq!(back.mask.size, [48, 36]);
+        assert_eq!(back.frames.stride, 2);
+        assert_eq!(back.frames.prefix, "bad_apple_");
+        assert_eq!(back.frames.pattern, "{prefix}{n:03}.png");
+    }
+}
@@ -0,0 +1,410 @@
+//! Refinement of the places found by the kernel, which only tries whole tile pixels and
+//! a few zooms. Each of the best places is moved by a fraction of a pixel and zoomed in
+//! between the bins by a Nelder–Mead search on its full resolution gradient tile, so
+//! that the place matched drifts smoothly from frame to frame.
+
+use crate::data::TilePos;
+use crate::gpu::algorithm::{PosResult, PosResults, SearchBins};
+use crate::gpu::tile_cache::TileGrad;
+use crate::mask::MaskChannels;
+use crate::project::RefineConfig;
+use rustc_hash::FxHashMap;
+
+/// Weight of the tile gradient under the surround of the mask, `AROUND_COEFF_2` of the
+/// kernel
+const AROUND_COEFF: f32 = 1.0;
+
+/// How well `mask` matches the gradient tile at `pos`, as the detailed pass of the kernel
+/// scores it but sampling the tile under each mask pixel. The color match with the
+/// previous frame is left out. `NEG_INFINITY` if the mask reaches out of the tile or a
+/// pixel with no data.
+pub fn score(mask: &MaskChannels, tile_grad: &TileGrad, pos: &PosResult) -> f32 {
+    let (sin, cos) = pos.angle.to_radians().sin_cos();
+    let (sin2, cos2) = (sin * sin, cos * cos);
+
+    let mut sum = 0.0;
+    let mut total = 1e-5;
+    for yy in 0..mask.height() {
+        for xx in 0..mask.width() {
+            let (off_x, off_y) = pos.mask_offset(xx as f32, yy as f32, mask.dimensions(), 1);
+            let Some(tile) = sample(tile_grad, pos.x as f32 + off_x, pos.y as f32 + off_y) else {
+                return f32::NEG_INFINITY;
+            };
+
+            // the signs of the gradients aren't stored, as in the kernel their energy is
+            // shared between the axes of a turned mask
+            let [gx, gy] = mask.grad(xx, yy);
+            let (gx, gy) = if sin == 0.0 {
+                (gx, gy)
+            } else {
+                (
+                    (cos2 * gx * gx + sin2 * gy * gy).sqrt(),
+                    (sin2 * gx * gx + cos2 * gy * gy).sqrt(),
+                )
+            };
+
+            let attenuated = tile.map(|t| t - 0.3 * f32::max(0.1 - t, 0.0));
+            sum += gx * attenuated[0] + gy * attenuated[1]
+                - (tile[0] * tile[0] + tile[1] * tile[1]) * mask.surround(xx, yy) * AROUND_COEFF;
+            total += gx * gx + gy * gy;
+        }
+    }
+    sum / total
+}
+
+/// Gradient of the tile at (x, y), a Catmull-Rom spline through its 4x4 nearest pixels.
+/// Unlike a bilinear one it is smooth, so the best place can be between two pixels. None
+/// out of the tile or next to a pixel with no data.
+fn sample(tile_grad: &TileGrad, x: f32, y: f32) -> Option<[f32; 2]> {
+    if x < 1.0 || y < 1.0 {
+        return None;
+    }
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    if x0 + 2 >= tile_grad.width() || y0 + 2 >= tile_grad.height() {
+        return None;
+    }
+    let (wx, wy) = (catmull_rom(x.fract()), catmull_rom(y.fract()));
+
+    let mut value = [0.0; 2];
+    for (j, wy) in wy.into_iter().enumerate() {
+        for (i, wx) in wx.into_iter().enumerate() {
+            let pixel = tile_grad.get_pixel(x0 + i as u32 - 1, y0 + j as u32 - 1);
+            if pixel[0] == 255 {
+                return None;
+            }
+            value[0] += pixel[0] as f32 / 255.0 * wx * wy;
+            value[1] += pixel[1] as f32 / 255.0 * wx * wy;
+        }
+    }
+    Some(value)
+}
+
+/// Weights of the 4 pixels around a point `t` (0..1) after the second one
+fn catmull_rom(t: f32) -> [f32; 4] {
+    let (t2, t3) = (t * t, t * t * t);
+    [
+        (-t3 + 2.0 * t2 - t) / 2.0,
+        (3.0 * t3 - 5.0 * t2 + 2.0) / 2.0,
+        (-3.0 * t3 + 4.0 * t2 + t) / 2.0,
+        (t3 - t2) / 2.0,
+    ]
+}
+
+/// The place near `pos` with the best [`score`], moved by up to `max_shift` pixels and
+/// zoomed by up to `max_zoom_change` within the zooms of `bins`, the mask staying in the
+/// tile. [`score`] leaves out the color match of the kernel and isn't on its scale, so it
+/// goes in `refined_score`, also when the place isn't moved, while `score` stays the
+/// kernel's for the heuristics and the results CSV.
+pub fn refine(
+    mask: &MaskChannels,
+    tile_grad: &TileGrad,
+    pos: &PosResult,
+    bins: &SearchBins,
+    config: &RefineConfig,
+) -> PosResult {
+    let at = |[x, y, zoom]: [f32; 3]| PosResult {
+        x: x.floor() as u32,
+        y: y.floor() as u32,
+        sub_x: x.fract(),
+        sub_y: y.fract(),
+        zoom,
+        ..*pos
+    };
+    let cost = |point: [f32; 3]| -score(mask, tile_grad, &at(point));
+
+    let start = [pos.x as f32 + pos.sub_x, pos.y as f32 + pos.sub_y, pos.zoom];
+    let initial = cost(start);
+    if config.iterations == 0 {
+        return PosResult {
+            refined_score: Some(-initial),
+            ..*pos
+        };
+    }
+
+    let (min_zoom, max_zoom) = bins
+        .zooms
+        .iter()
+        .fold((f32::MAX, f32::MIN), |(lo, hi), &z| (lo.min(z), hi.max(z)));
+    let zoom = within(
+        start[2],
+        start[2] * (1.0 - config.max_zoom_change),
+        start[2] * (1.0 + config.max_zoom_change),
+        (min_zoom, max_zoom),
+    );
+    // the pixels sampled by the corners of the mask at the largest zoom, relative to (x, y)
+    let (w, h) = mask.dimensions();
+    let corners = [(0, 0), (w - 1, 0), (0, h - 1), (w - 1, h - 1)].map(|(xx, yy)| {
+        PosResult {
+            zoom: zoom.1,
+            sub_x: 0.0,
+            sub_y: 0.0,
+            ..*pos
+        }
+        .mask_offset(xx as f32, yy as f32, (w, h), 1)
+    });
+    let extent = |axis: fn(&(f32, f32)) -> f32, side: u32| {
+        let offsets = corners.iter().map(axis);
+        let lowest = offsets.clone().fold(f32::MAX, f32::min);
+        let highest = offsets.fold(f32::MIN, f32::max);
+        // sample needs a pixel before and two after each point
+        (1.0 - lowest, side as f32 - 2.0 - highest - 1e-3)
+    };
+    let bounds = [
+        within(
+            start[0],
+            start[0] - config.max_shift,
+            start[0] + config.max_shift,
+            extent(|c| c.0, tile_grad.width()),
+        ),
+        within(
+            start[1],
+            start[1] - config.max_shift,
+            start[1] + config.max_shift,
+            extent(|c| c.1, tile_grad.height()),
+        ),
+        zoom,
+    ];
+
+    let steps = [
+        config.max_shift / 2.0,
+        config.max_shift / 2.0,
+        start[2] * config.max_zoom_change / 2.0,
+    ];
+    let (best, best_cost) = nelder_mead(cost, start, steps, bounds, config.iterations);
+    if best_cost >= initial {
+        return PosResult {
+            refined_score: Some(-initial),
+            ..*pos
+        };
+    }
+    PosResult {
+        refined_score: Some(-best_cost),
+        ..at(best)
+    }
+}
+
+/// `lo..hi` clamped to `limits`, but always holding `start`
+fn within(start: f32, lo: f32, hi: f32, limits: (f32, f32)) -> (f32, f32) {
+    (
+        lo.max(limits.0).max(0.0).min(start),
+        hi.min(limits.1).max(start),
+    )
+}
+
+/// Minimizes `cost` from `start`, with an initial simplex of `steps` along each axis and
+/// every point kept within `bounds`
+fn nelder_mead(
+    cost: impl Fn([f32; 3]) -> f32,
+    start: [f32; 3],
+    steps: [f32; 3],
+    bounds: [(f32, f32); 3],
+    iterations: usize,
+) -> ([f32; 3], f32) {
+    let clamp = |p: [f32; 3]| std::array::from_fn(|i| p[i].clamp(bounds[i].0, bounds[i].1));
+    // a + (b - a) * t
+    let lerp =
+        |a: [f32; 3], b: [f32; 3], t: f32| clamp(std::array::from_fn(|i| a[i] + (b[i] - a[i]) * t));
+
+    let mut simplex = vec![(start, cost(start))];
+    for axis in 0..3 {
+        let mut p = start;
+        p[axis] += steps[axis];
+        let p = clamp(p);
+        simplex.push((p, cost(p)));
+    }
+
+    for _ in 0..iterations {
+        simplex.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+        let spread = simplex[3].1 - simplex[0].1;
+        if spread.abs() < 1e-6 {
+            break;
+        }
+
+        let centroid: [f32; 3] =
+            std::array::from_fn(|i| simplex[..3].iter().map(|(p, _)| p[i]).sum::<f32>() / 3.0);
+        let (worst, worst_cost) = simplex[3];
+
+        let reflected = lerp(worst, centroid, 2.0);
+        let reflected_cost = cost(reflected);
+        if reflected_cost < simplex[0].1 {
+            let expanded = lerp(worst, centroid, 3.0);
+            let expanded_cost = cost(expanded);
+            simplex[3] = if expanded_cost < reflected_cost {
+                (expanded, expanded_cost)
+            } else {
+                (reflected, reflected_cost)
+            };
+        } else if reflected_cost < simplex[2].1 {
+            simplex[3] = (reflected, reflected_cost);
+        } else {
+            let contracted = lerp(worst, centroid, 0.5);
+            let contracted_cost = cost(contracted);
+            if contracted_cost < worst_cost {
+                simplex[3] = (contracted, contracted_cost);
+            } else {
+                let best = simplex[0].0;
+                for (p, c) in &mut simplex[1..] {
+                    *p = lerp(best, *p, 0.5);
+                    *c = cost(*p);
+                }
+            }
+        }
+    }
+
+    simplex
+        .into_iter()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .expect("the simplex has 4 points")
+}
+
+/// [`refine`]s each of `results`, with the gradient of their tile given by `tile_grad`,
+/// and ranks them again by their `refined_score`. Places whose tile isn't given are
+/// ranked last.
+pub fn refine_results<'a>(
+    mask: &MaskChannels,
+    results: &PosResults,
+    tile_grad: impl Fn(TilePos) -> Option<TileGrad<'a>>,
+    bins: &SearchBins,
+    config: &RefineConfig,
+) -> PosResults {
+    use rayon::prelude::*;
+
+    let found = results
+        .results()
+        .iter()
+        .filter(|pos| pos.tile_x != u32::MAX)
+        .collect::<Vec<_>>();
+    // places often share a tile, which may have to be decoded
+    let mut tiles = FxHashMap::default();
+    for pos in &found {
+        tiles
+            .entry(pos.tile_pos())
+            .or_insert_with(|| tile_grad(pos.tile_pos()));
+    }
+    let refined = found
+        .par_iter()
+        .map(|pos| match &tiles[&pos.tile_pos()] {
+            Some(tile_grad) => refine(mask, tile_grad, pos, bins, config),
+            None => **pos,
+        })
+        .collect::<Vec<_>>();
+
+    let mut ranked = PosResults::new(results.results().len());
+    for pos in refined {
+        ranked.insert(pos);
+    }
+    ranked.sort_by(|pos| pos.refined_score.unwrap_or(f32::NEG_INFINITY));
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{refine, score};
+    use crate::gpu::algorithm::{PosResult, SearchBins};
+    use crate::gpu::tile_cache::TileGrad;
+    use crate::mask::MaskChannels;
+    use crate::project::RefineConfig;
+    use image::RgbaImage;
+    use nanoserde::DeJson;
+    use std::borrow::Cow;
+
+    /// Blurred vertical edges at `edges_x`, as `tiles_grad` would find them
+    fn tile_with_edges(edges_x: &[f32]) -> RgbaImage {
+        RgbaImage::from_fn(64, 64, |x, _| {
+            let v = edges_x
+                .iter()
+                .map(|e| (-(x as f32 - e).powi(2) / 4.5).exp())
+                .sum::<f32>();
+            image::Rgba([(200.0 * v) as u8, 0, 0, 255])
+        })
+    }
+
+    #[test]
+    fn places_are_refined_between_pixels_and_zooms() {
+        let (w, h) = (16, 12);
+        let grad_x = (0..w * h)
+            .map(|i| if i % w == 2 || i % w == 14 { 1.0 } else { 0.0 })
+            .collect::<Vec<_>>();
+        let mask = MaskChannels::from_gradients(w, h, &grad_x, &vec![0.0; (w * h) as usize]);
+        // the edges of the mask land on the ones of the tile at 12.3, zoomed by 1.05
+        let tile = tile_with_edges(&[12.3 + 2.0 * 1.05, 12.3 + 14.0 * 1.05]);
+        let tile = TileGrad::from_raw(64, 64, Cow::Borrowed(tile.as_raw().as_slice())).unwrap();
+
+        let pos = PosResult {
+            tile_x: 0,
+            tile_y: 0,
+            tile_z: 7,
+            x: 12,
+            y: 20,
+            score: -1.0,
+            zoom: 1.0,
+            ..PosResult::default()
+        };
+        let bins = SearchBins::default();
+        let config = RefineConfig::deserialize_json("{}").unwrap();
+        let refined = refine(&mask, &tile, &pos, &bins, &config);
+
+        assert_eq!(refined.x, 12);
+        assert!((refined.sub_x - 0.3).abs() < 0.1, "{:?}", refined);
+        assert!((refined.zoom - 1.05).abs() < 0.01, "{:?}", refined);
+        // the score of the kernel is kept, the refined one is on the scale of `score`
+        assert_eq!(refined.score, pos.score);
+        assert_eq!(refined.refined_score, Some(score(&mask, &tile, &refined)));
+        assert!(refined.refined_score > Some(score(&mask, &tile, &pos)));
+
+        let config = RefineConfig {
+            iterations: 0,
+            ..config
+        };
+        let unrefined = PosResult {
+            refined_score: Some(score(&mask, &tile, &pos)),
+            ..pos
+        };
+        assert_eq!(refine(&mask, &tile, &pos, &bins, &config), unrefined);
+
+        let outside = PosResult { x: 60, ..pos };
+        assert_eq!(score(&mask, &tile, &outside), f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn refined_places_stay_in_the_bins_and_the_tile() {
+        let (w, h) = (16, 12);
+        let mask = MaskChannels::from_gradients(
+            w,
+            h,
+            &vec![1.0; (w * h) as usize],
+            &vec![0.0; (w * h) as usize],
+        );
+        let tile = RgbaImage::from_pixel(64, 64, image::Rgba([100, 100, 0, 255]));
+        let tile = TileGrad::from_raw(64, 64, Cow::Borrowed(tile.as_raw().as_slice())).unwrap();
+        let bins = SearchBins {
+            zooms: vec![1.0, 1.1],
+            angles: vec![0.0],
+        };
+        let config = RefineConfig {
+            max_shift: 8.0,
+            max_zoom_change: 0.5,
+            ..RefineConfig::deserialize_json("{}").unwrap()
+        };
+
+        for x in [1, 20, 45] {
+            let pos = PosResult {
+                tile_x: 0,
+                tile_y: 0,
+                tile_z: 7,
+                x,
+                y: 30,
+                zoom: 1.1,
+                ..PosResult::default()
+            };
+            let refined = refine(&mask, &tile, &pos, &bins, &config);
+            assert!((1.0..=1.1).contains(&refined.zoom), "{:?}", refined);
+            assert!(refined.refined_score.unwrap().is_finite(), "{:?}", refined);
+            assert!(
+                refined.x as f32 + refined.sub_x + 15.0 * refined.zoom < 62.0,
+                "{:?}",
+                refined
+            );
+        }
+    }
+}
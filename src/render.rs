@@ -1,22 +1,34 @@
-use crate::data::{deform_width, parse_csv, TilePos};
-use crate::gpu::algorithm::{PosResult, STEP_SIZE};
+use crate::animation::Animation;
+use crate::cli::RenderArgs;
+use crate::data::{open_image, TilePos};
+use crate::error::{self, EarthfinderError, Result};
+use crate::geo::deform_width;
+use crate::gpu::algorithm::{offset_pixel, PosResult, STEP_SIZE};
+use crate::layout::DataLayout;
+use crate::project::Project;
+use crate::results::{self, FrameData};
+use crate::shots::Shots;
 use crate::TILE_HEIGHT;
 use image::imageops::FilterType;
-use image::{GenericImageView, ImageError, RgbImage};
+use image::{DynamicImage, RgbImage};
 use rustc_hash::{FxHashMap, FxHashSet};
-use std::io::{stdout, ErrorKind, Write};
+use std::io::ErrorKind;
+use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 pub fn tiles_needed(mask_size: (u32, u32), result: &PosResult, z_up: u32) -> FxHashSet<TilePos> {
     let upscale = 1 << z_up;
     let mut tiles = FxHashSet::default();
 
     let deform_w = deform_width(TILE_HEIGHT, result.tile_y, result.tile_z);
+    let up_size = (mask_size.0 * upscale, mask_size.1 * upscale);
 
     for yy in 0..mask_size.1 * upscale {
         for xx in 0..mask_size.0 * upscale {
-            let up_x = (result.x * STEP_SIZE as u32) * upscale + (xx as f32 * result.zoom) as u32;
-            let up_y = (result.y * STEP_SIZE as u32) * upscale + (yy as f32 * result.zoom) as u32;
+            let (off_x, off_y) = result.mask_offset(xx as f32, yy as f32, up_size, upscale);
+            let up_x = offset_pixel((result.x * STEP_SIZE as u32) * upscale, off_x);
+            let up_y = offset_pixel((result.y * STEP_SIZE as u32) * upscale, off_y);
 
             let tile_x = result.tile_x * upscale + up_x / deform_w;
             let tile_y = result.tile_y * upscale + up_y / TILE_HEIGHT;
@@ -28,90 +40,114 @@ pub fn tiles_needed(mask_size: (u32, u32), result: &PosResult, z_up: u32) -> FxH
     tiles
 }
 
-pub fn fetch_tiles_to_cache<'a>(tiles: &FxHashSet<TilePos>) {
+/// Downloads the `tiles` not on disk yet. Tiles that can't be fetched are returned as
+/// errors, the frames needing them then fail to render.
+pub fn fetch_tiles_to_cache(
+    layout: &DataLayout,
+    tiles: &FxHashSet<TilePos>,
+) -> Vec<EarthfinderError> {
     use rayon::prelude::*;
     let i = AtomicUsize::new(0);
     let n = tiles.len();
-    tiles.par_iter().for_each(|(x, y, z)| {
-        let path = format!("./data/tiles/{z}/{y}/{x}.tif");
-        let path_png = format!("./data/tiles/{z}/{y}/{x}.png");
-
-        if !std::fs::exists(&path_png).unwrap() {
-            std::fs::create_dir_all(format!("./data/tiles/{z}/{y}")).unwrap();
-            let aws_path = format!("s3://eox-s2maps/tiles/{z}/{y}/{x}.tif");
-
-            let output = std::process::Command::new("aws")
-                .arg("s3")
-                .arg("cp")
-                .arg(&aws_path)
-                .arg(&path)
-                .arg("--request-payer")
-                .arg("requester")
-                .output()
-                .map_err(|e| panic!("Failed to fetch tile {z}/{y}/{x}: {:?}", e))
-                .unwrap();
-
-            if !output.status.success() {
-                stdout().write_all(&output.stderr).unwrap();
-                stdout().write_all(&output.stdout).unwrap();
-                eprintln!("Failed to fetch tile {z}/{y}/{x}");
-                return;
+    tiles
+        .par_iter()
+        .filter_map(|&pos| {
+            let fetched = fetch_tile(layout, pos);
+            let v = i.fetch_add(1, Ordering::Relaxed);
+            if v.is_multiple_of(100) {
+                eprintln!("fetch: {} / {}", v, n);
             }
+            fetched.err()
+        })
+        .collect()
+}
 
-            let _ = std::process::Command::new("magick")
-                .arg("mogrify")
-                .arg("-format")
-                .arg("png")
-                .arg(&path)
-                .output();
+fn fetch_tile(layout: &DataLayout, pos @ (x, y, z): TilePos) -> Result<()> {
+    let path_png = layout.tile(pos);
+    let path = layout.tile_tif(pos);
 
-            let _ = std::fs::remove_file(&path);
-        }
+    if std::fs::exists(&path_png).map_err(|e| EarthfinderError::io(&path_png, e))? {
+        return Ok(());
+    }
+    if let Some(dir) = path_png.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| EarthfinderError::io(dir, e))?;
+    }
+    // the bucket is always z/y/x, whatever the scheme of the local tiles
+    let aws_path = format!("s3://eox-s2maps/tiles/{z}/{y}/{x}.tif");
+
+    let output = std::process::Command::new("aws")
+        .arg("s3")
+        .arg("cp")
+        .arg(&aws_path)
+        .arg(&path)
+        .arg("--request-payer")
+        .arg("requester")
+        .output()
+        .map_err(|e| EarthfinderError::layout(&path, format!("could not run aws: {}", e)))?;
+    if !output.status.success() {
+        return Err(EarthfinderError::layout(
+            &path,
+            format!(
+                "could not fetch {}: {}",
+                aws_path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
 
-        let v = i.fetch_add(1, Ordering::Relaxed);
-        if v % 100 == 0 {
-            eprintln!("fetch: {} / {}", v, n);
-        }
-    });
+    let output = std::process::Command::new("magick")
+        .arg("mogrify")
+        .arg("-format")
+        .arg("png")
+        .arg(&path)
+        .output();
+    let _ = std::fs::remove_file(&path);
+    match output {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(EarthfinderError::layout(
+            &path,
+            format!(
+                "could not convert to png: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        )),
+        Err(e) => Err(EarthfinderError::layout(
+            &path,
+            format!("could not run magick: {}", e),
+        )),
+    }
 }
 
-fn render_final<'a>(
-    mask_idx: u32,
-    mask_size: (u32, u32),
+/// Renders the place matched by `result` from the tiles `z_up` zoom levels above the tile
+/// it was found in, `mask_size` times `2^z_up` pixels. The tiles must already be on disk,
+/// see [`tiles_needed`] and [`fetch_tiles_to_cache`].
+pub fn render_result(
+    layout: &DataLayout,
     result: &PosResult,
-    tiles_needed: impl Iterator<Item = &'a TilePos>,
-) {
-    let mut z_up = 6.min(13 - result.tile_z);
+    mask_size: (u32, u32),
+    z_up: u32,
+) -> Result<RgbImage> {
     let upscale = 1 << z_up;
 
     let deform_w = deform_width(TILE_HEIGHT, result.tile_y, result.tile_z);
-    let tiles = tiles_needed
-        .map(|pos @ &(x, y, z)| {
-            let path = format!("./data/tiles/{z}/{y}/{x}.png");
-            let mut image = image::open(&path)
-                .unwrap_or_else(|e| {
-                    if let ImageError::IoError(io_err) = &e {
-                        if io_err.kind() == ErrorKind::UnexpectedEof {
+    let tiles = tiles_needed(mask_size, result, z_up)
+        .into_iter()
+        .map(|pos @ (x, y, z)| {
+            let path = layout.tile(pos);
+            let mut image = open_image(&path)
+                .inspect_err(|e| {
+                    if let EarthfinderError::Io { source, .. } = e {
+                        if source.kind() == ErrorKind::UnexpectedEof {
                             eprintln!("Removing corrupted tile: {z}/{y}/{x}");
                             let _ = std::fs::remove_file(&path);
                         }
                     }
-                    panic!(
-                        "Failed to open tile {z}/{y}/{x} for mask {mask_idx}: {:?}",
-                        e
-                    )
-                })
+                })?
                 .to_rgb8();
             image = image::imageops::resize(&image, deform_w, TILE_HEIGHT, FilterType::Lanczos3);
-            (pos, image)
+            Ok((pos, image))
         })
-        .collect::<FxHashMap<_, _>>();
-
-    let thumbnail_size = 100;
-    let real_frame = image::open(format!("data/bad_apple_frames/bad_apple_{mask_idx:03}.png"))
-        .unwrap()
-        .resize(4 * thumbnail_size, 3 * thumbnail_size, FilterType::Lanczos3)
-        .to_rgb8();
+        .collect::<Result<FxHashMap<_, _>>>()?;
 
     let img_w = mask_size.0 * upscale;
     let img_h = mask_size.1 * upscale;
@@ -119,8 +155,9 @@ fn render_final<'a>(
 
     for yy in 0..mask_size.1 * upscale {
         for xx in 0..mask_size.0 * upscale {
-            let up_x = (result.x * STEP_SIZE as u32) * upscale + (xx as f32 * result.zoom) as u32;
-            let up_y = (result.y * STEP_SIZE as u32) * upscale + (yy as f32 * result.zoom) as u32;
+            let (off_x, off_y) = result.mask_offset(xx as f32, yy as f32, (img_w, img_h), upscale);
+            let up_x = offset_pixel((result.x * STEP_SIZE as u32) * upscale, off_x);
+            let up_y = offset_pixel((result.y * STEP_SIZE as u32) * upscale, off_y);
 
             let up_tile_x = up_x / deform_w;
             let up_tile_y = up_y / TILE_HEIGHT;
@@ -131,8 +168,8 @@ fn render_final<'a>(
                 result.tile_z + z_up,
             )];
 
-            let up_x = ((result.x * STEP_SIZE as u32) * upscale) as f32 + (xx as f32 * result.zoom);
-            let up_y = ((result.y * STEP_SIZE as u32) * upscale) as f32 + (yy as f32 * result.zoom);
+            let up_x = (((result.x * STEP_SIZE as u32) * upscale) as f32 + off_x).max(0.0);
+            let up_y = (((result.y * STEP_SIZE as u32) * upscale) as f32 + off_y).max(0.0);
 
             let up_x_fract = up_x.fract();
             let up_y_fract = up_y.fract();
@@ -161,7 +198,39 @@ fn render_final<'a>(
         }
     }
 
-    while z_up < 6 {
+    Ok(img)
+}
+
+/// Renders a frame, with its satellite image multiplied by `fade`
+fn render_final(
+    layout: &DataLayout,
+    args: &RenderArgs,
+    output: &Path,
+    frame: &FrameData,
+    mask_size: (u32, u32),
+    thumbnails: &FxHashMap<u32, RgbImage>,
+    fade: f32,
+) -> Result<()> {
+    let (mask_idx, result) = (frame.frame, &frame.result);
+    let mut z_up = render_z_up(args, result);
+    let mut img = render_result(layout, result, mask_size, z_up)?;
+    if fade < 1.0 {
+        img.pixels_mut()
+            .for_each(|p| p.0 = p.0.map(|v| (v as f32 * fade) as u8));
+    }
+
+    let real_frame = match (layout.animation(), thumbnails.get(&mask_idx)) {
+        (_, Some(thumbnail)) => thumbnail.clone(),
+        (Some(animation), None) => {
+            return Err(EarthfinderError::layout(
+                animation,
+                format!("no frame {}", mask_idx),
+            ))
+        }
+        (None, None) => thumbnail(open_image(&layout.frame(mask_idx))?, args.thumbnail_height),
+    };
+
+    while z_up < args.z_up {
         img = image::imageops::resize(
             &img,
             img.width() * 2,
@@ -181,35 +250,111 @@ fn render_final<'a>(
         }
     }
 
-    img.save(format!("data/render_final/{}.png", mask_idx))
-        .unwrap();
+    let path = output.join(format!("{}.png", mask_idx));
+    img.save(&path)
+        .map_err(|e| EarthfinderError::image(path, e))
+}
+
+/// How much of the satellite image is left in a frame near a cut, from `1 / (cut_fade + 1)`
+/// on the frames around the cut to 1 past `cut_fade` frames
+fn cut_fade(shots: Option<&Shots>, frame: u32, cut_fade: u32) -> f32 {
+    match shots.and_then(|shots| shots.cut_distance(frame)) {
+        Some(distance) => ((distance + 1) as f32 / (cut_fade + 1) as f32).min(1.0),
+        None => 1.0,
+    }
+}
+
+fn thumbnail(frame: DynamicImage, height: u32) -> RgbImage {
+    frame
+        .resize(height * 4 / 3, height, FilterType::Lanczos3)
+        .to_rgb8()
+}
+
+/// Thumbnails of the `wanted` frames of an animation, which can only be read in order
+fn animation_thumbnails(
+    path: &Path,
+    wanted: &FxHashSet<u32>,
+    height: u32,
+) -> Result<FxHashMap<u32, RgbImage>> {
+    let mut thumbnails = FxHashMap::default();
+    for frame in Animation::open(path)? {
+        let (n, frame) = frame?;
+        if wanted.contains(&n) {
+            thumbnails.insert(n, thumbnail(frame, height));
+            if thumbnails.len() == wanted.len() {
+                break;
+            }
+        }
+    }
+    Ok(thumbnails)
+}
+
+/// How many zoom levels above the matched tile the frame is rendered from
+fn render_z_up(args: &RenderArgs, result: &PosResult) -> u32 {
+    args.z_up.min(args.max_z.saturating_sub(result.tile_z))
 }
 
-pub fn render(path: &str) {
+pub fn render(layout: &DataLayout, project: &Project, args: &RenderArgs) -> Result<()> {
     use rayon::prelude::*;
 
-    let _ = std::fs::create_dir_all("data/render_final");
+    let path = args.csv.clone().unwrap_or_else(|| {
+        let default_path = layout.results_csv();
+        eprintln!(
+            "No path provided, using default: {}",
+            default_path.display()
+        );
+        default_path
+    });
+    let output = args.output.clone().unwrap_or_else(|| layout.render_dir());
+
+    let _ = std::fs::create_dir_all(&output);
 
-    let mask_example = image::open("data/bad_apple_masks/bad_apple_5.png").unwrap();
-    let mask_size = mask_example.dimensions();
+    let [mask_w, mask_h] = project.mask.size;
+    let mask_size = (mask_w, mask_h);
 
-    let csv = std::fs::read_to_string(path).unwrap();
-    let frames = parse_csv(&csv);
+    let (frames, errors) = results::read(&path)?;
+    error::report("lines", &errors);
 
     let i = AtomicUsize::new(0);
 
     let tiles_to_fetch = frames
         .iter()
-        .flat_map(|frame| tiles_needed(mask_size, &frame.result, 6.min(13 - frame.result.tile_z)))
+        .flat_map(|frame| tiles_needed(mask_size, &frame.result, render_z_up(args, &frame.result)))
         .collect::<FxHashSet<_>>();
-    fetch_tiles_to_cache(&tiles_to_fetch);
+    let fetch_errors = fetch_tiles_to_cache(layout, &tiles_to_fetch);
+
+    let thumbnails = match layout.animation() {
+        Some(animation) => {
+            let wanted = frames.iter().map(|frame| frame.frame).collect();
+            animation_thumbnails(&animation, &wanted, args.thumbnail_height)?
+        }
+        None => FxHashMap::default(),
+    };
+
+    let shots = match args.cut_fade {
+        0 => None,
+        _ => {
+            let shots = Shots::read_if_any(layout)?;
+            if shots.is_none() {
+                eprintln!("No shots found, run `shots` first to fade the cuts");
+            }
+            shots
+        }
+    };
+
+    let errors = Mutex::new(fetch_errors);
 
     frames.par_iter().for_each(|frame| {
-        let needed = tiles_needed(mask_size, &frame.result, 6.min(13 - frame.result.tile_z));
-        render_final(frame.frame, mask_size, &frame.result, needed.iter());
+        let fade = cut_fade(shots.as_ref(), frame.frame, args.cut_fade);
+        if let Err(e) = render_final(layout, args, &output, frame, mask_size, &thumbnails, fade) {
+            errors.lock().unwrap().push(e);
+        }
         let val = i.fetch_add(1, Ordering::Relaxed);
-        if val % 100 == 0 {
+        if val.is_multiple_of(100) {
             eprintln!("render: {} / {}", val, frames.len());
         }
     });
+
+    error::report("tiles and frames", &errors.into_inner().unwrap());
+    Ok(())
 }
@@ -0,0 +1,792 @@
+//! The results CSV written by `gpu` and read by `render`.
+//!
+//! Since version 2 the file starts with a `#earthfinder-results v<N>` line, followed by
+//! the column names. Columns are looked up by name, so their order doesn't matter when
+//! reading, files in another order are rewritten before `gpu` appends to them.
+//! Version 1 files (no version line, `Frame` capitalized) are still read, and are
+//! migrated when `gpu` appends to them. Version 3 adds the `angle` the mask was turned
+//! by, frames of older files are read with an angle of 0. Version 4 adds the sub-pixel
+//! part of the position, `sub_x` and `sub_y`, 0 in older files. Version 5 adds the
+//! `refined_score` of the places refined, empty when they weren't.
+
+use crate::error::{EarthfinderError, Result};
+use crate::gpu::algorithm::PosResult;
+use rustc_hash::FxHashMap;
+use std::collections::hash_map::Entry;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const VERSION: u32 = 5;
+
+const VERSION_PREFIX: &str = "#earthfinder-results v";
+
+const COLUMNS: [&str; 13] = [
+    "frame",
+    "tile_x",
+    "tile_y",
+    "tile_z",
+    "zoom",
+    "x",
+    "y",
+    "score",
+    "time",
+    "angle",
+    "sub_x",
+    "sub_y",
+    "refined_score",
+];
+
+/// Columns that must be present, the ones after them are optional
+const REQUIRED_COLUMNS: usize = 8;
+
+/// The best place found for one frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameData {
+    pub frame: u32,
+    pub result: PosResult,
+    /// Seconds the GPU took to search this frame
+    pub time: Option<f32>,
+}
+
+/// Reads a results CSV, see [`parse`]
+pub fn read(path: &Path) -> Result<(Vec<FrameData>, Vec<EarthfinderError>)> {
+    let content = std::fs::read_to_string(path).map_err(|e| EarthfinderError::io(path, e))?;
+    parse(path, &content).map(|(_, frames, errors)| (frames, errors))
+}
+
+/// Parses a results CSV, returning its version and its frames. A missing or invalid
+/// header is an error, while malformed lines and frames appearing twice are returned
+/// next to the frames that could be read. `path` is only used to report errors.
+pub fn parse(path: &Path, content: &str) -> Result<(u32, Vec<FrameData>, Vec<EarthfinderError>)> {
+    let mut lines = content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty());
+
+    let (mut header_no, mut header) = lines
+        .next()
+        .ok_or_else(|| EarthfinderError::parse(path, 1, "empty results file"))?;
+
+    let version = match header.strip_prefix(VERSION_PREFIX) {
+        Some(version) => {
+            let version = version.parse::<u32>().map_err(|_| {
+                EarthfinderError::parse(path, header_no, format!("invalid version {:?}", version))
+            })?;
+            if version > VERSION {
+                return Err(EarthfinderError::parse(
+                    path,
+                    header_no,
+                    format!(
+                        "version {} was written by a newer earthfinder (this one reads up to {})",
+                        version, VERSION
+                    ),
+                ));
+            }
+            (header_no, header) = lines
+                .next()
+                .ok_or_else(|| EarthfinderError::parse(path, header_no + 1, "missing columns"))?;
+            version
+        }
+        None => 1,
+    };
+
+    let names = header
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let mut indices = [None; COLUMNS.len()];
+    for (index, column) in indices.iter_mut().zip(COLUMNS) {
+        *index = names.iter().position(|name| name == column);
+    }
+    if let Some(missing) = COLUMNS[..REQUIRED_COLUMNS]
+        .iter()
+        .zip(indices)
+        .find(|(_, index)| index.is_none())
+    {
+        return Err(EarthfinderError::parse(
+            path,
+            header_no,
+            format!("missing column {}", missing.0),
+        ));
+    }
+
+    let mut frames: Vec<FrameData> = vec![];
+    let mut frame_lines = FxHashMap::default();
+    let mut errors = vec![];
+
+    for (line_no, line) in lines {
+        if line.starts_with('#') {
+            continue;
+        }
+        let frame = match parse_line(line, &indices) {
+            Ok(frame) => frame,
+            Err(msg) => {
+                errors.push(EarthfinderError::parse(path, line_no, msg));
+                continue;
+            }
+        };
+        match frame_lines.entry(frame.frame) {
+            Entry::Occupied(first) => errors.push(EarthfinderError::parse(
+                path,
+                line_no,
+                format!(
+                    "frame {} already found on line {}",
+                    frame.frame,
+                    first.get()
+                ),
+            )),
+            Entry::Vacant(entry) => {
+                entry.insert(line_no);
+                frames.push(frame);
+            }
+        }
+    }
+
+    Ok((version, frames, errors))
+}
+
+type Indices = [Option<usize>; COLUMNS.len()];
+
+fn parse_line(line: &str, indices: &Indices) -> Result<FrameData, String> {
+    let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+
+    Ok(FrameData {
+        frame: required(&fields, indices, 0)?,
+        result: PosResult {
+            tile_x: required(&fields, indices, 1)?,
+            tile_y: required(&fields, indices, 2)?,
+            tile_z: required(&fields, indices, 3)?,
+            zoom: required(&fields, indices, 4)?,
+            x: required(&fields, indices, 5)?,
+            y: required(&fields, indices, 6)?,
+            score: required(&fields, indices, 7)?,
+            angle: field(&fields, indices, 9)?.unwrap_or(0.0),
+            sub_x: field(&fields, indices, 10)?.unwrap_or(0.0),
+            sub_y: field(&fields, indices, 11)?.unwrap_or(0.0),
+            refined_score: field(&fields, indices, 12)?,
+        },
+        time: field(&fields, indices, 8)?,
+    })
+}
+
+/// Value of `column` on a line, `None` if the file doesn't have this column or the line
+/// leaves it empty
+fn field<T: FromStr>(
+    fields: &[&str],
+    indices: &Indices,
+    column: usize,
+) -> Result<Option<T>, String> {
+    let Some(value) = indices[column].and_then(|i| fields.get(i)) else {
+        return Ok(None);
+    };
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| format!("invalid {} {:?}", COLUMNS[column], value))
+}
+
+fn required<T: FromStr>(fields: &[&str], indices: &Indices, column: usize) -> Result<T, String> {
+    field(fields, indices, column)?.ok_or_else(|| format!("missing {}", COLUMNS[column]))
+}
+
+/// Writes frames in the current version of the format
+pub struct ResultsWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> ResultsWriter<W> {
+    /// Starts a new file, writing its header
+    pub fn new(mut out: W) -> std::io::Result<Self> {
+        writeln!(out, "{}{}", VERSION_PREFIX, VERSION)?;
+        writeln!(out, "{}", COLUMNS.join(","))?;
+        Ok(Self { out })
+    }
+
+    /// Continues a file that already has a header of the current version, with the
+    /// columns in order
+    pub fn append(out: W) -> Self {
+        Self { out }
+    }
+
+    /// Writes one frame. Floats are written with as many digits as needed to read them
+    /// back exactly.
+    pub fn write(&mut self, frame: &FrameData) -> std::io::Result<()> {
+        let r = &frame.result;
+        write!(
+            self.out,
+            "{},{},{},{},{},{},{},{},",
+            frame.frame, r.tile_x, r.tile_y, r.tile_z, r.zoom, r.x, r.y, r.score
+        )?;
+        if let Some(time) = frame.time {
+            write!(self.out, "{}", time)?;
+        }
+        write!(self.out, ",{},{},{},", r.angle, r.sub_x, r.sub_y)?;
+        if let Some(refined_score) = r.refined_score {
+            write!(self.out, "{}", refined_score)?;
+        }
+        writeln!(self.out)
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// A results CSV opened by [`open_append`]
+pub struct AppendResults {
+    pub writer: ResultsWriter<BufWriter<File>>,
+    /// Frames already in the file
+    pub frames: Vec<FrameData>,
+    /// Lines of the file that could not be read
+    pub errors: Vec<EarthfinderError>,
+}
+
+/// Opens the results CSV at `path` to add frames to it, creating it if needed.
+///
+/// Files of an older version are rewritten in the current one, the original is kept
+/// next to it as `<path>.v<version>`.
+pub fn open_append(path: &Path) -> Result<AppendResults> {
+    let io_err = |e| EarthfinderError::io(path, e);
+
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(io_err(e)),
+    };
+
+    if content.trim().is_empty() {
+        let file = File::create(path).map_err(io_err)?;
+        let writer = ResultsWriter::new(BufWriter::new(file)).map_err(io_err)?;
+        return Ok(AppendResults {
+            writer,
+            frames: vec![],
+            errors: vec![],
+        });
+    }
+
+    let (version, frames, errors) = parse(path, &content)?;
+
+    // frames are written in the order of COLUMNS, files with other columns are rewritten
+    let reordered = version == VERSION && !has_columns_in_order(&content);
+    if version < VERSION || reordered {
+        let backup = PathBuf::from(format!("{}.v{}", path.display(), version));
+        std::fs::rename(path, &backup).map_err(|e| EarthfinderError::io(&backup, e))?;
+        if reordered {
+            eprintln!(
+                "Rewriting {} with the columns in order, the original is kept as {}",
+                path.display(),
+                backup.display()
+            );
+        } else {
+            eprintln!(
+                "Migrating {} to version {}, the original is kept as {}",
+                path.display(),
+                VERSION,
+                backup.display()
+            );
+        }
+
+        let file = File::create(path).map_err(io_err)?;
+        let mut writer = ResultsWriter::new(BufWriter::new(file)).map_err(io_err)?;
+        for frame in &frames {
+            writer.write(frame).map_err(io_err)?;
+        }
+        writer.flush().map_err(io_err)?;
+        return Ok(AppendResults {
+            writer,
+            frames,
+            errors,
+        });
+    }
+
+    let mut file = File::options().append(true).open(path).map_err(io_err)?;
+    if !content.ends_with('\n') {
+        // the last line was cut by an interrupted run
+        writeln!(file).map_err(io_err)?;
+    }
+    Ok(AppendResults {
+        writer: ResultsWriter::append(BufWriter::new(file)),
+        frames,
+        errors,
+    })
+}
+
+/// Whether the header of a file of the current version has exactly [`COLUMNS`], in order
+fn has_columns_in_order(content: &str) -> bool {
+    let columns = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .nth(1)
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase());
+    columns.eq(COLUMNS)
+}
+
+/// An inclusive range of frames, written `first-last` or `frame`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameRange {
+    pub first: u32,
+    pub last: u32,
+}
+
+impl FrameRange {
+    pub fn contains(&self, frame: u32) -> bool {
+        (self.first..=self.last).contains(&frame)
+    }
+}
+
+impl FromStr for FrameRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |n: &str| {
+            n.trim()
+                .parse::<u32>()
+                .map_err(|_| format!("invalid frame range {:?}, expected N or N-M", s))
+        };
+        let (first, last) = match s.split_once('-') {
+            Some((first, last)) => (number(first)?, number(last)?),
+            None => (number(s)?, number(s)?),
+        };
+        if first > last {
+            return Err(format!(
+                "invalid frame range {:?}, {} is after {}",
+                s, first, last
+            ));
+        }
+        Ok(Self { first, last })
+    }
+}
+
+impl std::fmt::Display for FrameRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+/// Groups consecutive frames into ranges, `frames` must be sorted
+pub fn to_ranges(frames: impl IntoIterator<Item = u32>) -> Vec<FrameRange> {
+    let mut ranges: Vec<FrameRange> = vec![];
+    for frame in frames {
+        match ranges.last_mut() {
+            Some(range) if range.last + 1 == frame => range.last = frame,
+            _ => ranges.push(FrameRange {
+                first: frame,
+                last: frame,
+            }),
+        }
+    }
+    ranges
+}
+
+/// Which result is kept when several files have the same frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum MergePolicy {
+    /// The one with the highest score
+    Best,
+    /// The one from the file given first
+    First,
+}
+
+/// Merges the frames of several files, sorted by frame. Also returns the number of
+/// frames that were in more than one file.
+pub fn merge(files: Vec<Vec<FrameData>>, policy: MergePolicy) -> (Vec<FrameData>, usize) {
+    let mut merged = FxHashMap::<u32, FrameData>::default();
+    let mut conflicts = 0;
+
+    for frame in files.into_iter().flatten() {
+        match merged.entry(frame.frame) {
+            Entry::Vacant(entry) => {
+                entry.insert(frame);
+            }
+            Entry::Occupied(mut entry) => {
+                conflicts += 1;
+                if policy == MergePolicy::Best && frame.result.score > entry.get().result.score {
+                    entry.insert(frame);
+                }
+            }
+        }
+    }
+
+    let mut merged = merged.into_values().collect::<Vec<_>>();
+    merged.sort_unstable_by_key(|f| f.frame);
+    (merged, conflicts)
+}
+
+/// Keeps the frames in one of the `keep` ranges (all if empty) and in none of the
+/// `drop` ranges
+pub fn slice(frames: &mut Vec<FrameData>, keep: &[FrameRange], drop: &[FrameRange]) {
+    frames.retain(|f| {
+        (keep.is_empty() || keep.iter().any(|r| r.contains(f.frame)))
+            && !drop.iter().any(|r| r.contains(f.frame))
+    });
+}
+
+/// The `expected` frames that have no result, sorted
+pub fn missing(frames: &[FrameData], expected: impl IntoIterator<Item = u32>) -> Vec<u32> {
+    let done = frames
+        .iter()
+        .map(|f| f.frame)
+        .collect::<rustc_hash::FxHashSet<_>>();
+    let mut missing = expected
+        .into_iter()
+        .filter(|frame| !done.contains(frame))
+        .collect::<Vec<_>>();
+    missing.sort_unstable();
+    missing
+}
+
+/// The results of one frame in two runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameDiff {
+    pub frame: u32,
+    pub a: Option<PosResult>,
+    pub b: Option<PosResult>,
+}
+
+impl FrameDiff {
+    /// Score of `b` minus score of `a`, if both runs have the frame
+    pub fn score_delta(&self) -> Option<f32> {
+        Some(self.b?.score - self.a?.score)
+    }
+
+    /// Whether both runs found the same place, turned by the same angle
+    pub fn same_place(&self) -> bool {
+        // a difference far below a pixel or a degree is not another place
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        match (self.a, self.b) {
+            (Some(a), Some(b)) => {
+                (a.tile_pos(), a.x, a.y) == (b.tile_pos(), b.x, b.y)
+                    && close(a.zoom, b.zoom)
+                    && close(a.angle, b.angle)
+                    && close(a.sub_x, b.sub_x)
+                    && close(a.sub_y, b.sub_y)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Pairs the frames of two runs, sorted by frame
+pub fn diff(a: &[FrameData], b: &[FrameData]) -> Vec<FrameDiff> {
+    let mut diffs = FxHashMap::<u32, FrameDiff>::default();
+    for f in a {
+        diffs.insert(
+            f.frame,
+            FrameDiff {
+                frame: f.frame,
+                a: Some(f.result),
+                b: None,
+            },
+        );
+    }
+    for f in b {
+        diffs
+            .entry(f.frame)
+            .or_insert(FrameDiff {
+                frame: f.frame,
+                a: None,
+                b: None,
+            })
+            .b = Some(f.result);
+    }
+
+    let mut diffs = diffs.into_values().collect::<Vec<_>>();
+    diffs.sort_unstable_by_key(|d| d.frame);
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        diff, merge, missing, open_append, parse, read, slice, to_ranges, FrameData, FrameRange,
+        MergePolicy, ResultsWriter, VERSION,
+    };
+    use crate::error::EarthfinderError;
+    use crate::gpu::algorithm::PosResult;
+    use std::path::Path;
+
+    fn frame(frame: u32, score: f32, time: Option<f32>) -> FrameData {
+        FrameData {
+            frame,
+            result: PosResult {
+                tile_x: 77,
+                tile_y: 19,
+                tile_z: 7,
+                x: 277,
+                y: 95,
+                score,
+                zoom: 0.874_312_5,
+                angle: 0.0,
+                sub_x: 0.0,
+                sub_y: 0.0,
+                refined_score: None,
+            },
+            time,
+        }
+    }
+
+    fn error_lines(errors: &[EarthfinderError]) -> Vec<usize> {
+        errors
+            .iter()
+            .map(|e| match e {
+                EarthfinderError::Parse { line, .. } => *line,
+                _ => panic!("unexpected error {}", e),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn write_read_roundtrip() {
+        let frames = vec![
+            frame(1, -1.970_123_4, Some(0.512_345_6)),
+            frame(6, f32::MIN_POSITIVE, None),
+            frame(11, -123_456.79, Some(12.0)),
+            FrameData {
+                result: PosResult {
+                    angle: -22.5,
+                    sub_x: 0.125,
+                    sub_y: 0.999_5,
+                    refined_score: Some(-0.812_5),
+                    ..frame(16, -1.0, None).result
+                },
+                ..frame(16, -1.0, None)
+            },
+        ];
+
+        let mut out = vec![];
+        let mut writer = ResultsWriter::new(&mut out).unwrap();
+        for f in &frames {
+            writer.write(f).unwrap();
+        }
+
+        let content = String::from_utf8(out).unwrap();
+        let (version, back, errors) = parse(Path::new("out.csv"), &content).unwrap();
+
+        assert_eq!(version, VERSION);
+        assert!(errors.is_empty());
+        assert_eq!(back, frames);
+    }
+
+    #[test]
+    fn read_version_1() {
+        let csv = "Frame,tile_x,tile_y,tile_z,zoom,x,y,score,time\n\
+                   1,77,19,7,0.874,277,95,-1.970000,0.50\n\
+                   \n\
+                   2,77,oops,7,0.874,277,95,-1.97,0.5\n\
+                   3,77,19\n\
+                   1,78,20,7,1,12,13,-2.5,0.5\n\
+                   4,78,20,7,1,12,13,-2.5,0.5\n";
+
+        let (version, frames, errors) = parse(Path::new("out.csv"), csv).unwrap();
+
+        assert_eq!(version, 1);
+        assert_eq!(frames.iter().map(|f| f.frame).collect::<Vec<_>>(), [1, 4]);
+        assert_eq!(frames[0].result.score, -1.97);
+        assert_eq!(frames[0].time, Some(0.5));
+        assert_eq!(frames[1].result.tile_pos(), (78, 20, 7));
+
+        assert_eq!(error_lines(&errors), [4, 5, 6]);
+        assert_eq!(errors[0].to_string(), "out.csv:4: invalid tile_y \"oops\"");
+        assert_eq!(
+            errors[2].to_string(),
+            "out.csv:6: frame 1 already found on line 2"
+        );
+    }
+
+    #[test]
+    fn columns_are_read_by_name() {
+        let csv = "#earthfinder-results v2\n\
+                   score,frame,x,y,zoom,tile_z,tile_y,tile_x\n\
+                   -1.5,3,10,20,0.5,8,40,50\n";
+
+        let (_, frames, errors) = parse(Path::new("out.csv"), csv).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(frames[0].frame, 3);
+        assert_eq!(frames[0].result.tile_pos(), (50, 40, 8));
+        assert_eq!((frames[0].result.x, frames[0].result.y), (10, 20));
+        assert_eq!(frames[0].time, None);
+    }
+
+    #[test]
+    fn bad_headers_are_errors() {
+        let path = Path::new("out.csv");
+        assert!(parse(path, "").is_err());
+        assert!(parse(path, "#earthfinder-results v99\nframe\n").is_err());
+        assert_eq!(
+            parse(path, "frame,tile_x,tile_y,zoom,x,y,score\n")
+                .unwrap_err()
+                .to_string(),
+            "out.csv:1: missing column tile_z"
+        );
+    }
+
+    #[test]
+    fn open_append_migrates_version_1() {
+        let dir = std::env::temp_dir().join("earthfinder_results_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.csv");
+
+        let v1 = "Frame,tile_x,tile_y,tile_z,zoom,x,y,score,time\n\
+                  1,77,19,7,0.874,277,95,-1.97,0.50\n\
+                  6,77,19,7,0.874,2";
+        std::fs::write(&path, v1).unwrap();
+
+        let mut results = open_append(&path).unwrap();
+        assert_eq!(results.frames.len(), 1);
+        assert_eq!(results.errors.len(), 1);
+        results.writer.write(&frame(6, -2.0, Some(0.25))).unwrap();
+        results.writer.flush().unwrap();
+        drop(results);
+
+        assert_eq!(std::fs::read_to_string(dir.join("out.csv.v1")).unwrap(), v1);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let (version, frames, errors) = parse(&path, &content).unwrap();
+        assert_eq!(version, VERSION);
+        assert!(errors.is_empty());
+        assert_eq!(frames.iter().map(|f| f.frame).collect::<Vec<_>>(), [1, 6]);
+
+        let mut results = open_append(&path).unwrap();
+        assert_eq!(results.frames, frames);
+        results.writer.write(&frame(11, -3.0, None)).unwrap();
+        results.writer.flush().unwrap();
+        drop(results);
+        assert_eq!(read(&path).unwrap().0.len(), 3);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn open_append_rewrites_reordered_columns() {
+        let dir = std::env::temp_dir().join("earthfinder_results_order_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.csv");
+
+        let reordered = format!(
+            "#earthfinder-results v{}\n\
+             score,frame,x,y,zoom,tile_z,tile_y,tile_x\n\
+             -1.5,3,10,20,0.5,8,40,50\n",
+            VERSION
+        );
+        std::fs::write(&path, &reordered).unwrap();
+
+        let mut results = open_append(&path).unwrap();
+        results.writer.write(&frame(6, -2.0, Some(0.25))).unwrap();
+        results.writer.flush().unwrap();
+        drop(results);
+
+        let backup = dir.join(format!("out.csv.v{}", VERSION));
+        assert_eq!(std::fs::read_to_string(backup).unwrap(), reordered);
+        let (frames, errors) = read(&path).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(frames.iter().map(|f| f.frame).collect::<Vec<_>>(), [3, 6]);
+        assert_eq!(frames[0].result.tile_pos(), (50, 40, 8));
+        assert_eq!(frames[1], frame(6, -2.0, Some(0.25)));
+
+        // files already in order are appended to
+        let content = std::fs::read_to_string(&path).unwrap();
+        let results = open_append(&path).unwrap();
+        drop(results);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn frame_ranges() {
+        assert_eq!(
+            "12".parse(),
+            Ok(FrameRange {
+                first: 12,
+                last: 12
+            })
+        );
+        assert_eq!(
+            "1-100".parse(),
+            Ok(FrameRange {
+                first: 1,
+                last: 100
+            })
+        );
+        assert!("100-1".parse::<FrameRange>().is_err());
+        assert!("a-b".parse::<FrameRange>().is_err());
+
+        let ranges = to_ranges([1, 2, 3, 7, 9, 10]);
+        let ranges = ranges.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        assert_eq!(ranges, ["1-3", "7", "9-10"]);
+    }
+
+    #[test]
+    fn merge_slice_missing_diff() {
+        let a = vec![
+            frame(1, -2.0, None),
+            frame(2, -1.0, None),
+            frame(3, -4.0, None),
+        ];
+        let b = vec![
+            frame(2, -3.0, None),
+            frame(3, -1.5, None),
+            frame(5, -1.0, None),
+        ];
+
+        let (merged, conflicts) = merge(vec![a.clone(), b.clone()], MergePolicy::Best);
+        assert_eq!(conflicts, 2);
+        let scores = merged
+            .iter()
+            .map(|f| (f.frame, f.result.score))
+            .collect::<Vec<_>>();
+        assert_eq!(scores, [(1, -2.0), (2, -1.0), (3, -1.5), (5, -1.0)]);
+
+        let (merged, _) = merge(vec![a.clone(), b.clone()], MergePolicy::First);
+        assert_eq!(merged[2].result.score, -4.0);
+
+        let mut sliced = merged.clone();
+        slice(
+            &mut sliced,
+            &["1-3".parse().unwrap()],
+            &["2".parse().unwrap()],
+        );
+        assert_eq!(sliced.iter().map(|f| f.frame).collect::<Vec<_>>(), [1, 3]);
+
+        assert_eq!(missing(&merged, 1..=6), [4, 6]);
+
+        let diffs = diff(&a, &b);
+        assert_eq!(
+            diffs.iter().map(|d| d.frame).collect::<Vec<_>>(),
+            [1, 2, 3, 5]
+        );
+        assert_eq!(diffs[0].score_delta(), None);
+        assert_eq!(diffs[1].score_delta(), Some(-2.0));
+        assert!(diffs[1].same_place());
+        assert!(diffs[3].a.is_none());
+
+        let turned = |angle: f32, sub_x: f32| FrameData {
+            result: PosResult {
+                angle,
+                sub_x,
+                ..frame(1, -1.0, None).result
+            },
+            ..frame(1, -1.0, None)
+        };
+        let same = |a: FrameData, b: FrameData| diff(&[a], &[b])[0].same_place();
+        assert!(!same(turned(0.0, 0.0), turned(15.0, 0.0)));
+        assert!(!same(turned(0.0, 0.0), turned(0.0, 0.5)));
+        assert!(same(turned(15.0, 0.5), turned(15.000_001, 0.5)));
+    }
+}
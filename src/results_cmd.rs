@@ -0,0 +1,151 @@
+use crate::cli::{DiffArgs, MergeArgs, MissingArgs, ResultsArgs, ResultsCommand, SliceArgs};
+use crate::error::{self, EarthfinderError, Result};
+use crate::gpu::algorithm::PosResult;
+use crate::layout::DataLayout;
+use crate::project::Project;
+use crate::results::{self, FrameData, ResultsWriter};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub fn results_cmd(layout: &DataLayout, project: &Project, args: &ResultsArgs) -> Result<()> {
+    match &args.command {
+        ResultsCommand::Merge(args) => merge(args),
+        ResultsCommand::Slice(args) => slice(layout, args),
+        ResultsCommand::Missing(args) => missing(layout, project, args),
+        ResultsCommand::Diff(args) => diff(args),
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<FrameData>> {
+    let (frames, errors) = results::read(path)?;
+    error::report("lines", &errors);
+    Ok(frames)
+}
+
+fn input_or_default(layout: &DataLayout, input: &Option<PathBuf>) -> PathBuf {
+    input.clone().unwrap_or_else(|| layout.results_csv())
+}
+
+/// Writes a CSV to `output`, or to stdout
+fn write(output: &Option<PathBuf>, frames: &[FrameData]) -> Result<()> {
+    fn write_to(out: impl Write, frames: &[FrameData]) -> std::io::Result<()> {
+        let mut writer = ResultsWriter::new(out)?;
+        for frame in frames {
+            writer.write(frame)?;
+        }
+        writer.flush()
+    }
+
+    match output {
+        Some(path) => {
+            let file = std::fs::File::create(path).map_err(|e| EarthfinderError::io(path, e))?;
+            write_to(std::io::BufWriter::new(file), frames)
+                .map_err(|e| EarthfinderError::io(path, e))
+        }
+        None => write_to(std::io::stdout().lock(), frames)
+            .map_err(|e| EarthfinderError::io("<stdout>", e)),
+    }
+}
+
+fn merge(args: &MergeArgs) -> Result<()> {
+    let files = args
+        .inputs
+        .iter()
+        .map(|path| read(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let (frames, conflicts) = results::merge(files, args.policy);
+    eprintln!(
+        "{} frames, {} found in several files (kept the {:?} one)",
+        frames.len(),
+        conflicts,
+        args.policy
+    );
+    write(&args.output, &frames)
+}
+
+fn slice(layout: &DataLayout, args: &SliceArgs) -> Result<()> {
+    let mut frames = read(&input_or_default(layout, &args.input))?;
+    let before = frames.len();
+    results::slice(&mut frames, &args.keep, &args.drop);
+    eprintln!("kept {}/{} frames", frames.len(), before);
+    write(&args.output, &frames)
+}
+
+fn missing(layout: &DataLayout, project: &Project, args: &MissingArgs) -> Result<()> {
+    let frames = read(&input_or_default(layout, &args.input))?;
+    let first = args.first.unwrap_or(project.frames.first);
+    let last = args.last.unwrap_or(project.frames.last);
+
+    // gpu skips the frames without a mask
+    let expected = (first..=last).filter(|&i| layout.mask(i).exists());
+    let missing = results::missing(&frames, expected);
+
+    for range in results::to_ranges(missing.iter().copied()) {
+        println!("{}", range);
+    }
+    eprintln!("{} frames missing", missing.len());
+    Ok(())
+}
+
+fn diff(args: &DiffArgs) -> Result<()> {
+    let a = read(&args.a)?;
+    let b = read(&args.b)?;
+
+    fn place(pos: &PosResult) -> String {
+        format!(
+            "({},{},{}) {},{} z{:.3}",
+            pos.tile_x, pos.tile_y, pos.tile_z, pos.x, pos.y, pos.zoom
+        )
+    }
+
+    let diffs = results::diff(&a, &b);
+    let (mut both, mut moved, mut total_delta) = (0, 0, 0.0);
+
+    for d in &diffs {
+        match (&d.a, &d.b, d.score_delta()) {
+            (Some(pos_a), Some(pos_b), Some(delta)) => {
+                both += 1;
+                total_delta += delta;
+                if d.same_place() {
+                    if !args.changed {
+                        println!(
+                            "Frame {}: same place, score {:.4} -> {:.4} ({:+.4})",
+                            d.frame, pos_a.score, pos_b.score, delta
+                        );
+                    }
+                } else {
+                    moved += 1;
+                    println!(
+                        "Frame {}: {} -> {}, score {:.4} -> {:.4} ({:+.4})",
+                        d.frame,
+                        place(pos_a),
+                        place(pos_b),
+                        pos_a.score,
+                        pos_b.score,
+                        delta
+                    );
+                }
+            }
+            (Some(_), None, _) => println!("Frame {}: only in {}", d.frame, args.a.display()),
+            (None, Some(_), _) => println!("Frame {}: only in {}", d.frame, args.b.display()),
+            _ => unreachable!("every frame comes from one of the files"),
+        }
+    }
+
+    eprintln!(
+        "{} frames in both files, {} moved, mean score delta {:+.4}; {} only in {}, {} only in {}",
+        both,
+        moved,
+        if both > 0 {
+            total_delta / both as f32
+        } else {
+            0.0
+        },
+        a.len() - both,
+        args.a.display(),
+        b.len() - both,
+        args.b.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use crate::data::{tile_grad_entries, TilePos};
+use crate::error::Result;
+use crate::gpu::algorithm::{AlgoResult, PosResults, SearchBins};
+use crate::gpu::tile_cache::TileGrad;
+use crate::gpu::State;
+use crate::layout::DataLayout;
+use crate::tile_pack::TilePack;
+use image::RgbaImage;
+use rustc_hash::FxHashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use walkdir::DirEntry;
+
+/// The preprocessed tiles searched, see `tiles_grad` and `pack`
+pub struct TileIndex {
+    entries: Vec<DirEntry>,
+    packs: Vec<Arc<TilePack>>,
+}
+
+impl TileIndex {
+    /// Lists the gradient tiles of the given zoom levels, from their pack when there is
+    /// one, or from the `tiles_grad` tree
+    pub fn build(layout: &DataLayout, zooms: &[u32]) -> Result<TileIndex> {
+        let mut packs = vec![];
+        let mut unpacked = vec![];
+        for &z in zooms {
+            let path = layout.tile_pack(z);
+            if path.exists() {
+                packs.push(TilePack::open(&path)?);
+            } else {
+                unpacked.push(z);
+            }
+        }
+
+        let entries = if unpacked.is_empty() {
+            vec![]
+        } else {
+            tile_grad_entries(layout, &unpacked)
+        };
+        Ok(TileIndex { entries, packs })
+    }
+
+    pub fn from_entries(entries: Vec<DirEntry>) -> TileIndex {
+        TileIndex {
+            entries,
+            packs: vec![],
+        }
+    }
+
+    /// Tiles read from the `tiles_grad` tree
+    pub fn entries(&self) -> &[DirEntry] {
+        &self.entries
+    }
+
+    pub fn packs(&self) -> &[Arc<TilePack>] {
+        &self.packs
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.packs.iter().map(|pack| pack.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Searches the best places of a tile index for one mask at a time.
+///
+/// The tiles are read and decoded once in [`Search::new`], keeping up to `tile_cache_mb`
+/// of them in RAM, so a search should be reused for every mask of the same size. When they
+/// fit in `resident_tiles_mb` of VRAM they are uploaded once as well.
+pub struct Search {
+    state: State,
+    mask_size: (u32, u32),
+}
+
+impl Search {
+    pub fn new(
+        layout: &DataLayout,
+        index: &TileIndex,
+        mask_size: (u32, u32),
+        top_k: usize,
+        tile_cache_mb: u64,
+        resident_tiles_mb: u64,
+        bins: &SearchBins,
+    ) -> Result<Search> {
+        let mut state = pollster::block_on(State::new(mask_size, 1, top_k, bins));
+        state.prepare(layout, index, tile_cache_mb << 20)?;
+        state.make_resident(resident_tiles_mb << 20);
+        Ok(Search { state, mask_size })
+    }
+
+    /// Number of tiles searched, tiles that failed to load excluded
+    pub fn n_tiles(&self) -> usize {
+        self.state.n_tiles()
+    }
+
+    /// Gradient pixels of the searched tile at `pos`, see [`State::tile_grad`]
+    pub fn tile_grad(&self, pos: TilePos) -> Option<TileGrad<'_>> {
+        self.state.tile_grad(pos)
+    }
+
+    /// The best places for `mask`, best first
+    pub fn run(&mut self, mask: &RgbaImage) -> PosResults {
+        let previous = RgbaImage::new(self.mask_size.0 / 4, self.mask_size.1 / 4);
+        self.run_with(mask, 0, &previous, &FxHashSet::default())
+            .0
+            .best_pos
+    }
+
+    /// Like [`Search::run`], favouring places whose colors look like `previous` (the
+    /// quarter resolution result of the previous frame, see [`PosResult::to_rgba_quarter`])
+    /// and skipping `forbidden_tiles`. `frame` is only used to select `--capture frame:N`.
+    /// Also returns how long the GPU took.
+    ///
+    /// [`PosResult::to_rgba_quarter`]: crate::gpu::algorithm::PosResult::to_rgba_quarter
+    pub fn run_with(
+        &mut self,
+        mask: &RgbaImage,
+        frame: u32,
+        previous: &RgbaImage,
+        forbidden_tiles: &FxHashSet<TilePos>,
+    ) -> (AlgoResult, Duration) {
+        let (results, elapsed) = self
+            .state
+            .run_on_image(&[(mask, frame, previous)], forbidden_tiles);
+        let (_, result) = results.into_iter().next().expect("one result per mask");
+        (result, elapsed)
+    }
+}
@@ -0,0 +1,263 @@
+//! Shots of the animation, split at the hard cuts found by `shots`. After a cut the
+//! place matched for the previous frames says nothing about the next one, so `gpu`
+//! starts over and `render` can fade the frames around it.
+//!
+//! Written as one [`FrameRange`] per line, in [`DataLayout::shots`].
+
+use crate::cli::ShotsArgs;
+use crate::error::{self, EarthfinderError, Result};
+use crate::layout::DataLayout;
+use crate::mask::MaskChannels;
+use crate::mask_store::MaskStore;
+use crate::project::{Project, ShotsConfig};
+use crate::results::FrameRange;
+use std::path::Path;
+
+/// Cells of the grid the gradient energy of a mask is summed over, along each side
+const GRID: u32 = 4;
+
+/// Where the edges of a mask are: its gradient summed over a [`GRID`]² grid of cells,
+/// normalized to a total of 1. All zero for a blank mask.
+pub fn edge_histogram(mask: &MaskChannels) -> [f32; (GRID * GRID) as usize] {
+    let mut histogram = [0.0; (GRID * GRID) as usize];
+    for y in 0..mask.height() {
+        for x in 0..mask.width() {
+            let cell = (y * GRID / mask.height()) * GRID + x * GRID / mask.width();
+            histogram[cell as usize] += mask.grad_l1(x, y);
+        }
+    }
+    let total = histogram.iter().sum::<f32>();
+    if total > 0.0 {
+        histogram.iter_mut().for_each(|v| *v /= total);
+    }
+    histogram
+}
+
+/// Half the L1 distance of two histograms: 0 when they are the same, 1 when they don't
+/// overlap. A blank histogram is at 1 from any other.
+fn histogram_distance(a: &[f32], b: &[f32]) -> f32 {
+    let (sum_a, sum_b) = (a.iter().sum::<f32>(), b.iter().sum::<f32>());
+    if sum_a == 0.0 || sum_b == 0.0 {
+        return if sum_a == sum_b { 0.0 } else { 1.0 };
+    }
+    a.iter().zip(b).map(|(a, b)| (a - b).abs()).sum::<f32>() / 2.0
+}
+
+/// The shots of an animation, in order
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Shots {
+    shots: Vec<FrameRange>,
+}
+
+impl Shots {
+    /// Splits the masks of `store` where two consecutive masks are both dissimilar and
+    /// have their edges in different places. Each shot lasts until the next one starts,
+    /// so that the frames between masks belong to one.
+    pub fn detect(store: &MaskStore, config: &ShotsConfig) -> Shots {
+        let frames = store.frames();
+        let histograms = frames
+            .iter()
+            .map(|&frame| edge_histogram(store.get(frame).expect("frames of the store")))
+            .collect::<Vec<_>>();
+
+        let is_cut = |a: usize, b: usize| {
+            store.similarity_at(a, b) <= config.max_similarity
+                && histogram_distance(&histograms[a], &histograms[b])
+                    >= config.min_histogram_distance
+        };
+
+        // index in `frames` of the first mask of each shot but the first one
+        let mut starts: Vec<usize> = vec![];
+        for i in 1..frames.len() {
+            if !is_cut(i - 1, i) {
+                continue;
+            }
+            match starts.last().copied() {
+                // the shot before is too short: a flash, or frames in the middle of a cut
+                Some(start) if i - start < config.min_length => {
+                    starts.pop();
+                    if is_cut(start - 1, i) {
+                        starts.push(i);
+                    }
+                }
+                None if i < config.min_length => {}
+                _ => starts.push(i),
+            }
+        }
+        if starts
+            .last()
+            .is_some_and(|&start| frames.len() - start < config.min_length)
+        {
+            starts.pop();
+        }
+
+        let mut shots = vec![];
+        let mut first = 0;
+        for &start in &starts {
+            shots.push(FrameRange {
+                first: frames[first],
+                last: frames[start] - 1,
+            });
+            first = start;
+        }
+        if let Some(&last) = frames.last() {
+            shots.push(FrameRange {
+                first: frames[first],
+                last,
+            });
+        }
+        Shots { shots }
+    }
+
+    pub fn shots(&self) -> &[FrameRange] {
+        &self.shots
+    }
+
+    /// Whether `frame` is the first frame of a shot that follows a cut
+    pub fn is_cut(&self, frame: u32) -> bool {
+        self.shots.iter().skip(1).any(|shot| shot.first == frame)
+    }
+
+    /// Number of frames between `frame` and the nearest cut, none if it is in no shot or
+    /// there are no cuts. The first frame after a cut and the last before are at 0.
+    pub fn cut_distance(&self, frame: u32) -> Option<u32> {
+        let i = self.shots.iter().position(|shot| shot.contains(frame))?;
+        let shot = self.shots[i];
+        let after_cut = (i > 0).then(|| frame - shot.first);
+        let before_cut = (i + 1 < self.shots.len()).then(|| shot.last - frame);
+        match (after_cut, before_cut) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let content = self
+            .shots
+            .iter()
+            .map(|shot| format!("{}\n", shot))
+            .collect::<String>();
+        std::fs::write(path, content).map_err(|e| EarthfinderError::io(path, e))
+    }
+
+    /// Reads shots written by [`Shots::write`], lines that can't be parsed are returned
+    /// as errors
+    pub fn read(path: &Path) -> Result<(Shots, Vec<EarthfinderError>)> {
+        let content = std::fs::read_to_string(path).map_err(|e| EarthfinderError::io(path, e))?;
+
+        let mut shots = vec![];
+        let mut errors = vec![];
+        for (i, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match line.parse::<FrameRange>() {
+                Ok(shot) => shots.push(shot),
+                Err(e) => errors.push(EarthfinderError::parse(path, i + 1, e)),
+            }
+        }
+        shots.sort_unstable_by_key(|shot| shot.first);
+        Ok((Shots { shots }, errors))
+    }
+
+    /// Reads the shots of the layout if `shots` was run, reporting the bad lines
+    pub fn read_if_any(layout: &DataLayout) -> Result<Option<Shots>> {
+        let path = layout.shots();
+        if !std::fs::exists(&path).unwrap_or(false) {
+            return Ok(None);
+        }
+        let (shots, errors) = Shots::read(&path)?;
+        error::report("lines", &errors);
+        Ok(Some(shots))
+    }
+}
+
+pub fn shots(layout: &DataLayout, project: &Project, args: &ShotsArgs) -> Result<()> {
+    let first = args.first.unwrap_or(project.frames.first);
+    let last = args.last.unwrap_or(project.frames.last);
+    let output = args.output.clone().unwrap_or_else(|| layout.shots());
+
+    let (store, errors) = MaskStore::load(layout, first..=last);
+    error::report("masks", &errors);
+
+    let shots = Shots::detect(&store, &project.shots);
+    shots.write(&output)?;
+
+    for (i, shot) in shots.shots().iter().enumerate() {
+        println!(
+            "Shot {:>3}: frames {:<11} ({} frames)",
+            i + 1,
+            shot.to_string(),
+            shot.last - shot.first + 1
+        );
+    }
+    eprintln!(
+        "{} shots written to {}",
+        shots.shots().len(),
+        output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edge_histogram, Shots};
+    use crate::mask::MaskChannels;
+    use crate::mask_store::MaskStore;
+    use crate::project::ShotsConfig;
+    use crate::results::FrameRange;
+    use nanoserde::DeJson;
+
+    /// A vertical edge in the left or right half of a 16x12 mask
+    fn edge(right: bool, offset: u32) -> MaskChannels {
+        let column = if right { 10 } else { 2 } + offset;
+        let grad_x = (0..16 * 12)
+            .map(|i| if i % 16 == column { 1.0 } else { 0.0 })
+            .collect::<Vec<_>>();
+        MaskChannels::from_gradients(16, 12, &grad_x, &[0.0; 16 * 12])
+    }
+
+    #[test]
+    fn shots_are_split_at_cuts() {
+        let histogram = edge_histogram(&edge(false, 0));
+        assert_eq!(histogram.iter().filter(|&&v| v > 0.0).count(), 4);
+
+        // masks every 5 frames, a cut at 31 and a one mask flash at 56
+        let store = MaskStore::from_masks(
+            (0..16)
+                .map(|i| {
+                    let frame = 1 + i * 5;
+                    (frame, edge(frame > 30 && frame != 56, i % 2))
+                })
+                .collect(),
+        );
+        let config = ShotsConfig::deserialize_json("{}").unwrap();
+        let shots = Shots::detect(&store, &config);
+        assert_eq!(
+            shots.shots(),
+            [
+                FrameRange { first: 1, last: 30 },
+                FrameRange {
+                    first: 31,
+                    last: 76
+                }
+            ]
+        );
+        assert!(shots.is_cut(31));
+        assert!(!shots.is_cut(1));
+        assert_eq!(shots.cut_distance(28), Some(2));
+        assert_eq!(shots.cut_distance(31), Some(0));
+        assert_eq!(shots.cut_distance(76), Some(45));
+        assert_eq!(shots.cut_distance(80), None);
+
+        let path = std::env::temp_dir().join("earthfinder_shots_test.txt");
+        shots.write(&path).unwrap();
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str("12-\n");
+        std::fs::write(&path, content).unwrap();
+        let (read, errors) = Shots::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(read, shots);
+        assert_eq!(errors.len(), 1);
+    }
+}
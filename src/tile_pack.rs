@@ -0,0 +1,273 @@
+//! Packed tile store: the gradient and quarter resolution tiles of one zoom level in a
+//! single file, so that `gpu` doesn't have to open tens of thousands of small PNGs.
+//!
+//! Built by the `pack` command from the `tiles_grad` and `tiles_smol` trees, and used
+//! by [`crate::search::TileIndex::build`] instead of the trees when present. Layout,
+//! little endian:
+//! - header: `EFTPACK\0`, version (u32), zoom (u32)
+//! - the PNG payloads, back to back
+//! - index: per tile x (u32), y (u32), then offset and length (u64) of the gradient
+//!   and of the quarter resolution payloads
+//! - footer: offset of the index (u64), number of tiles (u64)
+
+use crate::data::{tile_grad_entries, TilePos};
+use crate::error::{self, EarthfinderError, Result};
+use crate::layout::DataLayout;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"EFTPACK\0";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 40;
+const FOOTER_SIZE: usize = 16;
+
+/// Tiles read at once while packing
+const PACK_CHUNK_SIZE: usize = 256;
+
+struct PackEntry {
+    x: u32,
+    y: u32,
+    grad: Range<usize>,
+    smol: Range<usize>,
+}
+
+/// A pack file, mapped in memory
+pub struct TilePack {
+    mmap: Mmap,
+    z: u32,
+    entries: Vec<PackEntry>,
+}
+
+impl TilePack {
+    pub fn open(path: &Path) -> Result<Arc<TilePack>> {
+        let file = File::open(path).map_err(|e| EarthfinderError::io(path, e))?;
+        // SAFETY: packs are only written by `pack`, to a temporary file renamed once
+        // complete, so a mapped pack is never modified
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| EarthfinderError::io(path, e))?;
+        let invalid =
+            |msg: &str| EarthfinderError::layout(path, format!("invalid tile pack: {msg}"));
+
+        let u32_at = |at: usize| u32::from_le_bytes(mmap[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(mmap[at..at + 8].try_into().unwrap()) as usize;
+
+        if mmap.len() < HEADER_SIZE + FOOTER_SIZE || &mmap[..8] != MAGIC {
+            return Err(invalid("not a tile pack"));
+        }
+        if u32_at(8) != VERSION {
+            return Err(invalid(&format!("unsupported version {}", u32_at(8))));
+        }
+        let z = u32_at(12);
+
+        let footer = mmap.len() - FOOTER_SIZE;
+        let (index, count) = (u64_at(footer), u64_at(footer + 8));
+        if index < HEADER_SIZE || count.checked_mul(ENTRY_SIZE) != footer.checked_sub(index) {
+            return Err(invalid("corrupted index"));
+        }
+
+        let payload = |at: usize| -> Result<Range<usize>> {
+            let (offset, len) = (u64_at(at), u64_at(at + 8));
+            match offset.checked_add(len) {
+                Some(end) if offset >= HEADER_SIZE && end <= index => Ok(offset..end),
+                _ => Err(invalid("payload out of bounds")),
+            }
+        };
+        let entries = (0..count)
+            .map(|i| {
+                let at = index + i * ENTRY_SIZE;
+                Ok(PackEntry {
+                    x: u32_at(at),
+                    y: u32_at(at + 4),
+                    grad: payload(at + 8)?,
+                    smol: payload(at + 24)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Arc::new(TilePack { mmap, z, entries }))
+    }
+
+    pub fn zoom(&self) -> u32 {
+        self.z
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Every tile of the pack, with its gradient and quarter resolution PNGs
+    pub fn tiles(self: &Arc<Self>) -> impl Iterator<Item = (TilePos, TileBytes, TileBytes)> + '_ {
+        self.entries.iter().map(|entry| {
+            (
+                (entry.x, entry.y, self.z),
+                TileBytes::Packed(self.clone(), entry.grad.clone()),
+                TileBytes::Packed(self.clone(), entry.smol.clone()),
+            )
+        })
+    }
+}
+
+/// The encoded bytes of a tile, read from its file or borrowed from a pack
+#[derive(Clone)]
+pub enum TileBytes {
+    Owned(Arc<Vec<u8>>),
+    Packed(Arc<TilePack>, Range<usize>),
+}
+
+impl Deref for TileBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            TileBytes::Owned(data) => data,
+            TileBytes::Packed(pack, range) => &pack.mmap[range.clone()],
+        }
+    }
+}
+
+/// Packs the tiles of zoom `z` found in `tiles_grad`, along with their quarter
+/// resolution twins. Returns how many tiles were packed and the ones that couldn't be.
+pub fn pack_zoom(layout: &DataLayout, z: u32) -> Result<(usize, Vec<EarthfinderError>)> {
+    use rayon::prelude::*;
+
+    let path = layout.tile_pack(z);
+    let tmp_path = path.with_extension("pack.tmp");
+    let dir = layout.tile_packs_dir();
+    std::fs::create_dir_all(&dir).map_err(|e| EarthfinderError::io(&dir, e))?;
+
+    let mut positions = vec![];
+    let mut errors = vec![];
+    for entry in tile_grad_entries(layout, &[z]) {
+        match layout.tile_scheme().parse(entry.path()) {
+            Ok(pos) => positions.push(pos),
+            Err(e) => errors.push(e),
+        }
+    }
+    positions.sort_by_key(|&(x, y, _)| (y, x));
+
+    let io_err = |e| EarthfinderError::io(&tmp_path, e);
+    let mut out = BufWriter::new(File::create(&tmp_path).map_err(io_err)?);
+    out.write_all(MAGIC).map_err(io_err)?;
+    out.write_all(&VERSION.to_le_bytes()).map_err(io_err)?;
+    out.write_all(&z.to_le_bytes()).map_err(io_err)?;
+
+    let mut offset = HEADER_SIZE as u64;
+    let mut index = Vec::with_capacity(positions.len() * ENTRY_SIZE);
+    let read = |path: &Path| std::fs::read(path).map_err(|e| EarthfinderError::io(path, e));
+
+    for chunk in positions.chunks(PACK_CHUNK_SIZE) {
+        let tiles = chunk
+            .par_iter()
+            .map(|&pos| {
+                Ok((
+                    pos,
+                    read(&layout.tile_grad(pos))?,
+                    read(&layout.tile_smol(pos))?,
+                ))
+            })
+            .collect::<Vec<Result<_>>>();
+
+        for tile in tiles {
+            let ((x, y, _), grad, smol) = match tile {
+                Ok(tile) => tile,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+            index.extend(x.to_le_bytes());
+            index.extend(y.to_le_bytes());
+            for payload in [&grad, &smol] {
+                out.write_all(payload).map_err(io_err)?;
+                index.extend(offset.to_le_bytes());
+                index.extend((payload.len() as u64).to_le_bytes());
+                offset += payload.len() as u64;
+            }
+        }
+    }
+
+    let count = index.len() / ENTRY_SIZE;
+    out.write_all(&index).map_err(io_err)?;
+    out.write_all(&offset.to_le_bytes()).map_err(io_err)?;
+    out.write_all(&(count as u64).to_le_bytes())
+        .map_err(io_err)?;
+    out.flush().map_err(io_err)?;
+    drop(out);
+
+    std::fs::rename(&tmp_path, &path).map_err(|e| EarthfinderError::io(&path, e))?;
+    Ok((count, errors))
+}
+
+/// Builds the pack of every zoom level in `zooms`
+pub fn pack(layout: &DataLayout, zooms: &[u32]) -> Result<()> {
+    for &z in zooms {
+        let (count, errors) = pack_zoom(layout, z)?;
+        error::report("tiles", &errors);
+        println!(
+            "packed {} tiles in {}",
+            count,
+            layout.tile_pack(z).display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pack_zoom, TilePack};
+    use crate::layout::DataLayout;
+
+    #[test]
+    fn pack_roundtrip() {
+        let dir = std::env::temp_dir().join("earthfinder_tile_pack_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let layout = DataLayout::new(&dir);
+
+        // only the bytes matter, they are decoded when searching
+        let tiles = [(3, 1, 4), (0, 2, 4), (7, 1, 4)];
+        for (i, &pos) in tiles.iter().enumerate() {
+            for (path, kind) in [
+                (layout.tile_grad(pos), "grad"),
+                (layout.tile_smol(pos), "smol"),
+            ] {
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, format!("{kind} {i}").repeat(i + 1)).unwrap();
+            }
+        }
+        // no quarter resolution twin
+        let orphan = layout.tile_grad((5, 5, 4));
+        std::fs::create_dir_all(orphan.parent().unwrap()).unwrap();
+        std::fs::write(&orphan, "orphan").unwrap();
+
+        let (count, errors) = pack_zoom(&layout, 4).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(errors.len(), 1);
+
+        let pack = TilePack::open(&layout.tile_pack(4)).unwrap();
+        assert_eq!(pack.zoom(), 4);
+        let packed = pack.tiles().collect::<Vec<_>>();
+        // sorted by row then column
+        assert_eq!(
+            packed.iter().map(|(pos, _, _)| *pos).collect::<Vec<_>>(),
+            [(3, 1, 4), (7, 1, 4), (0, 2, 4)]
+        );
+        assert_eq!(&*packed[1].1, "grad 2".repeat(3).as_bytes());
+        assert_eq!(&*packed[2].2, b"smol 1smol 1");
+
+        let mut content = std::fs::read(layout.tile_pack(4)).unwrap();
+        let len = content.len();
+        content[len - 1] = 1;
+        std::fs::write(layout.tile_pack(4), &content).unwrap();
+        assert!(TilePack::open(&layout.tile_pack(4)).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,228 @@
+//! How tile positions map to file paths in a tile tree.
+//!
+//! Every tree of a tile root (`tiles`, `tiles_grad`, `tiles_smol`) uses the same
+//! scheme, see [`crate::layout::DataLayout::with_tile_scheme`]. The trees produced by
+//! `tiles_grad` follow the scheme of the raw tiles they are made from.
+
+use crate::data::TilePos;
+use crate::error::{EarthfinderError, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum TileScheme {
+    /// `z/x/y.ext`, the usual XYZ layout
+    Zxy,
+    /// `z/y/x.ext`, the layout of the EOX s2maps bucket
+    #[default]
+    Zyx,
+    /// `z/x/y.ext` with y counted from the bottom of the map
+    Tms,
+    /// `<quadkey>.ext` in a single directory, the zoom being the length of the key
+    Quadkey,
+}
+
+impl TileScheme {
+    /// Path of a tile in the tree rooted at `dir`
+    pub fn path(&self, dir: &Path, (x, y, z): TilePos, ext: &str) -> PathBuf {
+        match self {
+            TileScheme::Zxy => dir
+                .join(z.to_string())
+                .join(x.to_string())
+                .join(format!("{y}.{ext}")),
+            TileScheme::Zyx => dir
+                .join(z.to_string())
+                .join(y.to_string())
+                .join(format!("{x}.{ext}")),
+            TileScheme::Tms => dir
+                .join(z.to_string())
+                .join(x.to_string())
+                .join(format!("{}.{ext}", flip_y(y, z))),
+            TileScheme::Quadkey => dir.join(format!("{}.{ext}", quadkey((x, y, z)))),
+        }
+    }
+
+    /// Reads the position of a tile from its path, only the last components are used
+    pub fn parse(&self, path: &Path) -> Result<TilePos> {
+        let invalid = || {
+            EarthfinderError::layout(
+                path,
+                format!("tile path is not of the form {}", self.form()),
+            )
+        };
+        fn stem(path: Option<&Path>) -> Option<&str> {
+            path.and_then(Path::file_stem).and_then(|s| s.to_str())
+        }
+        let component = |path: Option<&Path>| -> Result<u32> {
+            stem(path).and_then(|s| s.parse().ok()).ok_or_else(invalid)
+        };
+
+        let parent = path.parent();
+        let grand_parent = parent.and_then(Path::parent);
+        match self {
+            TileScheme::Zxy => Ok((
+                component(parent)?,
+                component(Some(path))?,
+                component(grand_parent)?,
+            )),
+            TileScheme::Zyx => Ok((
+                component(Some(path))?,
+                component(parent)?,
+                component(grand_parent)?,
+            )),
+            TileScheme::Tms => {
+                let z = component(grand_parent)?;
+                let y = component(Some(path))?;
+                if z == 0 || y >= rows(z) {
+                    return Err(invalid());
+                }
+                Ok((component(parent)?, flip_y(y, z), z))
+            }
+            TileScheme::Quadkey => stem(Some(path)).and_then(from_quadkey).ok_or_else(invalid),
+        }
+    }
+
+    /// Directory of a tree holding the tiles of zoom `z`. For quadkeys this is the whole
+    /// tree, so what is found there must be filtered by zoom.
+    pub fn zoom_dir(&self, dir: &Path, z: u32) -> PathBuf {
+        match self {
+            TileScheme::Quadkey => dir.to_path_buf(),
+            _ => dir.join(z.to_string()),
+        }
+    }
+
+    /// Deletes the tiles of zoom `z` of a tree
+    pub fn remove_zoom(&self, dir: &Path, z: u32) {
+        match self {
+            TileScheme::Quadkey => {
+                let Ok(entries) = std::fs::read_dir(dir) else {
+                    return;
+                };
+                for entry in entries.flatten() {
+                    if self.parse(&entry.path()).is_ok_and(|(_, _, tz)| tz == z) {
+                        let _ = std::fs::remove_file(entry.path());
+                    }
+                }
+            }
+            _ => {
+                let _ = std::fs::remove_dir_all(self.zoom_dir(dir, z));
+            }
+        }
+    }
+
+    fn form(&self) -> &'static str {
+        match self {
+            TileScheme::Zxy | TileScheme::Tms => "z/x/y.ext",
+            TileScheme::Zyx => "z/y/x.ext",
+            TileScheme::Quadkey => "<quadkey>.ext",
+        }
+    }
+}
+
+/// Number of tile rows at zoom `z`, the world being twice as wide as high
+fn rows(z: u32) -> u32 {
+    1 << (z - 1)
+}
+
+fn flip_y(y: u32, z: u32) -> u32 {
+    rows(z) - 1 - y
+}
+
+fn quadkey((x, y, z): TilePos) -> String {
+    (1..=z)
+        .rev()
+        .map(|i| {
+            let mask = 1 << (i - 1);
+            let digit = (x & mask != 0) as u8 + 2 * (y & mask != 0) as u8;
+            (b'0' + digit) as char
+        })
+        .collect()
+}
+
+fn from_quadkey(key: &str) -> Option<TilePos> {
+    if key.is_empty() || key.len() > 31 {
+        return None;
+    }
+    let (mut x, mut y) = (0, 0);
+    for c in key.chars() {
+        let digit = c.to_digit(4)?;
+        x = x << 1 | (digit & 1);
+        y = y << 1 | (digit >> 1);
+    }
+    Some((x, y, key.len() as u32))
+}
+
+impl FromStr for TileScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "z/x/y" | "xyz" => Ok(TileScheme::Zxy),
+            "z/y/x" => Ok(TileScheme::Zyx),
+            "tms" => Ok(TileScheme::Tms),
+            "quadkey" => Ok(TileScheme::Quadkey),
+            _ => Err(format!(
+                "unknown tile scheme {:?}, expected z/x/y, z/y/x, tms or quadkey",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TileScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TileScheme::Zxy => "z/x/y",
+            TileScheme::Zyx => "z/y/x",
+            TileScheme::Tms => "tms",
+            TileScheme::Quadkey => "quadkey",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TileScheme;
+    use std::path::Path;
+
+    #[test]
+    fn parse_rejects_bad_names() {
+        assert_eq!(
+            TileScheme::Zyx
+                .parse(Path::new("tiles_grad/7/27/35.png"))
+                .unwrap(),
+            (35, 27, 7)
+        );
+        assert!(TileScheme::Zyx
+            .parse(Path::new("tiles_grad/7/27/35 (copy).png"))
+            .is_err());
+        assert!(TileScheme::Zyx.parse(Path::new("35.png")).is_err());
+        assert!(TileScheme::Tms.parse(Path::new("7/35/64.png")).is_err());
+        assert!(TileScheme::Quadkey.parse(Path::new("0124.png")).is_err());
+    }
+
+    #[test]
+    fn schemes_roundtrip() {
+        let dir = Path::new("tiles");
+        let pos = (35, 27, 7);
+        for (scheme, expected) in [
+            (TileScheme::Zxy, "tiles/7/35/27.png"),
+            (TileScheme::Zyx, "tiles/7/27/35.png"),
+            (TileScheme::Tms, "tiles/7/35/36.png"),
+            (TileScheme::Quadkey, "tiles/0122033.png"),
+        ] {
+            let path = scheme.path(dir, pos, "png");
+            assert_eq!(path, Path::new(expected), "{}", scheme);
+            assert_eq!(scheme.parse(&path).unwrap(), pos, "{}", scheme);
+            assert_eq!(scheme.to_string().parse(), Ok(scheme));
+        }
+        // corners of the map
+        for pos in [(0, 0, 1), (1, 0, 1), (255, 127, 8)] {
+            let path = TileScheme::Quadkey.path(dir, pos, "png");
+            assert_eq!(TileScheme::Quadkey.parse(&path).unwrap(), pos);
+            let path = TileScheme::Tms.path(dir, pos, "png");
+            assert_eq!(TileScheme::Tms.parse(&path).unwrap(), pos);
+        }
+    }
+}
@@ -1,43 +1,44 @@
-use crate::data::{deform_width, deformation, extract_tile_pos};
+use crate::cli::TilesGradArgs;
+use crate::data::open_image;
+use crate::error::{self, EarthfinderError};
+use crate::geo::{self, deform_width, deformation};
+use crate::layout::DataLayout;
+use crate::project::Project;
 use image::codecs::png::{CompressionType, FilterType, PngEncoder};
 use image::{GenericImage, GenericImageView, ImageBuffer, Rgb, Rgb32FImage, RgbImage};
 use rayon::prelude::*;
-use std::path::PathBuf;
 use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
 
-static SHOW_ORIGINAL: bool = false;
-
-pub fn gen_tiles_grad(tile_z: u32) {
+pub fn gen_tiles_grad(layout: &DataLayout, project: &Project, tile_z: u32, args: &TilesGradArgs) {
+    let show_original = args.show_original;
+    let max_latitude = args.max_latitude.unwrap_or(project.tiles.max_latitude);
     let i = AtomicU32::new(0);
-    let entries: Vec<_> = walkdir::WalkDir::new(format!("data/tiles/{}", tile_z))
+    let scheme = layout.tile_scheme();
+    let entries: Vec<_> = walkdir::WalkDir::new(scheme.zoom_dir(&layout.tiles_dir(), tile_z))
         .into_iter()
         .collect();
     let mut to_process = 0;
 
-    let _ = std::fs::remove_dir_all(format!("data/tiles_grad/{}", tile_z));
-    let _ = std::fs::remove_dir_all(format!("data/tiles_smol/{}", tile_z));
+    scheme.remove_zoom(&layout.tiles_grad_dir(), tile_z);
+    scheme.remove_zoom(&layout.tiles_smol_dir(), tile_z);
+    // the pack would shadow the new tiles
+    if std::fs::remove_file(layout.tile_pack(tile_z)).is_ok() {
+        eprintln!("Removed the tile pack of zoom {}, run `pack` again", tile_z);
+    }
 
     for entry in entries.iter() {
         let Ok(entry) = entry else {
             continue;
         };
-        let ftype = entry.file_type();
-
-        if ftype.is_dir() {
-            let mut path = entry.path().display().to_string();
-            path = path.replace("tiles", "tiles_grad");
-            let _ = std::fs::create_dir_all(path);
-
-            let mut path = entry.path().display().to_string();
-            path = path.replace("tiles", "tiles_smol");
-            let _ = std::fs::create_dir_all(path);
-            continue;
-        } else {
+        if entry.file_type().is_file() {
             to_process += 1;
         }
     }
 
     let skipped = AtomicUsize::new(0);
+    let errors = Mutex::new(vec![]);
+    let report = |e: EarthfinderError| errors.lock().unwrap().push(e);
 
     entries.par_iter().for_each(|entry| {
         let Ok(entry) = entry else {
@@ -52,17 +53,21 @@ pub fn gen_tiles_grad(tile_z: u32) {
             return;
         }
 
-        let path_str = path.display().to_string();
-        let (_, tile_y, _) = extract_tile_pos(&path_str);
-        let latitude = 90.0 - (tile_y as f32 / (1 << (tile_z - 1)) as f32) * 180.0;
+        let tile_pos @ (_, tile_y, _) = match scheme.parse(path) {
+            // with quadkeys, every zoom is in the same directory
+            Ok((_, _, z)) if z != tile_z => return,
+            Ok(pos) => pos,
+            Err(e) => return report(e),
+        };
+        let latitude = geo::tile_to_lat(tile_y as f64, tile_z) as f32;
 
-        if latitude.abs() > 70.0 {
+        if latitude.abs() > max_latitude {
             skipped.fetch_add(1, Ordering::Relaxed);
             return;
         }
 
         let v = i.fetch_add(1, Ordering::Relaxed);
-        if v % 1000 == 0 {
+        if v.is_multiple_of(1000) {
             eprintln!(
                 "{} images processed ({:.0}%)",
                 v,
@@ -70,9 +75,9 @@ pub fn gen_tiles_grad(tile_z: u32) {
             );
         }
 
-        let orig_image = match image::open(path) {
+        let orig_image = match open_image(path) {
             Ok(image) => image.to_rgb8(),
-            Err(e) => panic!("Could not open image {}: {}", path.display(), e),
+            Err(e) => return report(e),
         };
 
         let new_w = deform_width(orig_image.width(), tile_y, tile_z);
@@ -143,7 +148,7 @@ pub fn gen_tiles_grad(tile_z: u32) {
             return;
         }
 
-        let gradient_w = if SHOW_ORIGINAL {
+        let gradient_w = if show_original {
             image.width() * 2
         } else {
             image.width()
@@ -152,7 +157,7 @@ pub fn gen_tiles_grad(tile_z: u32) {
 
         const CONV_SIZE: i32 = 2;
 
-        if SHOW_ORIGINAL {
+        if show_original {
             for y in 0..image.height() {
                 for x in 0..image.width() {
                     gradient_image.put_pixel(x + image.width(), y, *image.get_pixel(x, y));
@@ -233,41 +238,38 @@ pub fn gen_tiles_grad(tile_z: u32) {
         }
 
         {
-            let path_smol_str = path
-                .display()
-                .to_string()
-                .replace("data/tiles", "data/tiles_smol");
+            let path_smol = layout.tile_smol(tile_pos);
+            let _ = std::fs::create_dir_all(path_smol.parent().unwrap());
 
-            let mut path_smol = PathBuf::from(path_smol_str);
-            path_smol.set_extension("png");
-
-            image_smol.save(path_smol).unwrap();
+            if let Err(e) = image_smol.save(&path_smol) {
+                return report(EarthfinderError::image(path_smol, e));
+            }
         }
 
-        let mut path_string = path.display().to_string();
-        path_string = path_string.replace("tiles", "tiles_grad");
-
-        let mut path = PathBuf::from(path_string);
-        path.set_extension("png");
+        let path = layout.tile_grad(tile_pos);
+        let _ = std::fs::create_dir_all(path.parent().unwrap());
 
-        let file_writer = std::fs::File::create(&path).unwrap_or_else(|e| {
-            panic!("Could not create file {}: {}", path.display(), e);
-        });
+        let file_writer = match std::fs::File::create(&path) {
+            Ok(file) => file,
+            Err(e) => return report(EarthfinderError::io(path, e)),
+        };
         let mut bufwriter = std::io::BufWriter::new(file_writer);
 
         /*gradient_image
         .write_with_encoder(JpegEncoder::new_with_quality(&mut bufwriter, 95))
         .expect("Could not write image");*/
 
-        gradient_image
-            .write_with_encoder(PngEncoder::new_with_quality(
-                &mut bufwriter,
-                CompressionType::Best,
-                FilterType::Adaptive,
-            ))
-            .expect("Could not write image");
+        if let Err(e) = gradient_image.write_with_encoder(PngEncoder::new_with_quality(
+            &mut bufwriter,
+            CompressionType::Best,
+            FilterType::Adaptive,
+        )) {
+            report(EarthfinderError::image(path, e));
+        }
     });
 
+    error::report("tiles", &errors.into_inner().unwrap());
+
     eprintln!(
         "Skipped {}/{} images",
         skipped.load(Ordering::SeqCst),